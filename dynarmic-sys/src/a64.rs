@@ -0,0 +1,149 @@
+use std::ffi::c_void;
+
+#[repr(C)]
+pub struct Jit(c_void);
+
+// Mirrors Dynarmic::A64::Exception, order matters
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Exception {
+    UnallocatedEncoding,
+    ReservedValue,
+    UnpredictableInstruction,
+    WaitForInterrupt,
+    WaitForEvent,
+    SendEvent,
+    SendEventLocal,
+    Yield,
+    Breakpoint,
+}
+
+impl Exception {
+    // Hints are only reported when hint hooking is enabled, execution continues after the instruction.
+    pub fn is_hint(self) -> bool {
+        match self {
+            Exception::UnallocatedEncoding |
+            Exception::ReservedValue |
+            Exception::UnpredictableInstruction |
+            Exception::Breakpoint => false,
+            _ => true,
+        }
+    }
+}
+
+// Mirrors Dynarmic::A64::DataCacheOperation
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DataCacheOperation {
+    CleanAndInvalidateBySetWay,
+    CleanAndInvalidateByVAToPoC,
+    CleanBySetWay,
+    CleanByVAToPoC,
+    CleanByVAToPoU,
+    CleanByVAToPoP,
+    InvalidateBySetWay,
+    InvalidateByVAToPoC,
+    ZeroByVA,
+}
+
+// Mirrors Dynarmic::A64::InstructionCacheOperation
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InstructionCacheOperation {
+    InvalidateByVAToPoU,
+    InvalidateAllToPoU,
+    InvalidateAllToPoUInnerSharable,
+}
+
+// Dynarmic::A64::Vector, lower half first
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Vector {
+    pub lo: u64,
+    pub hi: u64,
+}
+
+impl From<u128> for Vector {
+    fn from(value: u128) -> Self {
+        Vector {
+            lo: value as u64,
+            hi: (value >> 64) as u64,
+        }
+    }
+}
+
+impl From<Vector> for u128 {
+    fn from(value: Vector) -> Self {
+        (value.lo as u128) | ((value.hi as u128) << 64)
+    }
+}
+
+pub type MemoryReadCallback<T> = extern fn(&mut Jit, u64) -> T;
+pub type MemoryWriteCallback<T> = extern fn(&mut Jit, u64, T) -> ();
+pub type CallSVCCallback = extern fn(&mut Jit, u32) -> ();
+pub type ExceptionRaisedCallback = extern fn(&mut Jit, u64, Exception);
+pub type DataCacheOperationRaisedCallback = extern fn(&mut Jit, DataCacheOperation, u64);
+pub type InstructionCacheOperationRaisedCallback = extern fn(&mut Jit, InstructionCacheOperation, u64);
+pub type AddTicksCallback = extern fn(&mut Jit, u64);
+pub type GetTicksRemainingCallback = extern fn(&mut Jit) -> u64;
+pub type GetCNTPCTCallback = extern fn(&mut Jit) -> u64;
+
+#[repr(C)]
+pub struct Callbacks {
+    pub read8: MemoryReadCallback<u8>,
+    pub read16: MemoryReadCallback<u16>,
+    pub read32: MemoryReadCallback<u32>,
+    pub read64: MemoryReadCallback<u64>,
+    pub read128: MemoryReadCallback<Vector>,
+
+    pub write8: MemoryWriteCallback<u8>,
+    pub write16: MemoryWriteCallback<u16>,
+    pub write32: MemoryWriteCallback<u32>,
+    pub write64: MemoryWriteCallback<u64>,
+    pub write128: MemoryWriteCallback<Vector>,
+
+    pub call_svc: CallSVCCallback,
+    pub exception_raised: ExceptionRaisedCallback,
+    pub data_cache_operation_raised: DataCacheOperationRaisedCallback,
+    pub instruction_cache_operation_raised: InstructionCacheOperationRaisedCallback,
+    pub add_ticks: AddTicksCallback,
+    pub get_ticks_remaining: GetTicksRemainingCallback,
+    pub get_cntpct: GetCNTPCTCallback,
+}
+
+extern {
+    pub fn dynarmic64_new<'a>(ud: *mut c_void, callbacks: &Callbacks) -> &'a mut Jit;
+    pub fn dynarmic64_delete(jit: &mut Jit);
+    pub fn dynarmic64_get_userdata(jit: &Jit) -> *mut c_void;
+    pub fn dynarmic64_run(jit: &mut Jit);
+    pub fn dynarmic64_step(jit: &mut Jit);
+
+    pub fn dynarmic64_register(jit: &Jit, index: usize) -> u64;
+    pub fn dynarmic64_set_register(jit: &Jit, index: usize, value: u64);
+
+    pub fn dynarmic64_sp(jit: &Jit) -> u64;
+    pub fn dynarmic64_set_sp(jit: &Jit, value: u64);
+
+    pub fn dynarmic64_pc(jit: &Jit) -> u64;
+    pub fn dynarmic64_set_pc(jit: &Jit, value: u64);
+
+    pub fn dynarmic64_vector(jit: &Jit, index: usize) -> Vector;
+    pub fn dynarmic64_set_vector(jit: &Jit, index: usize, value: Vector);
+
+    pub fn dynarmic64_fpcr(jit: &Jit) -> u32;
+    pub fn dynarmic64_set_fpcr(jit: &Jit, value: u32);
+
+    pub fn dynarmic64_fpsr(jit: &Jit) -> u32;
+    pub fn dynarmic64_set_fpsr(jit: &Jit, value: u32);
+
+    pub fn dynarmic64_pstate(jit: &Jit) -> u32;
+    pub fn dynarmic64_set_pstate(jit: &Jit, value: u32);
+
+    pub fn dynarmic64_tpidr_el0(jit: &Jit) -> u64;
+    pub fn dynarmic64_set_tpidr_el0(jit: &Jit, value: u64);
+
+    pub fn dynarmic64_tpidrro_el0(jit: &Jit) -> u64;
+    pub fn dynarmic64_set_tpidrro_el0(jit: &Jit, value: u64);
+
+    pub fn dynarmic64_halt(jit: &Jit);
+}
@@ -1,7 +1,10 @@
-use super::Jit;
+use super::{Jit, dynarmic_halt};
+use crate::unwind::catch_panic;
 use std::ffi::c_void;
 use std::marker::PhantomData;
 use std::cell::Cell;
+use std::io::{self, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
 
 pub type RawCallbackFn = extern fn(&mut Jit, user_arg: *mut c_void, arg0: u32, arg1: u32) -> u64;
 
@@ -14,7 +17,17 @@ pub struct RawCallback<'a> {
 
 extern fn callback_handler_delegator<'jit, J: for<'a> From<&'a mut Jit>, C: 'jit, H: CallbackHandler<J, C>>(jit: &mut Jit, user_arg: *mut c_void, arg0: u32, arg1: u32) -> u64 {
     let context = unsafe { &*(user_arg as *const C) };
-    H::handle(jit.into(), context, arg0, arg1)
+    catch_panic(|| H::handle((&mut *jit).into(), context, arg0, arg1)).unwrap_or_else(|| {
+        unsafe { dynarmic_halt(jit) };
+        0
+    })
+}
+
+// Stands in for whatever a compile method would have returned had it not panicked, so the JIT
+// stops before getting any further
+extern fn halt_after_panic(jit: &mut Jit, _user_arg: *mut c_void, _arg0: u32, _arg1: u32) -> u64 {
+    unsafe { dynarmic_halt(jit) };
+    0
 }
 
 pub trait CallbackHandler<J, C> {
@@ -22,6 +35,23 @@ pub trait CallbackHandler<J, C> {
 }
 
 impl RawCallback<'_> {
+    fn halt_after_panic<'jit>() -> RawCallback<'jit> {
+        RawCallback {
+            func: halt_after_panic,
+            user_arg: std::ptr::null_mut(),
+            _phantom: PhantomData,
+        }
+    }
+
+    // user_arg has to stay valid for as long as the JIT may call func with it
+    pub unsafe fn new<'jit>(func: RawCallbackFn, user_arg: *mut c_void) -> RawCallback<'jit> {
+        RawCallback {
+            func,
+            user_arg,
+            _phantom: PhantomData,
+        }
+    }
+
     pub fn from<'jit, J: for<'a> From<&'a mut Jit>, C: 'jit, H: CallbackHandler<J, C>>(context: &'jit C) -> RawCallback<'jit> {
         RawCallback {
             func: callback_handler_delegator::<J, C, H>,
@@ -74,11 +104,13 @@ pub type CallbackOrAccessTwoWords<'jit> = CallbackOrAccess<'jit, [&'jit Cell<u32
 pub type CallbackOrAccessTwoWordsMut<'jit> = CallbackOrAccess<'jit, [&'jit Cell<u32>; 2]>;
 
 #[repr(C)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum CoprocReg {
     C0, C1, C2, C3, C4, C5, C6, C7, C8, C9, C10, C11, C12, C13, C14, C15
 }
 
+// Dynarmic aborts when an instruction compiles to None, dynarmic::coprocessor raises an undefined
+// instruction exception for those instead
 #[allow(unused_variables)]
 pub trait Coprocessor<'jit> {
     fn compile_internal_operation(&'jit self, two: bool, opc1: u32, cr_d: CoprocReg, cr_n: CoprocReg, cr_m: CoprocReg, opc2: u32) -> Callback<'jit> {
@@ -108,10 +140,20 @@ pub trait Coprocessor<'jit> {
     fn compile_store_words(&'jit self, two: bool, long_transfer: bool, cr_d: CoprocReg, option: Option<u8>) -> Callback<'jit> {
         Callback::None
     }
+
+    // Savestate support, stateless coprocessors can leave these alone
+    fn save_state(&self, w: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn load_state(&self, r: &mut dyn Read) -> io::Result<()> {
+        Ok(())
+    }
 }
 
+// What the wrapper copies into its RustCoprocessor
 #[repr(C)]
-pub struct CoprocessorCallbacks<'jit> {
+pub struct RawCoprocessorCallbacks<'jit> {
     this: *mut c_void,
     compile_internal_operation: extern fn(this: *const c_void, two: bool, opc1: u32, cr_d: CoprocReg, cr_n: CoprocReg, cr_m: CoprocReg, opc2: u32) -> Callback<'jit>,
     compile_send_one_word: extern fn(this: *const c_void, two: bool, opc1: u32, cr_n: CoprocReg, cr_m: CoprocReg, opc2: u32) -> CallbackOrAccessOneWordMut<'jit>,
@@ -123,50 +165,109 @@ pub struct CoprocessorCallbacks<'jit> {
     destroy: extern fn(this: *mut c_void),
 }
 
+// The JIT takes ownership of the coprocessor when created with these
+pub struct CoprocessorCallbacks<'jit> {
+    raw: RawCoprocessorCallbacks<'jit>,
+    // Only called from Rust, so they stay out of the C struct
+    save_state: fn(this: *const c_void, w: &mut dyn Write) -> io::Result<()>,
+    load_state: fn(this: *const c_void, r: &mut dyn Read) -> io::Result<()>,
+}
+
+// Handle to a coprocessor owned by a JIT, valid as long as that JIT is
+#[derive(Copy, Clone)]
+pub struct CoprocessorState {
+    this: *const c_void,
+    save_state: fn(this: *const c_void, w: &mut dyn Write) -> io::Result<()>,
+    load_state: fn(this: *const c_void, r: &mut dyn Read) -> io::Result<()>,
+}
+
+impl CoprocessorState {
+    pub unsafe fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        (self.save_state)(self.this, w)
+    }
+
+    pub unsafe fn load(&self, r: &mut dyn Read) -> io::Result<()> {
+        (self.load_state)(self.this, r)
+    }
+}
+
 impl<'jit> CoprocessorCallbacks<'jit> {
-    pub fn callbacks_from<T: Coprocessor<'jit> + 'jit>(coproc: Box<T>) -> Self {
+    // Send because the coprocessor moves with its JIT, executors can run on another thread
+    pub fn callbacks_from<T: Coprocessor<'jit> + Send + 'jit>(coproc: Box<T>) -> Self {
         extern fn compile_internal_operation<'jit, T: Coprocessor<'jit> + 'jit>(this: *const c_void, two: bool, opc1: u32, cr_d: CoprocReg, cr_n: CoprocReg, cr_m: CoprocReg, opc2: u32) -> Callback<'jit> {
-            unsafe { &*(this as *const T) }.compile_internal_operation(two, opc1, cr_d, cr_n, cr_m, opc2)
+            catch_panic(|| unsafe { &*(this as *const T) }.compile_internal_operation(two, opc1, cr_d, cr_n, cr_m, opc2))
+                .unwrap_or_else(|| Callback::Some(RawCallback::halt_after_panic()))
         }
 
         extern fn compile_send_one_word<'jit, T: Coprocessor<'jit> + 'jit>(this: *const c_void, two: bool, opc1: u32, cr_n: CoprocReg, cr_m: CoprocReg, opc2: u32) -> CallbackOrAccessOneWordMut<'jit> {
-            unsafe { &*(this as *const T) }.compile_send_one_word(two, opc1, cr_n, cr_m, opc2)
+            catch_panic(|| unsafe { &*(this as *const T) }.compile_send_one_word(two, opc1, cr_n, cr_m, opc2))
+                .unwrap_or_else(|| CallbackOrAccess::Callback(RawCallback::halt_after_panic()))
         }
 
         extern fn compile_send_two_words<'jit, T: Coprocessor<'jit> + 'jit>(this: *const c_void, two: bool, opc: u32, cr_m: CoprocReg) -> CallbackOrAccessTwoWordsMut<'jit> {
-            unsafe { &*(this as *const T) }.compile_send_two_words(two, opc, cr_m)
+            catch_panic(|| unsafe { &*(this as *const T) }.compile_send_two_words(two, opc, cr_m))
+                .unwrap_or_else(|| CallbackOrAccess::Callback(RawCallback::halt_after_panic()))
         }
 
         extern fn compile_get_one_word<'jit, T: Coprocessor<'jit> + 'jit>(this: *const c_void, two: bool, opc1: u32, cr_n: CoprocReg, cr_m: CoprocReg, opc2: u32) -> CallbackOrAccessOneWord<'jit> {
-            unsafe { &*(this as *const T) }.compile_get_one_word(two, opc1, cr_n, cr_m, opc2)
+            catch_panic(|| unsafe { &*(this as *const T) }.compile_get_one_word(two, opc1, cr_n, cr_m, opc2))
+                .unwrap_or_else(|| CallbackOrAccess::Callback(RawCallback::halt_after_panic()))
         }
 
         extern fn compile_get_two_words<'jit, T: Coprocessor<'jit> + 'jit>(this: *const c_void, two: bool, opc: u32, cr_m: CoprocReg) -> CallbackOrAccessTwoWords<'jit> {
-            unsafe { &*(this as *const T) }.compile_get_two_words(two, opc, cr_m)
+            catch_panic(|| unsafe { &*(this as *const T) }.compile_get_two_words(two, opc, cr_m))
+                .unwrap_or_else(|| CallbackOrAccess::Callback(RawCallback::halt_after_panic()))
         }
 
         extern fn compile_load_words<'jit, T: Coprocessor<'jit> + 'jit>(this: *const c_void, two: bool, long_transfer: bool, cr_d: CoprocReg, option: FFIOption<u8>) -> Callback<'jit> {
-            unsafe { &*(this as *const T) }.compile_load_words(two, long_transfer, cr_d, option.into())
+            catch_panic(|| unsafe { &*(this as *const T) }.compile_load_words(two, long_transfer, cr_d, option.into()))
+                .unwrap_or_else(|| Callback::Some(RawCallback::halt_after_panic()))
         }
 
         extern fn compile_store_words<'jit, T: Coprocessor<'jit> + 'jit>(this: *const c_void, two: bool, long_transfer: bool, cr_d: CoprocReg, option: FFIOption<u8>) -> Callback<'jit> {
-            unsafe { &*(this as *const T) }.compile_store_words(two, long_transfer, cr_d, option.into())
+            catch_panic(|| unsafe { &*(this as *const T) }.compile_store_words(two, long_transfer, cr_d, option.into()))
+                .unwrap_or_else(|| Callback::Some(RawCallback::halt_after_panic()))
         }
 
         extern fn destroy<'jit, T: Coprocessor<'jit>>(this: *mut c_void) {
-            unsafe { Box::from_raw(this as *mut T); }
+            // Runs while the executor is dropped, nobody would take a panic kept by catch_panic
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(unsafe { Box::from_raw(this as *mut T) })));
+        }
+
+        fn save_state<'jit, T: Coprocessor<'jit> + 'jit>(this: *const c_void, w: &mut dyn Write) -> io::Result<()> {
+            unsafe { &*(this as *const T) }.save_state(w)
+        }
+
+        fn load_state<'jit, T: Coprocessor<'jit> + 'jit>(this: *const c_void, r: &mut dyn Read) -> io::Result<()> {
+            unsafe { &*(this as *const T) }.load_state(r)
         }
 
         CoprocessorCallbacks {
-            this: Box::into_raw(coproc) as *mut c_void,
-            compile_internal_operation: compile_internal_operation::<T>,
-            compile_send_one_word: compile_send_one_word::<T>,
-            compile_send_two_words: compile_send_two_words::<T>,
-            compile_get_one_word: compile_get_one_word::<T>,
-            compile_get_two_words: compile_get_two_words::<T>,
-            compile_load_words: compile_load_words::<T>,
-            compile_store_words: compile_store_words::<T>,
-            destroy: destroy::<T>,
+            raw: RawCoprocessorCallbacks {
+                this: Box::into_raw(coproc) as *mut c_void,
+                compile_internal_operation: compile_internal_operation::<T>,
+                compile_send_one_word: compile_send_one_word::<T>,
+                compile_send_two_words: compile_send_two_words::<T>,
+                compile_get_one_word: compile_get_one_word::<T>,
+                compile_get_two_words: compile_get_two_words::<T>,
+                compile_load_words: compile_load_words::<T>,
+                compile_store_words: compile_store_words::<T>,
+                destroy: destroy::<T>,
+            },
+            save_state: save_state::<T>,
+            load_state: load_state::<T>,
+        }
+    }
+
+    pub fn raw(&self) -> &RawCoprocessorCallbacks<'jit> {
+        &self.raw
+    }
+
+    pub fn state(&self) -> CoprocessorState {
+        CoprocessorState {
+            this: self.raw.this,
+            save_state: self.save_state,
+            load_state: self.load_state,
         }
     }
 }
@@ -1,21 +1,55 @@
 use std::ffi::c_void;
 
+pub mod a64;
 pub mod coprocessor;
+pub mod unwind;
 
 #[repr(C)]
 pub struct Jit(c_void);
 
+#[repr(C)]
+pub struct ExclusiveMonitor(c_void);
+
+// Dynarmic::A32::Context
+#[repr(C)]
+pub struct RawContext(c_void);
+
 trait MemoryType {}
 
+// Mirrors Dynarmic::A32::Exception, order matters
 #[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Exception {
     UndefinedInstruction,
     UnpredictableInstruction,
-    Breakpoint
+    DecodeError,
+    SendEvent,
+    SendEventLocal,
+    WaitForInterrupt,
+    WaitForEvent,
+    Yield,
+    Breakpoint,
+    PreloadData,
+    PreloadDataWithIntentToWrite,
+    PreloadInstruction,
+}
+
+impl Exception {
+    // Hints are only reported when hint hooking is enabled, execution continues after the instruction.
+    pub fn is_hint(self) -> bool {
+        match self {
+            Exception::UndefinedInstruction |
+            Exception::UnpredictableInstruction |
+            Exception::DecodeError |
+            Exception::Breakpoint => false,
+            _ => true,
+        }
+    }
 }
 
 pub type MemoryReadCallback<T> = extern fn(&mut Jit, u32) -> T;
 pub type MemoryWriteCallback<T> = extern fn(&mut Jit, u32, T) -> ();
+pub type MemoryWriteExclusiveCallback<T> = extern fn(&mut Jit, u32, T, T) -> bool;
 pub type IsReadOnlyMemoryCallback = extern fn(&mut Jit, u32) -> bool;
 pub type CallSVCCallback = extern fn(&mut Jit, u32) -> ();
 pub type ExceptionRaisedCallback = extern fn(&mut Jit, u32, Exception);
@@ -28,12 +62,19 @@ pub struct Callbacks {
     pub read16: MemoryReadCallback<u16>,
     pub read32: MemoryReadCallback<u32>,
     pub read64: MemoryReadCallback<u64>,
+    // Instruction fetches, always word aligned
+    pub read_code: MemoryReadCallback<u32>,
 
     pub write8: MemoryWriteCallback<u8>,
     pub write16: MemoryWriteCallback<u16>,
     pub write32: MemoryWriteCallback<u32>,
     pub write64: MemoryWriteCallback<u64>,
 
+    pub write_exclusive8: MemoryWriteExclusiveCallback<u8>,
+    pub write_exclusive16: MemoryWriteExclusiveCallback<u16>,
+    pub write_exclusive32: MemoryWriteExclusiveCallback<u32>,
+    pub write_exclusive64: MemoryWriteExclusiveCallback<u64>,
+
     pub is_read_only_memory: IsReadOnlyMemoryCallback,
     pub call_svc: CallSVCCallback,
     pub exception_raised: ExceptionRaisedCallback,
@@ -41,14 +82,36 @@ pub struct Callbacks {
     pub get_ticks_remaining: GetTicksRemainingCallback,
 }
 
-const PAGE_BITS: usize = 12;
-const NUM_PAGE_TABLE_ENTRIES: usize = 1 << (32 - PAGE_BITS);
+// Subset of Dynarmic::A32::UserConfig, get the defaults from dynarmic_config_default
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct Config {
+    pub optimizations: u32, // Dynarmic::OptimizationFlag bits
+    pub define_unpredictable_behaviour: bool,
+    pub hook_hint_instructions: bool,
+    pub always_little_endian: bool,
+    pub absolute_offset_page_table: bool,
+    pub code_cache_size: u32,
+    pub processor_id: usize,
+    pub global_monitor: *mut ExclusiveMonitor,
+}
+
+pub const PAGE_BITS: usize = 12;
+pub const NUM_PAGE_TABLE_ENTRIES: usize = 1 << (32 - PAGE_BITS);
+
+pub type PageTable = [*mut u8; NUM_PAGE_TABLE_ENTRIES];
 
 extern {
-    pub fn dynarmic_new<'a>(ud: *mut c_void, callbacks: &Callbacks, page_table: *const [*mut u8; NUM_PAGE_TABLE_ENTRIES], coprocessors: Option<&[Option<&coprocessor::CoprocessorCallbacks>; 16]>) -> &'a mut Jit;
+    pub fn dynarmic_exclusive_monitor_new(processor_count: usize) -> *mut ExclusiveMonitor;
+    pub fn dynarmic_exclusive_monitor_delete(monitor: *mut ExclusiveMonitor);
+    pub fn dynarmic_exclusive_monitor_clear(monitor: *mut ExclusiveMonitor);
+
+    pub fn dynarmic_config_default() -> Config;
+    pub fn dynarmic_new<'a>(ud: *mut c_void, callbacks: &Callbacks, config: &Config, page_table: *const PageTable, coprocessors: Option<&[Option<&coprocessor::RawCoprocessorCallbacks>; 16]>) -> &'a mut Jit;
     pub fn dynarmic_delete(jit: &mut Jit);
     pub fn dynarmic_get_userdata(jit: &Jit) -> *mut c_void;
     pub fn dynarmic_run(jit: &mut Jit);
+    pub fn dynarmic_step(jit: &mut Jit);
 
     #[link_name="dynarmic_regs"]
     pub fn dynarmic_regs_mut(jit: &mut Jit) -> &mut [u32; 16];
@@ -65,6 +128,24 @@ extern {
     pub fn dynarmic_set_fpscr(jit: &Jit, fpscr: u32);
     
     pub fn dynarmic_halt(jit: &Jit);
+
+    pub fn dynarmic_context_new() -> *mut RawContext;
+    pub fn dynarmic_context_clone(ctx: *const RawContext) -> *mut RawContext;
+    pub fn dynarmic_context_delete(ctx: *mut RawContext);
+    pub fn dynarmic_context_regs(ctx: *mut RawContext) -> *mut [u32; 16];
+    pub fn dynarmic_context_extregs(ctx: *mut RawContext) -> *mut [u32; 64];
+    pub fn dynarmic_context_cpsr(ctx: *const RawContext) -> u32;
+    pub fn dynarmic_context_set_cpsr(ctx: *mut RawContext, cpsr: u32);
+    pub fn dynarmic_context_fpscr(ctx: *const RawContext) -> u32;
+    pub fn dynarmic_context_set_fpscr(ctx: *mut RawContext, fpscr: u32);
+
+    pub fn dynarmic_save_context(jit: &Jit, ctx: *mut RawContext);
+    pub fn dynarmic_load_context(jit: &Jit, ctx: *const RawContext);
+    pub fn dynarmic_swap_context(jit: &Jit, ctx: *mut RawContext);
+
+    // Deferred until the end of the current block (and halts there) when called during a run
+    pub fn dynarmic_invalidate_cache_range(jit: &Jit, start: u32, length: usize);
+    pub fn dynarmic_clear_cache(jit: &Jit);
 }
 
 
@@ -133,6 +214,19 @@ mod tests {
             panic!("Unhandled write 64 0x{:X}: 0x{:X}", addr, value)
         }
 
+        extern fn write_exclusive8(jit: &mut Jit, addr: u32, value: u8, expected: u8) -> bool {
+            panic!("Unhandled exclusive write 8 0x{:X}: 0x{:X}", addr, value)
+        }
+        extern fn write_exclusive16(jit: &mut Jit, addr: u32, value: u16, expected: u16) -> bool {
+            panic!("Unhandled exclusive write 16 0x{:X}: 0x{:X}", addr, value)
+        }
+        extern fn write_exclusive32(jit: &mut Jit, addr: u32, value: u32, expected: u32) -> bool {
+            panic!("Unhandled exclusive write 32 0x{:X}: 0x{:X}", addr, value)
+        }
+        extern fn write_exclusive64(jit: &mut Jit, addr: u32, value: u64, expected: u64) -> bool {
+            panic!("Unhandled exclusive write 64 0x{:X}: 0x{:X}", addr, value)
+        }
+
         extern fn is_read_only_memory(jit: &mut Jit, addr: u32) -> bool { true }
         extern fn call_svc(jit: &mut Jit, svc: u32) { unimplemented!() }
         extern fn exception_raised(jit: &mut Jit, addr: u32, ex: Exception) { unimplemented!() }
@@ -152,10 +246,15 @@ mod tests {
             read16,
             read32,
             read64,
+            read_code: read32,
             write8,
             write16,
             write32,
             write64,
+            write_exclusive8,
+            write_exclusive16,
+            write_exclusive32,
+            write_exclusive64,
             is_read_only_memory,
             call_svc,
             exception_raised,
@@ -167,6 +266,7 @@ mod tests {
             dynarmic_new(
                 context.as_mut() as *mut Context as *mut _,
                 &callbacks,
+                &dynarmic_config_default(),
                 std::ptr::null(),
                 None
             )
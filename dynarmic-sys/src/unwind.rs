@@ -0,0 +1,51 @@
+// Panics can't unwind through the JIT's C++ frames. Callbacks run Rust code through catch_panic,
+// which keeps the panic until whoever called into the JIT resumes it with take_panic.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::panic::{self, AssertUnwindSafe};
+
+thread_local! {
+    static PANIC: RefCell<Option<Box<dyn Any + Send>>> = RefCell::new(None);
+}
+
+// None if f panicked. Only the first panic is kept until it's taken.
+pub fn catch_panic<R, F: FnOnce() -> R>(f: F) -> Option<R> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(value) => Some(value),
+        Err(payload) => {
+            PANIC.with(|panic| {
+                panic.borrow_mut().get_or_insert(payload);
+            });
+            None
+        }
+    }
+}
+
+pub fn panic_pending() -> bool {
+    PANIC.with(|panic| panic.borrow().is_some())
+}
+
+pub fn take_panic() -> Option<Box<dyn Any + Send>> {
+    PANIC.with(|panic| panic.borrow_mut().take())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_panic_is_kept() {
+        assert_eq!(catch_panic(|| 1), Some(1));
+        assert!(!panic_pending());
+
+        let first: Option<()> = catch_panic(|| panic!("first"));
+        assert!(first.is_none());
+        let _: Option<()> = catch_panic(|| panic!("second"));
+        assert!(panic_pending());
+
+        let payload = take_panic().unwrap();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"first"));
+        assert!(take_panic().is_none());
+    }
+}
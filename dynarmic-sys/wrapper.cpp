@@ -1,16 +1,33 @@
 #include <array>
 #include <cstdint>
 #include <optional>
+#include <utility>
 
 #include <dynarmic/A32/a32.h>
 #include <dynarmic/A32/config.h>
+#include <dynarmic/A32/context.h>
 #include <dynarmic/A32/coprocessor.h>
+#include <dynarmic/A64/a64.h>
+#include <dynarmic/A64/config.h>
+#include <dynarmic/exclusive_monitor.h>
 
 using u8 = std::uint8_t;
 using u16 = std::uint16_t;
 using u32 = std::uint32_t;
 using u64 = std::uint64_t;
 
+// Exception is passed straight through to Rust, keep in sync with dynarmic_sys::Exception
+static_assert(static_cast<int>(Dynarmic::A32::Exception::UndefinedInstruction) == 0);
+static_assert(static_cast<int>(Dynarmic::A32::Exception::DecodeError) == 2);
+static_assert(static_cast<int>(Dynarmic::A32::Exception::Breakpoint) == 8);
+static_assert(static_cast<int>(Dynarmic::A32::Exception::PreloadInstruction) == 11);
+
+// Same for dynarmic_sys::a64
+static_assert(static_cast<int>(Dynarmic::A64::Exception::UnallocatedEncoding) == 0);
+static_assert(static_cast<int>(Dynarmic::A64::Exception::Breakpoint) == 8);
+static_assert(static_cast<int>(Dynarmic::A64::DataCacheOperation::ZeroByVA) == 8);
+static_assert(static_cast<int>(Dynarmic::A64::InstructionCacheOperation::InvalidateAllToPoUInnerSharable) == 2);
+
 struct JitWrapper;
 
 class RustCallbacks : public Dynarmic::A32::UserCallbacks {
@@ -21,6 +38,8 @@ public:
   using MemoryReadCB = T(*)(Jit*, u32);
   template <typename T>
   using MemoryWriteCB = void(*)(Jit*, u32, T);
+  template <typename T>
+  using MemoryWriteExclusiveCB = bool(*)(Jit*, u32, T, T);
   using IsReadOnlyMemoryCB = bool(*)(Jit*, u32);
   using CallSVCCB = void(*)(Jit*, u32);
   using ExceptionRaisedCB = void(*)(Jit*, u32, Dynarmic::A32::Exception);
@@ -32,12 +51,18 @@ public:
     MemoryReadCB<u16> Read16;
     MemoryReadCB<u32> Read32;
     MemoryReadCB<u64> Read64;
+    MemoryReadCB<u32> ReadCode;
 
     MemoryWriteCB<u8> Write8;
     MemoryWriteCB<u16> Write16;
     MemoryWriteCB<u32> Write32;
     MemoryWriteCB<u64> Write64;
 
+    MemoryWriteExclusiveCB<u8> WriteExclusive8;
+    MemoryWriteExclusiveCB<u16> WriteExclusive16;
+    MemoryWriteExclusiveCB<u32> WriteExclusive32;
+    MemoryWriteExclusiveCB<u64> WriteExclusive64;
+
     IsReadOnlyMemoryCB IsReadOnlyMemory;
     CallSVCCB CallSVC;
     ExceptionRaisedCB ExceptionRaised;
@@ -61,6 +86,10 @@ public:
     return callbacks.Read64(jit, vaddr);
   }
 
+  u32 MemoryReadCode(u32 vaddr) override {
+    return callbacks.ReadCode(jit, vaddr);
+  }
+
   void MemoryWrite8(u32 vaddr, u8 value) override {
     return callbacks.Write8(jit, vaddr, value);
   }
@@ -77,6 +106,22 @@ public:
     return callbacks.Write64(jit, vaddr, value);
   }
 
+  bool MemoryWriteExclusive8(u32 vaddr, u8 value, u8 expected) override {
+    return callbacks.WriteExclusive8(jit, vaddr, value, expected);
+  }
+
+  bool MemoryWriteExclusive16(u32 vaddr, u16 value, u16 expected) override {
+    return callbacks.WriteExclusive16(jit, vaddr, value, expected);
+  }
+
+  bool MemoryWriteExclusive32(u32 vaddr, u32 value, u32 expected) override {
+    return callbacks.WriteExclusive32(jit, vaddr, value, expected);
+  }
+
+  bool MemoryWriteExclusive64(u32 vaddr, u64 value, u64 expected) override {
+    return callbacks.WriteExclusive64(jit, vaddr, value, expected);
+  }
+
   bool IsReadOnlyMemory(u32 vaddr) override {
     return callbacks.IsReadOnlyMemory ? callbacks.IsReadOnlyMemory(jit, vaddr) : false;
   }
@@ -162,6 +207,9 @@ public:
     Callback (*compile_load_words)(const void *self, bool two, bool long_transfer, CoprocReg cr_d, Option<u8> option);
     Callback (*compile_store_words)(const void *self, bool two, bool long_transfer, CoprocReg cr_d, Option<u8> option);
     void (*destroy)(void *self);
+    // Savestate hooks, only called from Rust
+    void *save_state;
+    void *load_state;
   };
 
   CallbackData callback_data;
@@ -282,11 +330,52 @@ struct JitWrapper {
   // JIT structure needs to be first so we can convert Jit* into JitWrapper*
   Dynarmic::A32::Jit jit;
   void *user_data;
+  // Holds the outgoing state in dynarmic_swap_context, a Context allocates when constructed
+  Dynarmic::A32::Context scratch;
 
   JitWrapper(void *ud, Dynarmic::A32::UserConfig config) : user_data(ud), jit(config) {}
 };
 
-extern "C" JitWrapper *dynarmic_new(void *user_data, RustCallbacks::CallbackData *callbacks, std::array<u8*, Dynarmic::A32::UserConfig::NUM_PAGE_TABLE_ENTRIES> *page_table, std::array<RustCoprocessor::CallbackData*, 16> *coprocessors) {
+// Options from Dynarmic::A32::UserConfig exposed to Rust
+struct RustConfig {
+  u32 optimizations;
+  bool define_unpredictable_behaviour;
+  bool hook_hint_instructions;
+  bool always_little_endian;
+  bool absolute_offset_page_table;
+  u32 code_cache_size;
+  std::size_t processor_id;
+  Dynarmic::ExclusiveMonitor *global_monitor;
+};
+
+extern "C" Dynarmic::ExclusiveMonitor *dynarmic_exclusive_monitor_new(std::size_t processor_count) {
+  return new Dynarmic::ExclusiveMonitor(processor_count);
+}
+
+extern "C" void dynarmic_exclusive_monitor_delete(Dynarmic::ExclusiveMonitor *monitor) {
+  delete monitor;
+}
+
+extern "C" void dynarmic_exclusive_monitor_clear(Dynarmic::ExclusiveMonitor *monitor) {
+  monitor->Clear();
+}
+
+extern "C" RustConfig dynarmic_config_default() {
+  auto config = Dynarmic::A32::UserConfig();
+
+  return RustConfig {
+    static_cast<u32>(config.optimizations),
+    config.define_unpredictable_behaviour,
+    config.hook_hint_instructions,
+    config.always_little_endian,
+    config.absolute_offset_page_table,
+    static_cast<u32>(config.code_cache_size),
+    config.processor_id,
+    config.global_monitor,
+  };
+}
+
+extern "C" JitWrapper *dynarmic_new(void *user_data, RustCallbacks::CallbackData *callbacks, RustConfig *rust_config, std::array<u8*, Dynarmic::A32::UserConfig::NUM_PAGE_TABLE_ENTRIES> *page_table, std::array<RustCoprocessor::CallbackData*, 16> *coprocessors) {
   auto dynarmicCallbacks = new RustCallbacks();
   dynarmicCallbacks->callbacks = *callbacks;
 
@@ -295,6 +384,15 @@ extern "C" JitWrapper *dynarmic_new(void *user_data, RustCallbacks::CallbackData
   config.callbacks = dynarmicCallbacks;
   config.page_table = page_table;
 
+  config.optimizations = static_cast<Dynarmic::OptimizationFlag>(rust_config->optimizations);
+  config.define_unpredictable_behaviour = rust_config->define_unpredictable_behaviour;
+  config.hook_hint_instructions = rust_config->hook_hint_instructions;
+  config.always_little_endian = rust_config->always_little_endian;
+  config.absolute_offset_page_table = rust_config->absolute_offset_page_table;
+  config.code_cache_size = rust_config->code_cache_size;
+  config.processor_id = rust_config->processor_id;
+  config.global_monitor = rust_config->global_monitor;
+
   if (coprocessors) {
     for (int i=0; i<16; i++) {
       auto cp = coprocessors->at(i);
@@ -326,6 +424,10 @@ extern "C" void dynarmic_run(JitWrapper *w) {
   w->jit.Run();
 }
 
+extern "C" void dynarmic_step(JitWrapper *w) {
+  w->jit.Step();
+}
+
 extern "C" u32 *dynarmic_regs(JitWrapper *w) {
   return w->jit.Regs().data();
 }
@@ -354,3 +456,315 @@ extern "C" void dynarmic_halt(JitWrapper *w) {
   w->jit.HaltExecution();
 }
 
+extern "C" Dynarmic::A32::Context *dynarmic_context_new() {
+  return new Dynarmic::A32::Context();
+}
+
+extern "C" Dynarmic::A32::Context *dynarmic_context_clone(const Dynarmic::A32::Context *ctx) {
+  return new Dynarmic::A32::Context(*ctx);
+}
+
+extern "C" void dynarmic_context_delete(Dynarmic::A32::Context *ctx) {
+  delete ctx;
+}
+
+extern "C" u32 *dynarmic_context_regs(Dynarmic::A32::Context *ctx) {
+  return ctx->Regs().data();
+}
+
+extern "C" u32 *dynarmic_context_extregs(Dynarmic::A32::Context *ctx) {
+  return ctx->ExtRegs().data();
+}
+
+extern "C" u32 dynarmic_context_cpsr(const Dynarmic::A32::Context *ctx) {
+  return ctx->Cpsr();
+}
+
+extern "C" void dynarmic_context_set_cpsr(Dynarmic::A32::Context *ctx, u32 value) {
+  ctx->SetCpsr(value);
+}
+
+extern "C" u32 dynarmic_context_fpscr(const Dynarmic::A32::Context *ctx) {
+  return ctx->Fpscr();
+}
+
+extern "C" void dynarmic_context_set_fpscr(Dynarmic::A32::Context *ctx, u32 value) {
+  ctx->SetFpscr(value);
+}
+
+extern "C" void dynarmic_save_context(JitWrapper *w, Dynarmic::A32::Context *ctx) {
+  w->jit.SaveContext(*ctx);
+}
+
+extern "C" void dynarmic_load_context(JitWrapper *w, const Dynarmic::A32::Context *ctx) {
+  w->jit.LoadContext(*ctx);
+}
+
+extern "C" void dynarmic_swap_context(JitWrapper *w, Dynarmic::A32::Context *ctx) {
+  w->jit.SaveContext(w->scratch);
+  w->jit.LoadContext(*ctx);
+  std::swap(*ctx, w->scratch);
+}
+
+extern "C" void dynarmic_invalidate_cache_range(JitWrapper *w, u32 start, std::size_t length) {
+  w->jit.InvalidateCacheRange(start, length);
+}
+
+extern "C" void dynarmic_clear_cache(JitWrapper *w) {
+  w->jit.ClearCache();
+}
+
+struct JitWrapper64;
+
+class RustCallbacks64 : public Dynarmic::A64::UserCallbacks {
+public:
+  using Jit = JitWrapper64;
+
+  // Dynarmic::A64::Vector as a plain struct, so it can be passed by value to and from Rust
+  struct Vector {
+    u64 lo;
+    u64 hi;
+  };
+
+  template <typename T>
+  using MemoryReadCB = T(*)(Jit*, u64);
+  template <typename T>
+  using MemoryWriteCB = void(*)(Jit*, u64, T);
+  using CallSVCCB = void(*)(Jit*, u32);
+  using ExceptionRaisedCB = void(*)(Jit*, u64, Dynarmic::A64::Exception);
+  using DataCacheOperationRaisedCB = void(*)(Jit*, Dynarmic::A64::DataCacheOperation, u64);
+  using InstructionCacheOperationRaisedCB = void(*)(Jit*, Dynarmic::A64::InstructionCacheOperation, u64);
+  using AddTicksCB = void(*)(Jit*, u64);
+  using GetTicksRemainingCB = u64(*)(Jit*);
+  using GetCNTPCTCB = u64(*)(Jit*);
+
+  struct CallbackData {
+    MemoryReadCB<u8> Read8;
+    MemoryReadCB<u16> Read16;
+    MemoryReadCB<u32> Read32;
+    MemoryReadCB<u64> Read64;
+    MemoryReadCB<Vector> Read128;
+
+    MemoryWriteCB<u8> Write8;
+    MemoryWriteCB<u16> Write16;
+    MemoryWriteCB<u32> Write32;
+    MemoryWriteCB<u64> Write64;
+    MemoryWriteCB<Vector> Write128;
+
+    CallSVCCB CallSVC;
+    ExceptionRaisedCB ExceptionRaised;
+    DataCacheOperationRaisedCB DataCacheOperationRaised;
+    InstructionCacheOperationRaisedCB InstructionCacheOperationRaised;
+    AddTicksCB AddTicks;
+    GetTicksRemainingCB GetTicksRemaining;
+    GetCNTPCTCB GetCNTPCT;
+  };
+
+  u8 MemoryRead8(u64 vaddr) override {
+    return callbacks.Read8(jit, vaddr);
+  }
+
+  u16 MemoryRead16(u64 vaddr) override {
+    return callbacks.Read16(jit, vaddr);
+  }
+
+  u32 MemoryRead32(u64 vaddr) override {
+    return callbacks.Read32(jit, vaddr);
+  }
+
+  u64 MemoryRead64(u64 vaddr) override {
+    return callbacks.Read64(jit, vaddr);
+  }
+
+  Dynarmic::A64::Vector MemoryRead128(u64 vaddr) override {
+    auto value = callbacks.Read128(jit, vaddr);
+    return {value.lo, value.hi};
+  }
+
+  void MemoryWrite8(u64 vaddr, u8 value) override {
+    return callbacks.Write8(jit, vaddr, value);
+  }
+
+  void MemoryWrite16(u64 vaddr, u16 value) override {
+    return callbacks.Write16(jit, vaddr, value);
+  }
+
+  void MemoryWrite32(u64 vaddr, u32 value) override {
+    return callbacks.Write32(jit, vaddr, value);
+  }
+
+  void MemoryWrite64(u64 vaddr, u64 value) override {
+    return callbacks.Write64(jit, vaddr, value);
+  }
+
+  void MemoryWrite128(u64 vaddr, Dynarmic::A64::Vector value) override {
+    return callbacks.Write128(jit, vaddr, Vector {value[0], value[1]});
+  }
+
+  void InterpreterFallback(u64, std::size_t) override {
+    abort();
+  }
+
+  void CallSVC(u32 swi) override {
+    callbacks.CallSVC(jit, swi);
+  }
+
+  void ExceptionRaised(u64 pc, Dynarmic::A64::Exception exception) override {
+    callbacks.ExceptionRaised(jit, pc, exception);
+  }
+
+  void DataCacheOperationRaised(Dynarmic::A64::DataCacheOperation op, u64 value) override {
+    callbacks.DataCacheOperationRaised(jit, op, value);
+  }
+
+  void InstructionCacheOperationRaised(Dynarmic::A64::InstructionCacheOperation op, u64 value) override {
+    callbacks.InstructionCacheOperationRaised(jit, op, value);
+  }
+
+  void AddTicks(u64 ticks) override {
+    callbacks.AddTicks(jit, ticks);
+  }
+
+  u64 GetTicksRemaining() override {
+    return callbacks.GetTicksRemaining(jit);
+  }
+
+  u64 GetCNTPCT() override {
+    return callbacks.GetCNTPCT(jit);
+  }
+
+  CallbackData callbacks;
+  Jit* jit = nullptr;
+};
+
+struct JitWrapper64 {
+  Dynarmic::A64::Jit jit;
+  void *user_data;
+  // Backing storage for the thread ID registers, dynarmic only keeps pointers to them
+  u64 tpidr_el0 = 0;
+  u64 tpidrro_el0 = 0;
+
+  JitWrapper64(void *ud, Dynarmic::A64::UserConfig config) : jit(WithThreadRegisters(config, this)), user_data(ud) {}
+
+  static Dynarmic::A64::UserConfig WithThreadRegisters(Dynarmic::A64::UserConfig config, JitWrapper64 *w) {
+    config.tpidr_el0 = &w->tpidr_el0;
+    config.tpidrro_el0 = &w->tpidrro_el0;
+    return config;
+  }
+};
+
+extern "C" JitWrapper64 *dynarmic64_new(void *user_data, RustCallbacks64::CallbackData *callbacks) {
+  auto dynarmicCallbacks = new RustCallbacks64();
+  dynarmicCallbacks->callbacks = *callbacks;
+
+  auto config = Dynarmic::A64::UserConfig();
+
+  config.callbacks = dynarmicCallbacks;
+  // DC ZVA is left to the Rust side, which zeroes a 64 byte block to match DCZID_EL0
+  config.hook_data_cache_operations = true;
+  config.dczid_el0 = 4;
+  config.ctr_el0 = 0x8444c004;
+  config.cntfrq_el0 = 19200000;
+
+  auto jit = new JitWrapper64(
+    user_data,
+    config
+  );
+
+  dynarmicCallbacks->jit = jit;
+
+  return jit;
+}
+
+extern "C" void dynarmic64_delete(JitWrapper64 *w) {
+  delete w;
+}
+
+extern "C" void *dynarmic64_get_userdata(JitWrapper64 *w) {
+  return w->user_data;
+}
+
+extern "C" void dynarmic64_run(JitWrapper64 *w) {
+  w->jit.Run();
+}
+
+extern "C" void dynarmic64_step(JitWrapper64 *w) {
+  w->jit.Step();
+}
+
+extern "C" u64 dynarmic64_register(JitWrapper64 *w, std::size_t index) {
+  return w->jit.GetRegister(index);
+}
+
+extern "C" void dynarmic64_set_register(JitWrapper64 *w, std::size_t index, u64 value) {
+  w->jit.SetRegister(index, value);
+}
+
+extern "C" u64 dynarmic64_sp(JitWrapper64 *w) {
+  return w->jit.GetSP();
+}
+
+extern "C" void dynarmic64_set_sp(JitWrapper64 *w, u64 value) {
+  w->jit.SetSP(value);
+}
+
+extern "C" u64 dynarmic64_pc(JitWrapper64 *w) {
+  return w->jit.GetPC();
+}
+
+extern "C" void dynarmic64_set_pc(JitWrapper64 *w, u64 value) {
+  w->jit.SetPC(value);
+}
+
+extern "C" RustCallbacks64::Vector dynarmic64_vector(JitWrapper64 *w, std::size_t index) {
+  auto value = w->jit.GetVector(index);
+  return {value[0], value[1]};
+}
+
+extern "C" void dynarmic64_set_vector(JitWrapper64 *w, std::size_t index, RustCallbacks64::Vector value) {
+  w->jit.SetVector(index, {value.lo, value.hi});
+}
+
+extern "C" u32 dynarmic64_fpcr(JitWrapper64 *w) {
+  return w->jit.GetFpcr();
+}
+
+extern "C" void dynarmic64_set_fpcr(JitWrapper64 *w, u32 value) {
+  w->jit.SetFpcr(value);
+}
+
+extern "C" u32 dynarmic64_fpsr(JitWrapper64 *w) {
+  return w->jit.GetFpsr();
+}
+
+extern "C" void dynarmic64_set_fpsr(JitWrapper64 *w, u32 value) {
+  w->jit.SetFpsr(value);
+}
+
+extern "C" u32 dynarmic64_pstate(JitWrapper64 *w) {
+  return w->jit.GetPstate();
+}
+
+extern "C" void dynarmic64_set_pstate(JitWrapper64 *w, u32 value) {
+  w->jit.SetPstate(value);
+}
+
+extern "C" u64 dynarmic64_tpidr_el0(JitWrapper64 *w) {
+  return w->tpidr_el0;
+}
+
+extern "C" void dynarmic64_set_tpidr_el0(JitWrapper64 *w, u64 value) {
+  w->tpidr_el0 = value;
+}
+
+extern "C" u64 dynarmic64_tpidrro_el0(JitWrapper64 *w) {
+  return w->tpidrro_el0;
+}
+
+extern "C" void dynarmic64_set_tpidrro_el0(JitWrapper64 *w, u64 value) {
+  w->tpidrro_el0 = value;
+}
+
+extern "C" void dynarmic64_halt(JitWrapper64 *w) {
+  w->jit.HaltExecution();
+}
@@ -0,0 +1,477 @@
+use dynarmic_sys::a64::*;
+use dynarmic_sys::unwind;
+use std::any::Any;
+use std::cell::RefCell;
+
+use crate::memory::{AccessKind, Memory, MemoryFault, MemoryImpl, Primitive};
+use crate::{DataAbort, HaltReason, RunResult, RunState};
+
+pub use dynarmic_sys::a64::{Exception, DataCacheOperation, InstructionCacheOperation};
+
+pub type DataAbort64 = DataAbort<u64>;
+pub type HaltReason64 = HaltReason<u64, Exception>;
+pub type RunResult64 = RunResult<u64, Exception>;
+
+// Matches the DCZID_EL0 value set up by the wrapper
+const ZVA_BLOCK_SIZE: u64 = 64;
+
+pub trait Memory64 {
+    fn read<T: Primitive>(&self, addr: u64) -> Result<T, MemoryFault<u64>>;
+    fn write<T: Primitive>(&self, addr: u64, value: T) -> Result<(), MemoryFault<u64>>;
+}
+
+// MemoryImpl only covers the lower 4GiB, anything above faults
+impl Memory64 for MemoryImpl {
+    fn read<T: Primitive>(&self, addr: u64) -> Result<T, MemoryFault<u64>> {
+        let fault = MemoryFault {
+            addr,
+            size: T::SIZE,
+            kind: AccessKind::Read,
+        };
+        if !fits_32bit(addr, T::SIZE) {
+            return Err(fault);
+        }
+        Memory::read(self, addr as u32).map_err(|_| fault)
+    }
+
+    fn write<T: Primitive>(&self, addr: u64, value: T) -> Result<(), MemoryFault<u64>> {
+        let fault = MemoryFault {
+            addr,
+            size: T::SIZE,
+            kind: AccessKind::Write,
+        };
+        if !fits_32bit(addr, T::SIZE) {
+            return Err(fault);
+        }
+        Memory::write(self, addr as u32, value).map_err(|_| fault)
+    }
+}
+
+fn fits_32bit(addr: u64, size: usize) -> bool {
+    addr.checked_add(size as u64).is_some_and(|end| end <= 1 << 32)
+}
+
+pub trait Handlers64: Sized {
+    type Memory: Memory64;
+
+    fn memory(&self) -> &Self::Memory;
+
+    fn handle_svc(&mut self, _context: JitContext64, _swi: u32) {}
+
+    // Called for unallocated/reserved/unpredictable encodings, BRK and (if hooked) hint instructions.
+    // By default hints are ignored and everything else halts execution.
+    fn handle_exception(&mut self, context: JitContext64, pc: u64, exception: Exception) {
+        if !exception.is_hint() {
+            context.halt_with_reason(HaltReason::Exception { pc, exception });
+        }
+    }
+
+    // Only DC ZVA has a visible effect, by default it zeroes the block containing addr
+    fn handle_data_cache_operation(&mut self, context: JitContext64, op: DataCacheOperation, addr: u64) {
+        if op != DataCacheOperation::ZeroByVA {
+            return;
+        }
+
+        let base = addr & !(ZVA_BLOCK_SIZE - 1);
+        for offset in (0..ZVA_BLOCK_SIZE).step_by(8) {
+            if let Err(fault) = self.memory().write(base + offset, 0u64) {
+                let pc = context.pc();
+                context.halt_with_reason(HaltReason::DataAbort(DataAbort { pc, fault }));
+                return;
+            }
+        }
+    }
+
+    fn handle_instruction_cache_operation(&mut self, _context: JitContext64, _op: InstructionCacheOperation, _addr: u64) {}
+
+    // Value read from CNTPCT_EL0, defaults to the number of ticks executed
+    fn cntpct(&mut self, context: JitContext64) -> u64 {
+        context.ticks_executed()
+    }
+}
+
+// RunState has to come first so JitContext64 can find it without knowing H
+#[repr(C)]
+pub struct Context64<H: Handlers64> {
+    state: RunState<u64, Exception>,
+    handlers: H,
+}
+
+pub struct JitContext64<'a> {
+    jit: RefCell<&'a mut Jit>,
+}
+
+impl<'a> JitContext64<'a> {
+    fn state(&self) -> &RunState<u64, Exception> {
+        unsafe { &*(dynarmic64_get_userdata(*self.jit.borrow()) as *const RunState<u64, Exception>) }
+    }
+
+    // X0-X30
+    pub fn x(&self, index: usize) -> u64 {
+        assert!(index < 31, "X{} does not exist", index);
+        unsafe { dynarmic64_register(*self.jit.borrow(), index) }
+    }
+
+    pub fn set_x(&self, index: usize, value: u64) {
+        assert!(index < 31, "X{} does not exist", index);
+        unsafe { dynarmic64_set_register(*self.jit.borrow(), index, value) }
+    }
+
+    pub fn sp(&self) -> u64 {
+        unsafe { dynarmic64_sp(*self.jit.borrow()) }
+    }
+
+    pub fn set_sp(&self, value: u64) {
+        unsafe { dynarmic64_set_sp(*self.jit.borrow(), value) }
+    }
+
+    pub fn pc(&self) -> u64 {
+        unsafe { dynarmic64_pc(*self.jit.borrow()) }
+    }
+
+    pub fn set_pc(&self, value: u64) {
+        unsafe { dynarmic64_set_pc(*self.jit.borrow(), value) }
+    }
+
+    pub fn pstate(&self) -> u32 {
+        unsafe { dynarmic64_pstate(*self.jit.borrow()) }
+    }
+
+    pub fn set_pstate(&self, value: u32) {
+        unsafe { dynarmic64_set_pstate(*self.jit.borrow(), value) }
+    }
+
+    // V0-V31
+    pub fn vector(&self, index: usize) -> u128 {
+        assert!(index < 32, "V{} does not exist", index);
+        unsafe { dynarmic64_vector(*self.jit.borrow(), index) }.into()
+    }
+
+    pub fn set_vector(&self, index: usize, value: u128) {
+        assert!(index < 32, "V{} does not exist", index);
+        unsafe { dynarmic64_set_vector(*self.jit.borrow(), index, value.into()) }
+    }
+
+    pub fn fpcr(&self) -> u32 {
+        unsafe { dynarmic64_fpcr(*self.jit.borrow()) }
+    }
+
+    pub fn set_fpcr(&self, value: u32) {
+        unsafe { dynarmic64_set_fpcr(*self.jit.borrow(), value) }
+    }
+
+    pub fn fpsr(&self) -> u32 {
+        unsafe { dynarmic64_fpsr(*self.jit.borrow()) }
+    }
+
+    pub fn set_fpsr(&self, value: u32) {
+        unsafe { dynarmic64_set_fpsr(*self.jit.borrow(), value) }
+    }
+
+    pub fn tpidr_el0(&self) -> u64 {
+        unsafe { dynarmic64_tpidr_el0(*self.jit.borrow()) }
+    }
+
+    pub fn set_tpidr_el0(&self, value: u64) {
+        unsafe { dynarmic64_set_tpidr_el0(*self.jit.borrow(), value) }
+    }
+
+    pub fn tpidrro_el0(&self) -> u64 {
+        unsafe { dynarmic64_tpidrro_el0(*self.jit.borrow()) }
+    }
+
+    pub fn set_tpidrro_el0(&self, value: u64) {
+        unsafe { dynarmic64_set_tpidrro_el0(*self.jit.borrow(), value) }
+    }
+
+    pub fn ticks_executed(&self) -> u64 {
+        self.state().ticks_executed()
+    }
+
+    pub fn halt(&self) {
+        self.halt_with_reason(HaltReason::Halted)
+    }
+
+    pub fn halt_with<P: Any + Send>(&self, payload: P) {
+        self.halt_with_reason(HaltReason::User(Box::new(payload)))
+    }
+
+    pub fn halt_with_reason(&self, reason: HaltReason64) {
+        self.state().record_halt(reason);
+        unsafe { dynarmic64_halt(*self.jit.borrow()) }
+    }
+}
+
+// Same as the A32 guard
+fn guard<R: Default, F: FnOnce(&mut Jit) -> R>(jit: &mut Jit, f: F) -> R {
+    let value = unwind::catch_panic(|| f(&mut *jit));
+    if unwind::panic_pending() {
+        unsafe { dynarmic64_halt(jit) }
+    }
+    value.unwrap_or_default()
+}
+
+impl<H: Handlers64> Context64<H> {
+    fn from_jit<'a, 'b: 'a>(jit: &'a mut Jit) -> &'b mut Self {
+        let ud = unsafe {
+            dynarmic64_get_userdata(jit)
+        };
+        unsafe { &mut *(ud as *mut Self) }
+    }
+
+    // Stops the JIT at the end of the current block
+    fn data_abort(&mut self, jit: &mut Jit, fault: MemoryFault<u64>) {
+        let pc = unsafe { dynarmic64_pc(jit) };
+        self.state.record_halt(HaltReason::DataAbort(DataAbort { pc, fault }));
+        unsafe { dynarmic64_halt(jit) }
+    }
+
+    extern fn read<T: Primitive>(jit: &mut Jit, addr: u64) -> T {
+        guard(jit, |jit| {
+            let context = Self::from_jit(jit);
+            match context.handlers.memory().read(addr) {
+                Ok(value) => value,
+                Err(fault) => {
+                    context.data_abort(jit, fault);
+                    T::default()
+                }
+            }
+        })
+    }
+
+    extern fn write<T: Primitive>(jit: &mut Jit, addr: u64, value: T) {
+        guard(jit, |jit| {
+            let context = Self::from_jit(jit);
+            if let Err(fault) = context.handlers.memory().write(addr, value) {
+                context.data_abort(jit, fault);
+            }
+        })
+    }
+
+    extern fn read128(jit: &mut Jit, addr: u64) -> Vector {
+        Self::read::<u128>(jit, addr).into()
+    }
+
+    extern fn write128(jit: &mut Jit, addr: u64, value: Vector) {
+        Self::write::<u128>(jit, addr, value.into())
+    }
+
+    extern fn call_svc(jit: &mut Jit, svc: u32) {
+        guard(jit, |jit| {
+            let context = Self::from_jit(jit);
+            let jit_context = JitContext64 {
+                jit: RefCell::new(jit),
+            };
+            context.handlers.handle_svc(jit_context, svc);
+        })
+    }
+
+    extern fn exception_raised(jit: &mut Jit, pc: u64, exception: Exception) {
+        guard(jit, |jit| {
+            let context = Self::from_jit(jit);
+            let jit_context = JitContext64 {
+                jit: RefCell::new(jit),
+            };
+            context.handlers.handle_exception(jit_context, pc, exception);
+        })
+    }
+
+    extern fn data_cache_operation_raised(jit: &mut Jit, op: DataCacheOperation, addr: u64) {
+        guard(jit, |jit| {
+            let context = Self::from_jit(jit);
+            let jit_context = JitContext64 {
+                jit: RefCell::new(jit),
+            };
+            context.handlers.handle_data_cache_operation(jit_context, op, addr);
+        })
+    }
+
+    extern fn instruction_cache_operation_raised(jit: &mut Jit, op: InstructionCacheOperation, addr: u64) {
+        guard(jit, |jit| {
+            let context = Self::from_jit(jit);
+            let jit_context = JitContext64 {
+                jit: RefCell::new(jit),
+            };
+            context.handlers.handle_instruction_cache_operation(jit_context, op, addr);
+        })
+    }
+
+    extern fn add_ticks(jit: &mut Jit, ticks: u64) {
+        Self::from_jit(jit).state.add_ticks(ticks)
+    }
+
+    extern fn get_ticks_remaining(jit: &mut Jit) -> u64 {
+        Self::from_jit(jit).state.ticks_remaining()
+    }
+
+    extern fn get_cntpct(jit: &mut Jit) -> u64 {
+        guard(jit, |jit| {
+            let context = Self::from_jit(jit);
+            let jit_context = JitContext64 {
+                jit: RefCell::new(jit),
+            };
+            context.handlers.cntpct(jit_context)
+        })
+    }
+
+    fn callbacks() -> Callbacks {
+        Callbacks {
+            read8: Self::read,
+            read16: Self::read,
+            read32: Self::read,
+            read64: Self::read,
+            read128: Self::read128,
+            write8: Self::write,
+            write16: Self::write,
+            write32: Self::write,
+            write64: Self::write,
+            write128: Self::write128,
+            call_svc: Self::call_svc,
+            exception_raised: Self::exception_raised,
+            data_cache_operation_raised: Self::data_cache_operation_raised,
+            instruction_cache_operation_raised: Self::instruction_cache_operation_raised,
+            add_ticks: Self::add_ticks,
+            get_ticks_remaining: Self::get_ticks_remaining,
+            get_cntpct: Self::get_cntpct,
+        }
+    }
+}
+
+pub struct Executor64<H: Handlers64> {
+    jit: &'static mut Jit,
+    context: *mut Context64<H>,
+}
+
+impl<H: Handlers64> Executor64<H> {
+    pub fn new(handlers: H) -> Self {
+        let context = Box::leak(Box::new(Context64 {
+            state: RunState::new(),
+            handlers,
+        }));
+
+        let context_ptr = context as *mut Context64<H>;
+
+        let callbacks = Context64::<H>::callbacks();
+
+        let jit = unsafe {
+            dynarmic64_new(
+                context_ptr as *mut _,
+                &callbacks,
+            )
+        };
+
+        Executor64 {
+            jit,
+            context: context_ptr,
+        }
+    }
+
+    fn state(&self) -> &RunState<u64, Exception> {
+        unsafe { &(*self.context).state }
+    }
+
+    // Runs until halted, see Executor::run
+    pub fn run(&mut self) -> HaltReason64 {
+        self.run_for(u64::MAX).reason
+    }
+
+    pub fn run_for(&mut self, ticks: u64) -> RunResult64 {
+        self.state().discard_panic();
+        if ticks == 0 {
+            return RunResult {
+                reason: HaltReason::TicksExhausted,
+                ticks_executed: 0,
+            };
+        }
+
+        let start = self.ticks_executed();
+        self.state().set_ticks_remaining(ticks);
+        unsafe { dynarmic64_run(self.jit) }
+        self.state().resume_panic();
+        self.state().set_ticks_remaining(0);
+
+        RunResult {
+            reason: self.state().take_halt_reason(HaltReason::TicksExhausted),
+            ticks_executed: self.ticks_executed() - start,
+        }
+    }
+
+    pub fn step(&mut self) -> HaltReason64 {
+        self.state().discard_panic();
+        self.state().set_ticks_remaining(1);
+        unsafe { dynarmic64_step(self.jit) }
+        self.state().resume_panic();
+        self.state().set_ticks_remaining(0);
+        self.state().take_halt_reason(HaltReason::Stepped)
+    }
+
+    pub fn ticks_executed(&self) -> u64 {
+        self.state().ticks_executed()
+    }
+
+    pub fn context(&mut self) -> JitContext64<'_> {
+        JitContext64 {
+            jit: RefCell::new(self.jit),
+        }
+    }
+}
+
+impl<H: Handlers64> Drop for Executor64<H> {
+    fn drop(&mut self) {
+        unsafe { dynarmic64_delete(self.jit) }
+        drop(unsafe { Box::from_raw(self.context) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upper_memory_faults() {
+        let mut mem = MemoryImpl::new();
+        mem.map_memory(0xFFFFF000, 1, false);
+        Memory64::write(&mem, 0xFFFFFFF0, !0u128).unwrap();
+        assert_eq!(Memory64::read::<u128>(&mem, 0xFFFFFFF0), Ok(!0u128));
+        assert_eq!(Memory64::read::<u32>(&mem, 0xFFFFFFFE), Err(MemoryFault {
+            addr: 0xFFFFFFFE,
+            size: 4,
+            kind: AccessKind::Read,
+        }));
+        assert!(Memory64::read::<u8>(&mem, 0x1_0000_0000).is_err());
+    }
+
+    #[test]
+    fn executor_runs_guest_code() {
+        use crate::testing::TestHandlers;
+
+        let mut mem = MemoryImpl::new();
+
+        mem.map_memory(0x00000000, 1, true);
+        Memory::write(&mem, 0, 0xD28000A0u32).unwrap(); // movz x0, #5
+        Memory::write(&mem, 4, 0x8B010000u32).unwrap(); // add x0, x0, x1
+        Memory::write(&mem, 8, 0xD4000021u32).unwrap(); // svc #1
+        Memory::write(&mem, 12, 0x14000000u32).unwrap(); // b 12
+
+        let mut executor = Executor64::new(TestHandlers::new(mem).on_svc64(|_, context, swi| {
+            let x0 = context.x(0);
+            context.halt_with((swi, x0));
+        }));
+
+        {
+            let context = executor.context();
+            context.set_x(1, 0x1_0000_0000);
+            context.set_vector(31, 0x1234_5678_9ABC_DEF0_0FED_CBA9_8765_4321);
+            context.set_pc(0);
+        }
+
+        match executor.run() {
+            HaltReason::User(payload) => assert_eq!(payload.downcast_ref::<(u32, u64)>(), Some(&(1, 0x1_0000_0005))),
+            reason => panic!("Unexpected halt: {:?}", reason),
+        }
+
+        let context = executor.context();
+        assert_eq!(context.pc(), 12);
+        assert_eq!(context.vector(31), 0x1234_5678_9ABC_DEF0_0FED_CBA9_8765_4321);
+    }
+}
@@ -0,0 +1,131 @@
+use dynarmic_sys::{Config, dynarmic_config_default};
+use std::ops::{BitOr, BitOrAssign};
+use std::sync::Arc;
+
+use crate::{Executor, ExclusiveMonitor, Handlers};
+
+// Dynarmic::OptimizationFlag
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Optimizations(pub u32);
+
+impl Optimizations {
+    pub const NONE: Optimizations = Optimizations(0);
+    pub const ALL_SAFE: Optimizations = Optimizations(0x0000FFFF);
+
+    pub const BLOCK_LINKING: Optimizations = Optimizations(0x00000001);
+    pub const RETURN_STACK_BUFFER: Optimizations = Optimizations(0x00000002);
+    pub const FAST_DISPATCH: Optimizations = Optimizations(0x00000004);
+    pub const GET_SET_ELIMINATION: Optimizations = Optimizations(0x00000008);
+    pub const CONST_PROP: Optimizations = Optimizations(0x00000010);
+    pub const MISC_IR_OPT: Optimizations = Optimizations(0x00000020);
+
+    // Trade floating point accuracy for speed
+    pub const UNSAFE_UNFUSE_FMA: Optimizations = Optimizations(0x00010000);
+    pub const UNSAFE_REDUCED_ERROR_FP: Optimizations = Optimizations(0x00020000);
+
+    pub fn contains(self, other: Optimizations) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn with(self, other: Optimizations, enable: bool) -> Optimizations {
+        if enable {
+            Optimizations(self.0 | other.0)
+        } else {
+            Optimizations(self.0 & !other.0)
+        }
+    }
+}
+
+impl BitOr for Optimizations {
+    type Output = Optimizations;
+
+    fn bitor(self, rhs: Optimizations) -> Optimizations {
+        Optimizations(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for Optimizations {
+    fn bitor_assign(&mut self, rhs: Optimizations) {
+        self.0 |= rhs.0;
+    }
+}
+
+// Everything not set here keeps dynarmic's default
+pub struct ExecutorBuilder<H: Handlers> {
+    handlers: H,
+    config: Config,
+    monitor: Option<Arc<ExclusiveMonitor>>,
+}
+
+impl<H: Handlers> ExecutorBuilder<H> {
+    pub fn new(handlers: H) -> Self {
+        ExecutorBuilder {
+            handlers,
+            config: unsafe { dynarmic_config_default() },
+            monitor: None,
+        }
+    }
+
+    pub fn optimizations(mut self, optimizations: Optimizations) -> Self {
+        self.config.optimizations = optimizations.0;
+        self
+    }
+
+    // Shorthand for toggling Optimizations::FAST_DISPATCH
+    pub fn enable_fast_dispatch(mut self, enable: bool) -> Self {
+        self.config.optimizations = Optimizations(self.config.optimizations).with(Optimizations::FAST_DISPATCH, enable).0;
+        self
+    }
+
+    // Executes unpredictable instructions the way real hardware usually does instead of raising
+    // Exception::UnpredictableInstruction
+    pub fn define_unpredictable_behaviour(mut self, enable: bool) -> Self {
+        self.config.define_unpredictable_behaviour = enable;
+        self
+    }
+
+    // Reports WFI, WFE, SEV, YIELD and preloads to Handlers::handle_exception
+    pub fn hook_hint_instructions(mut self, enable: bool) -> Self {
+        self.config.hook_hint_instructions = enable;
+        self
+    }
+
+    // Ignores the CPSR E bit, SETEND becomes a no-op
+    pub fn always_little_endian(mut self, enable: bool) -> Self {
+        self.config.always_little_endian = enable;
+        self
+    }
+
+    // Page table entries become host pointers minus the guest page address. MemoryImpl's page
+    // table isn't laid out like that, only use this with a Memory that is.
+    pub fn absolute_offset_page_table(mut self, enable: bool) -> Self {
+        self.config.absolute_offset_page_table = enable;
+        self
+    }
+
+    // In bytes
+    pub fn code_cache_size(mut self, size: u32) -> Self {
+        self.config.code_cache_size = size;
+        self
+    }
+
+    // Shares LDREX/STREX reservations with the other executors using this monitor. Each of them
+    // needs a distinct processor_id below the monitor's processor count.
+    pub fn exclusive_monitor(mut self, monitor: Arc<ExclusiveMonitor>, processor_id: usize) -> Self {
+        assert!(processor_id < monitor.processor_count(),
+            "Processor id {} out of range for a monitor with {} processors", processor_id, monitor.processor_count());
+        self.config.processor_id = processor_id;
+        self.config.global_monitor = monitor.raw();
+        self.monitor = Some(monitor);
+        self
+    }
+
+    // Without exclusive_monitor the executor gets a monitor of its own, dynarmic needs one for
+    // LDREX/STREX
+    pub fn build(mut self) -> Executor<H> {
+        if self.monitor.is_none() {
+            self = self.exclusive_monitor(Arc::new(ExclusiveMonitor::new(1)), 0);
+        }
+        Executor::with_config(self.handlers, &self.config, self.monitor.unwrap())
+    }
+}
@@ -0,0 +1,765 @@
+// Coprocessors written with closures instead of raw callbacks. Compile methods return what an
+// instruction does, either direct access to a Cell or a boxed closure, and callbacks() turns the
+// coprocessor into something Handlers::make_coprocessors can return:
+//
+//   cp[15] = Some(coprocessor::callbacks(MyCp15::new()));
+//
+// Executors can move between threads, so coprocessors and their closures have to be Send. Closures
+// may borrow Sync parts of the coprocessor, Cells are only for Compiled::Access. Dynarmic doesn't
+// say when a compiled block goes away, so every closure is kept until the executor is dropped.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use dynarmic_sys::Jit;
+
+use crate::{savestate, Exception, JitContext};
+use crate::coproc::{self, CoprocReg, CoprocessorCallbacks, RawCallback};
+
+// MCR, CDP and friends either run a callback, use a Cell directly or are undefined
+pub enum Compiled<F, A = ()> {
+    Undefined,
+    Callback(F),
+    Access(A),
+}
+
+// CDP
+pub type InternalOperation<'cp> = Box<dyn Fn(&JitContext) + Send + 'cp>;
+// MCR, gets Rt
+pub type SendOneWord<'cp> = Box<dyn Fn(&JitContext, u32) + Send + 'cp>;
+// MCRR, gets Rt and Rt2
+pub type SendTwoWords<'cp> = Box<dyn Fn(&JitContext, u32, u32) + Send + 'cp>;
+// MRC, returns Rt
+pub type GetOneWord<'cp> = Box<dyn Fn(&JitContext) -> u32 + Send + 'cp>;
+// MRRC, returns Rt and Rt2
+pub type GetTwoWords<'cp> = Box<dyn Fn(&JitContext) -> (u32, u32) + Send + 'cp>;
+// LDC and STC, gets the address
+pub type LoadStoreWords<'cp> = Box<dyn Fn(&JitContext, u32) + Send + 'cp>;
+
+// Anything left at its default is undefined: executing it calls Handlers::handle_exception with
+// UndefinedInstruction, which halts by default. Unlike exceptions dynarmic raises itself, the PC
+// is the start of the block and the rest of the block still runs, with reads returning 0.
+#[allow(unused_variables)]
+pub trait Coprocessor {
+    fn compile_internal_operation(&self, two: bool, opc1: u32, cr_d: CoprocReg, cr_n: CoprocReg, cr_m: CoprocReg, opc2: u32) -> Option<InternalOperation<'_>> {
+        None
+    }
+
+    fn compile_send_one_word(&self, two: bool, opc1: u32, cr_n: CoprocReg, cr_m: CoprocReg, opc2: u32) -> Compiled<SendOneWord<'_>, &Cell<u32>> {
+        Compiled::Undefined
+    }
+
+    fn compile_send_two_words(&self, two: bool, opc: u32, cr_m: CoprocReg) -> Compiled<SendTwoWords<'_>, [&Cell<u32>; 2]> {
+        Compiled::Undefined
+    }
+
+    fn compile_get_one_word(&self, two: bool, opc1: u32, cr_n: CoprocReg, cr_m: CoprocReg, opc2: u32) -> Compiled<GetOneWord<'_>, &Cell<u32>> {
+        Compiled::Undefined
+    }
+
+    fn compile_get_two_words(&self, two: bool, opc: u32, cr_m: CoprocReg) -> Compiled<GetTwoWords<'_>, [&Cell<u32>; 2]> {
+        Compiled::Undefined
+    }
+
+    fn compile_load_words(&self, two: bool, long_transfer: bool, cr_d: CoprocReg, option: Option<u8>) -> Option<LoadStoreWords<'_>> {
+        None
+    }
+
+    fn compile_store_words(&self, two: bool, long_transfer: bool, cr_d: CoprocReg, option: Option<u8>) -> Option<LoadStoreWords<'_>> {
+        None
+    }
+
+    // Savestate support, stateless coprocessors can leave these alone
+    fn save_state(&self, w: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn load_state(&self, r: &mut dyn Read) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+macro_rules! forward_coprocessor {
+    ($($t:ty),*) => {$(
+        impl<C: Coprocessor + ?Sized> Coprocessor for $t {
+            fn compile_internal_operation(&self, two: bool, opc1: u32, cr_d: CoprocReg, cr_n: CoprocReg, cr_m: CoprocReg, opc2: u32) -> Option<InternalOperation<'_>> {
+                (**self).compile_internal_operation(two, opc1, cr_d, cr_n, cr_m, opc2)
+            }
+
+            fn compile_send_one_word(&self, two: bool, opc1: u32, cr_n: CoprocReg, cr_m: CoprocReg, opc2: u32) -> Compiled<SendOneWord<'_>, &Cell<u32>> {
+                (**self).compile_send_one_word(two, opc1, cr_n, cr_m, opc2)
+            }
+
+            fn compile_send_two_words(&self, two: bool, opc: u32, cr_m: CoprocReg) -> Compiled<SendTwoWords<'_>, [&Cell<u32>; 2]> {
+                (**self).compile_send_two_words(two, opc, cr_m)
+            }
+
+            fn compile_get_one_word(&self, two: bool, opc1: u32, cr_n: CoprocReg, cr_m: CoprocReg, opc2: u32) -> Compiled<GetOneWord<'_>, &Cell<u32>> {
+                (**self).compile_get_one_word(two, opc1, cr_n, cr_m, opc2)
+            }
+
+            fn compile_get_two_words(&self, two: bool, opc: u32, cr_m: CoprocReg) -> Compiled<GetTwoWords<'_>, [&Cell<u32>; 2]> {
+                (**self).compile_get_two_words(two, opc, cr_m)
+            }
+
+            fn compile_load_words(&self, two: bool, long_transfer: bool, cr_d: CoprocReg, option: Option<u8>) -> Option<LoadStoreWords<'_>> {
+                (**self).compile_load_words(two, long_transfer, cr_d, option)
+            }
+
+            fn compile_store_words(&self, two: bool, long_transfer: bool, cr_d: CoprocReg, option: Option<u8>) -> Option<LoadStoreWords<'_>> {
+                (**self).compile_store_words(two, long_transfer, cr_d, option)
+            }
+
+            fn save_state(&self, w: &mut dyn Write) -> io::Result<()> {
+                (**self).save_state(w)
+            }
+
+            fn load_state(&self, r: &mut dyn Read) -> io::Result<()> {
+                (**self).load_state(r)
+            }
+        }
+    )*};
+}
+
+// Lets the host keep a handle on coprocessor state after handing it to the executor
+forward_coprocessor!(&C, Arc<C>, Box<C>);
+
+pub fn callbacks<'jit, C: Coprocessor + Send + 'jit>(coprocessor: C) -> CoprocessorCallbacks<'jit> {
+    CoprocessorCallbacks::callbacks_from(Box::new(Closures {
+        callbacks: RefCell::new(Vec::new()),
+        coprocessor,
+    }))
+}
+
+// Every kind of closure ends up as one of these, matching dynarmic's callback signature
+type RawClosure<'cp> = Box<dyn Fn(&JitContext, u32, u32) -> u64 + Send + 'cp>;
+
+extern fn call_closure(jit: &mut Jit, user_arg: *mut c_void, arg0: u32, arg1: u32) -> u64 {
+    let closure = unsafe { &*(user_arg as *const RawClosure) };
+    crate::guard(jit, |jit| {
+        let context = JitContext {
+            jit: RefCell::new(jit),
+        };
+        closure(&context, arg0, arg1)
+    })
+}
+
+struct Closures<C> {
+    // Declared first so closures borrowing the coprocessor are dropped before it. Boxed again so
+    // the pointers handed to dynarmic stay put when the Vec grows.
+    #[allow(clippy::vec_box)]
+    callbacks: RefCell<Vec<Box<RawClosure<'static>>>>,
+    coprocessor: C,
+}
+
+impl<C> Closures<C> {
+    fn keep<'jit>(&'jit self, closure: RawClosure<'jit>) -> RawCallback<'jit> {
+        // Only ever borrows from self, which is boxed and outlives the closure
+        let closure: Box<RawClosure<'static>> = Box::new(unsafe { std::mem::transmute::<RawClosure<'jit>, RawClosure<'static>>(closure) });
+        let user_arg = &*closure as *const RawClosure as *mut c_void;
+        self.callbacks.borrow_mut().push(closure);
+        unsafe { RawCallback::new(call_closure, user_arg) }
+    }
+
+    // Dynarmic can't be told an instruction is undefined once it's decoded, it aborts instead
+    fn undefined<'jit>(&'jit self) -> RawCallback<'jit> {
+        self.keep(Box::new(|context, _, _| {
+            context.raise_exception(Exception::UndefinedInstruction);
+            0
+        }))
+    }
+
+    fn compile<'jit, F, A, B>(&'jit self, compiled: Compiled<F, A>, raw: impl FnOnce(F) -> RawClosure<'jit>, access: impl FnOnce(A) -> B) -> coproc::CallbackOrAccess<'jit, B> {
+        match compiled {
+            Compiled::Undefined => coproc::CallbackOrAccess::Callback(self.undefined()),
+            Compiled::Callback(f) => coproc::CallbackOrAccess::Callback(self.keep(raw(f))),
+            Compiled::Access(a) => coproc::CallbackOrAccess::Access(access(a)),
+        }
+    }
+
+    fn compile_option<'jit, F>(&'jit self, compiled: Option<F>, raw: impl FnOnce(F) -> RawClosure<'jit>) -> coproc::Callback<'jit> {
+        match compiled {
+            Some(f) => coproc::Callback::Some(self.keep(raw(f))),
+            None => coproc::Callback::Some(self.undefined()),
+        }
+    }
+}
+
+impl<'jit, C: Coprocessor + 'jit> coproc::Coprocessor<'jit> for Closures<C> {
+    fn compile_internal_operation(&'jit self, two: bool, opc1: u32, cr_d: CoprocReg, cr_n: CoprocReg, cr_m: CoprocReg, opc2: u32) -> coproc::Callback<'jit> {
+        let compiled = self.coprocessor.compile_internal_operation(two, opc1, cr_d, cr_n, cr_m, opc2);
+        self.compile_option(compiled, |f| Box::new(move |context, _, _| {
+            f(context);
+            0
+        }))
+    }
+
+    fn compile_send_one_word(&'jit self, two: bool, opc1: u32, cr_n: CoprocReg, cr_m: CoprocReg, opc2: u32) -> coproc::CallbackOrAccessOneWordMut<'jit> {
+        let compiled = self.coprocessor.compile_send_one_word(two, opc1, cr_n, cr_m, opc2);
+        self.compile(compiled, |f| Box::new(move |context, word, _| {
+            f(context, word);
+            0
+        }), |cell| cell)
+    }
+
+    fn compile_send_two_words(&'jit self, two: bool, opc: u32, cr_m: CoprocReg) -> coproc::CallbackOrAccessTwoWordsMut<'jit> {
+        let compiled = self.coprocessor.compile_send_two_words(two, opc, cr_m);
+        self.compile(compiled, |f| Box::new(move |context, word1, word2| {
+            f(context, word1, word2);
+            0
+        }), |cells| cells)
+    }
+
+    fn compile_get_one_word(&'jit self, two: bool, opc1: u32, cr_n: CoprocReg, cr_m: CoprocReg, opc2: u32) -> coproc::CallbackOrAccessOneWord<'jit> {
+        let compiled = self.coprocessor.compile_get_one_word(two, opc1, cr_n, cr_m, opc2);
+        self.compile(compiled, |f| Box::new(move |context, _, _| f(context) as u64), |cell| cell)
+    }
+
+    fn compile_get_two_words(&'jit self, two: bool, opc: u32, cr_m: CoprocReg) -> coproc::CallbackOrAccessTwoWords<'jit> {
+        let compiled = self.coprocessor.compile_get_two_words(two, opc, cr_m);
+        self.compile(compiled, |f| Box::new(move |context, _, _| {
+            let (word1, word2) = f(context);
+            word1 as u64 | (word2 as u64) << 32
+        }), |cells| cells)
+    }
+
+    fn compile_load_words(&'jit self, two: bool, long_transfer: bool, cr_d: CoprocReg, option: Option<u8>) -> coproc::Callback<'jit> {
+        let compiled = self.coprocessor.compile_load_words(two, long_transfer, cr_d, option);
+        self.compile_option(compiled, |f| Box::new(move |context, addr, _| {
+            f(context, addr);
+            0
+        }))
+    }
+
+    fn compile_store_words(&'jit self, two: bool, long_transfer: bool, cr_d: CoprocReg, option: Option<u8>) -> coproc::Callback<'jit> {
+        let compiled = self.coprocessor.compile_store_words(two, long_transfer, cr_d, option);
+        self.compile_option(compiled, |f| Box::new(move |context, addr, _| {
+            f(context, addr);
+            0
+        }))
+    }
+
+    fn save_state(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.coprocessor.save_state(w)
+    }
+
+    fn load_state(&self, r: &mut dyn Read) -> io::Result<()> {
+        self.coprocessor.load_state(r)
+    }
+}
+
+// Encodings a CoprocessorMap dispatches on. `two` selects the MCR2/MRC2/... forms.
+
+// MCR and MRC
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct OneWord {
+    pub two: bool,
+    pub opc1: u32,
+    pub cr_n: CoprocReg,
+    pub cr_m: CoprocReg,
+    pub opc2: u32,
+}
+
+impl OneWord {
+    pub fn new(opc1: u32, cr_n: CoprocReg, cr_m: CoprocReg, opc2: u32) -> OneWord {
+        OneWord { two: false, opc1, cr_n, cr_m, opc2 }
+    }
+}
+
+// MCRR and MRRC
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TwoWords {
+    pub two: bool,
+    pub opc: u32,
+    pub cr_m: CoprocReg,
+}
+
+impl TwoWords {
+    pub fn new(opc: u32, cr_m: CoprocReg) -> TwoWords {
+        TwoWords { two: false, opc, cr_m }
+    }
+}
+
+// CDP
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Operation {
+    pub two: bool,
+    pub opc1: u32,
+    pub cr_d: CoprocReg,
+    pub cr_n: CoprocReg,
+    pub cr_m: CoprocReg,
+    pub opc2: u32,
+}
+
+impl Operation {
+    pub fn new(opc1: u32, cr_d: CoprocReg, cr_n: CoprocReg, cr_m: CoprocReg, opc2: u32) -> Operation {
+        Operation { two: false, opc1, cr_d, cr_n, cr_m, opc2 }
+    }
+}
+
+// LDC and STC, option is only there for the unindexed addressing mode
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Transfer {
+    pub two: bool,
+    pub long_transfer: bool,
+    pub cr_d: CoprocReg,
+    pub option: Option<u8>,
+}
+
+impl Transfer {
+    pub fn new(cr_d: CoprocReg) -> Transfer {
+        Transfer { two: false, long_transfer: false, cr_d, option: None }
+    }
+}
+
+fn cr(reg: CoprocReg) -> String {
+    format!("{:?}", reg).to_lowercase()
+}
+
+fn mnemonic(name: &str, two: bool) -> String {
+    if two { format!("{}2", name) } else { name.to_string() }
+}
+
+// What a CoprocessorMap does with encodings nothing was registered for
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Unregistered {
+    // Raises an undefined instruction exception through Handlers::handle_exception, see Coprocessor
+    Undefined,
+    // Writes are dropped and reads return 0
+    Ignore,
+}
+
+// Coprocessor built from per-encoding handlers and storage instead of a hand-written match:
+//
+//   let tls = Arc::new(AtomicU32::new(0));
+//   let cp15 = CoprocessorMap::new(15)
+//       .word(OneWord::new(0, C13, C0, 2), 0)
+//       .shared_read_only_word(OneWord::new(0, C13, C0, 3), Arc::clone(&tls))
+//       .send_word(OneWord::new(0, C7, C10, 5), |_, _| atomic::fence(Ordering::SeqCst));
+//
+// With log_unregistered(true), every unregistered encoding is reported on stderr when it's compiled.
+// Handlers the map keeps, Sync since every compiled closure borrows them
+type OperationHandler = Box<dyn Fn(&JitContext) + Send + Sync>;
+type SendWordHandler = Box<dyn Fn(&JitContext, u32) + Send + Sync>;
+type GetWordHandler = Box<dyn Fn(&JitContext) -> u32 + Send + Sync>;
+type SendWordsHandler = Box<dyn Fn(&JitContext, u32, u32) + Send + Sync>;
+type GetWordsHandler = Box<dyn Fn(&JitContext) -> (u32, u32) + Send + Sync>;
+type TransferHandler = Box<dyn Fn(&JitContext, u32) + Send + Sync>;
+
+pub struct CoprocessorMap {
+    number: usize,
+    unregistered: Unregistered,
+    log_unregistered: bool,
+    operations: HashMap<Operation, OperationHandler>,
+    send_one: HashMap<OneWord, Compiled<SendWordHandler, Word>>,
+    get_one: HashMap<OneWord, Compiled<GetWordHandler, Word>>,
+    send_two: HashMap<TwoWords, Compiled<SendWordsHandler, Words>>,
+    get_two: HashMap<TwoWords, Compiled<GetWordsHandler, Words>>,
+    loads: HashMap<Transfer, TransferHandler>,
+    stores: HashMap<Transfer, TransferHandler>,
+    // Storage for word and words, in registration order
+    cells: Vec<Cell<u32>>,
+    // Access targets for Unregistered::Ignore
+    ignored: Cell<u32>,
+    zero: Cell<u32>,
+}
+
+// Where a registered word lives. The JIT accesses the map's own cells directly, words shared with
+// the host are atomic and go through callbacks.
+enum Word {
+    Cell(usize),
+    Shared(Arc<AtomicU32>),
+}
+
+enum Words {
+    Cells([usize; 2]),
+    Shared([Arc<AtomicU32>; 2]),
+}
+
+impl CoprocessorMap {
+    // number only shows up in log messages, the map still has to be put in the right slot
+    pub fn new(number: usize) -> CoprocessorMap {
+        assert!(number < 16, "Coprocessor number {} out of range", number);
+        CoprocessorMap {
+            number,
+            unregistered: Unregistered::Undefined,
+            log_unregistered: false,
+            operations: HashMap::new(),
+            send_one: HashMap::new(),
+            get_one: HashMap::new(),
+            send_two: HashMap::new(),
+            get_two: HashMap::new(),
+            loads: HashMap::new(),
+            stores: HashMap::new(),
+            cells: Vec::new(),
+            ignored: Cell::new(0),
+            zero: Cell::new(0),
+        }
+    }
+
+    pub fn unregistered(mut self, unregistered: Unregistered) -> Self {
+        self.unregistered = unregistered;
+        self
+    }
+
+    pub fn log_unregistered(mut self, enable: bool) -> Self {
+        self.log_unregistered = enable;
+        self
+    }
+
+    fn cell(&mut self, value: u32) -> usize {
+        self.cells.push(Cell::new(value));
+        self.cells.len() - 1
+    }
+
+    // MCR writes and MRC reads a Cell the map keeps, starting out as value. Only the guest and
+    // savestates see it.
+    pub fn word(mut self, encoding: OneWord, value: u32) -> Self {
+        let cell = self.cell(value);
+        self.send_one.insert(encoding, Compiled::Access(Word::Cell(cell)));
+        self.get_one.insert(encoding, Compiled::Access(Word::Cell(cell)));
+        self
+    }
+
+    // Read-only register, MCR stays unregistered
+    pub fn read_only_word(mut self, encoding: OneWord, value: u32) -> Self {
+        let cell = self.cell(value);
+        self.get_one.insert(encoding, Compiled::Access(Word::Cell(cell)));
+        self
+    }
+
+    // Like word, but the host keeps access and may change it while the executor runs
+    pub fn shared_word(mut self, encoding: OneWord, word: Arc<AtomicU32>) -> Self {
+        self.send_one.insert(encoding, Compiled::Access(Word::Shared(Arc::clone(&word))));
+        self.get_one.insert(encoding, Compiled::Access(Word::Shared(word)));
+        self
+    }
+
+    pub fn shared_read_only_word(mut self, encoding: OneWord, word: Arc<AtomicU32>) -> Self {
+        self.get_one.insert(encoding, Compiled::Access(Word::Shared(word)));
+        self
+    }
+
+    pub fn send_word<F: Fn(&JitContext, u32) + Send + Sync + 'static>(mut self, encoding: OneWord, f: F) -> Self {
+        self.send_one.insert(encoding, Compiled::Callback(Box::new(f)));
+        self
+    }
+
+    pub fn get_word<F: Fn(&JitContext) -> u32 + Send + Sync + 'static>(mut self, encoding: OneWord, f: F) -> Self {
+        self.get_one.insert(encoding, Compiled::Callback(Box::new(f)));
+        self
+    }
+
+    // MCRR writes and MRRC reads a pair of Cells, Rt goes to the first one
+    pub fn words(mut self, encoding: TwoWords, values: [u32; 2]) -> Self {
+        let cells = [self.cell(values[0]), self.cell(values[1])];
+        self.send_two.insert(encoding, Compiled::Access(Words::Cells(cells)));
+        self.get_two.insert(encoding, Compiled::Access(Words::Cells(cells)));
+        self
+    }
+
+    pub fn shared_words(mut self, encoding: TwoWords, words: [Arc<AtomicU32>; 2]) -> Self {
+        self.send_two.insert(encoding, Compiled::Access(Words::Shared(words.clone())));
+        self.get_two.insert(encoding, Compiled::Access(Words::Shared(words)));
+        self
+    }
+
+    pub fn send_words<F: Fn(&JitContext, u32, u32) + Send + Sync + 'static>(mut self, encoding: TwoWords, f: F) -> Self {
+        self.send_two.insert(encoding, Compiled::Callback(Box::new(f)));
+        self
+    }
+
+    pub fn get_words<F: Fn(&JitContext) -> (u32, u32) + Send + Sync + 'static>(mut self, encoding: TwoWords, f: F) -> Self {
+        self.get_two.insert(encoding, Compiled::Callback(Box::new(f)));
+        self
+    }
+
+    pub fn operation<F: Fn(&JitContext) + Send + Sync + 'static>(mut self, encoding: Operation, f: F) -> Self {
+        self.operations.insert(encoding, Box::new(f));
+        self
+    }
+
+    // f gets the address
+    pub fn load_words<F: Fn(&JitContext, u32) + Send + Sync + 'static>(mut self, encoding: Transfer, f: F) -> Self {
+        self.loads.insert(encoding, Box::new(f));
+        self
+    }
+
+    pub fn store_words<F: Fn(&JitContext, u32) + Send + Sync + 'static>(mut self, encoding: Transfer, f: F) -> Self {
+        self.stores.insert(encoding, Box::new(f));
+        self
+    }
+
+    fn report(&self, instruction: String) {
+        if self.log_unregistered {
+            eprintln!("Unregistered coprocessor instruction: {}", instruction);
+        }
+    }
+
+    fn undefined(context: &JitContext) {
+        context.raise_exception(Exception::UndefinedInstruction);
+    }
+
+    // For unregistered CDP, LDC and STC
+    fn unregistered_callback(&self) -> InternalOperation<'static> {
+        match self.unregistered {
+            Unregistered::Undefined => Box::new(Self::undefined),
+            Unregistered::Ignore => Box::new(|_| {}),
+        }
+    }
+
+    fn compile_transfer<'cp>(&'cp self, name: &str, transfers: &'cp HashMap<Transfer, TransferHandler>, encoding: Transfer) -> LoadStoreWords<'cp> {
+        if let Some(f) = transfers.get(&encoding) {
+            return Box::new(move |context, addr| f(context, addr));
+        }
+        let option = encoding.option.map(|option| format!(", {{{}}}", option)).unwrap_or_default();
+        let long = if encoding.long_transfer { "l" } else { "" };
+        self.report(format!("{}{} p{}, {}, [<Rn>]{}", mnemonic(name, encoding.two), long, self.number, cr(encoding.cr_d), option));
+        let f = self.unregistered_callback();
+        Box::new(move |context, _| f(context))
+    }
+}
+
+impl Coprocessor for CoprocessorMap {
+    fn compile_internal_operation(&self, two: bool, opc1: u32, cr_d: CoprocReg, cr_n: CoprocReg, cr_m: CoprocReg, opc2: u32) -> Option<InternalOperation<'_>> {
+        if let Some(f) = self.operations.get(&Operation { two, opc1, cr_d, cr_n, cr_m, opc2 }) {
+            return Some(Box::new(move |context| f(context)));
+        }
+        self.report(format!("{} p{}, {}, {}, {}, {}, {}", mnemonic("cdp", two), self.number, opc1, cr(cr_d), cr(cr_n), cr(cr_m), opc2));
+        Some(self.unregistered_callback())
+    }
+
+    fn compile_send_one_word(&self, two: bool, opc1: u32, cr_n: CoprocReg, cr_m: CoprocReg, opc2: u32) -> Compiled<SendOneWord<'_>, &Cell<u32>> {
+        match self.send_one.get(&OneWord { two, opc1, cr_n, cr_m, opc2 }) {
+            Some(Compiled::Callback(f)) => Compiled::Callback(Box::new(move |context, word| f(context, word))),
+            Some(Compiled::Access(Word::Cell(cell))) => Compiled::Access(&self.cells[*cell]),
+            Some(Compiled::Access(Word::Shared(target))) => Compiled::Callback(Box::new(move |_, word| target.store(word, Ordering::SeqCst))),
+            Some(Compiled::Undefined) | None => {
+                self.report(format!("{} p{}, {}, <Rt>, {}, {}, {}", mnemonic("mcr", two), self.number, opc1, cr(cr_n), cr(cr_m), opc2));
+                match self.unregistered {
+                    Unregistered::Undefined => Compiled::Callback(Box::new(|context, _| Self::undefined(context))),
+                    Unregistered::Ignore => Compiled::Access(&self.ignored),
+                }
+            }
+        }
+    }
+
+    fn compile_send_two_words(&self, two: bool, opc: u32, cr_m: CoprocReg) -> Compiled<SendTwoWords<'_>, [&Cell<u32>; 2]> {
+        match self.send_two.get(&TwoWords { two, opc, cr_m }) {
+            Some(Compiled::Callback(f)) => Compiled::Callback(Box::new(move |context, word1, word2| f(context, word1, word2))),
+            Some(Compiled::Access(Words::Cells([cell1, cell2]))) => Compiled::Access([&self.cells[*cell1], &self.cells[*cell2]]),
+            Some(Compiled::Access(Words::Shared([target1, target2]))) => Compiled::Callback(Box::new(move |_, word1, word2| {
+                target1.store(word1, Ordering::SeqCst);
+                target2.store(word2, Ordering::SeqCst);
+            })),
+            Some(Compiled::Undefined) | None => {
+                self.report(format!("{} p{}, {}, <Rt>, <Rt2>, {}", mnemonic("mcrr", two), self.number, opc, cr(cr_m)));
+                match self.unregistered {
+                    Unregistered::Undefined => Compiled::Callback(Box::new(|context, _, _| Self::undefined(context))),
+                    Unregistered::Ignore => Compiled::Access([&self.ignored, &self.ignored]),
+                }
+            }
+        }
+    }
+
+    fn compile_get_one_word(&self, two: bool, opc1: u32, cr_n: CoprocReg, cr_m: CoprocReg, opc2: u32) -> Compiled<GetOneWord<'_>, &Cell<u32>> {
+        match self.get_one.get(&OneWord { two, opc1, cr_n, cr_m, opc2 }) {
+            Some(Compiled::Callback(f)) => Compiled::Callback(Box::new(move |context| f(context))),
+            Some(Compiled::Access(Word::Cell(cell))) => Compiled::Access(&self.cells[*cell]),
+            Some(Compiled::Access(Word::Shared(source))) => Compiled::Callback(Box::new(move |_| source.load(Ordering::SeqCst))),
+            Some(Compiled::Undefined) | None => {
+                self.report(format!("{} p{}, {}, <Rt>, {}, {}, {}", mnemonic("mrc", two), self.number, opc1, cr(cr_n), cr(cr_m), opc2));
+                match self.unregistered {
+                    Unregistered::Undefined => Compiled::Callback(Box::new(|context| {
+                        Self::undefined(context);
+                        0
+                    })),
+                    Unregistered::Ignore => Compiled::Access(&self.zero),
+                }
+            }
+        }
+    }
+
+    fn compile_get_two_words(&self, two: bool, opc: u32, cr_m: CoprocReg) -> Compiled<GetTwoWords<'_>, [&Cell<u32>; 2]> {
+        match self.get_two.get(&TwoWords { two, opc, cr_m }) {
+            Some(Compiled::Callback(f)) => Compiled::Callback(Box::new(move |context| f(context))),
+            Some(Compiled::Access(Words::Cells([cell1, cell2]))) => Compiled::Access([&self.cells[*cell1], &self.cells[*cell2]]),
+            Some(Compiled::Access(Words::Shared([source1, source2]))) => Compiled::Callback(Box::new(move |_| {
+                (source1.load(Ordering::SeqCst), source2.load(Ordering::SeqCst))
+            })),
+            Some(Compiled::Undefined) | None => {
+                self.report(format!("{} p{}, {}, <Rt>, <Rt2>, {}", mnemonic("mrrc", two), self.number, opc, cr(cr_m)));
+                match self.unregistered {
+                    Unregistered::Undefined => Compiled::Callback(Box::new(|context| {
+                        Self::undefined(context);
+                        (0, 0)
+                    })),
+                    Unregistered::Ignore => Compiled::Access([&self.zero, &self.zero]),
+                }
+            }
+        }
+    }
+
+    fn compile_load_words(&self, two: bool, long_transfer: bool, cr_d: CoprocReg, option: Option<u8>) -> Option<LoadStoreWords<'_>> {
+        Some(self.compile_transfer("ldc", &self.loads, Transfer { two, long_transfer, cr_d, option }))
+    }
+
+    fn compile_store_words(&self, two: bool, long_transfer: bool, cr_d: CoprocReg, option: Option<u8>) -> Option<LoadStoreWords<'_>> {
+        Some(self.compile_transfer("stc", &self.stores, Transfer { two, long_transfer, cr_d, option }))
+    }
+
+    // Shared words belong to the host, only the map's own cells are saved
+    fn save_state(&self, w: &mut dyn Write) -> io::Result<()> {
+        let words: Vec<u32> = self.cells.iter().map(Cell::get).collect();
+        savestate::write_words(w, &words)
+    }
+
+    fn load_state(&self, r: &mut dyn Read) -> io::Result<()> {
+        let mut words = vec![0; self.cells.len()];
+        savestate::read_words(r, &mut words)?;
+        for (cell, word) in self.cells.iter().zip(words) {
+            cell.set(word);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cpsr, Executor, HaltReason, ProcessorMode};
+    use crate::memory::{Memory, MemoryImpl};
+    use crate::testing::TestHandlers;
+
+    #[test]
+    fn executor_closure_callbacks() {
+        struct Cp15 {
+            tpidrurw: AtomicU32,
+            tpidruro: Cell<u32>,
+        }
+
+        impl Coprocessor for Cp15 {
+            fn compile_send_one_word(&self, _two: bool, opc1: u32, cr_n: CoprocReg, cr_m: CoprocReg, opc2: u32) -> Compiled<SendOneWord<'_>, &Cell<u32>> {
+                match (opc1, cr_n, cr_m, opc2) {
+                    (0, CoprocReg::C13, CoprocReg::C0, 2) => {
+                        // Borrows just the atomic, the Cell keeps Cp15 from being Sync
+                        let tpidrurw = &self.tpidrurw;
+                        Compiled::Callback(Box::new(move |context, word| {
+                            tpidrurw.store(word + context.regs()[4], Ordering::SeqCst);
+                        }))
+                    }
+                    _ => Compiled::Undefined,
+                }
+            }
+
+            fn compile_get_one_word(&self, _two: bool, opc1: u32, cr_n: CoprocReg, cr_m: CoprocReg, opc2: u32) -> Compiled<GetOneWord<'_>, &Cell<u32>> {
+                match (opc1, cr_n, cr_m, opc2) {
+                    (0, CoprocReg::C13, CoprocReg::C0, 2) => {
+                        let tpidrurw = &self.tpidrurw;
+                        Compiled::Callback(Box::new(move |_| tpidrurw.load(Ordering::SeqCst)))
+                    }
+                    (0, CoprocReg::C13, CoprocReg::C0, 3) => Compiled::Access(&self.tpidruro),
+                    _ => Compiled::Undefined,
+                }
+            }
+        }
+
+        let mut memory = MemoryImpl::new();
+        memory.map_memory(0x0000, 1, false);
+        memory.write(0x00, 0xEE0D3F50u32).unwrap(); // mcr p15, 0, r3, c13, c0, 2
+        memory.write(0x04, 0xEE1D2F50u32).unwrap(); // mrc p15, 0, r2, c13, c0, 2
+        memory.write(0x08, 0xEE1D0F70u32).unwrap(); // mrc p15, 0, r0, c13, c0, 3
+        memory.write(0x0C, 0xEE1D1F90u32).unwrap(); // mrc p15, 0, r1, c13, c0, 4
+        memory.write(0x10, 0xEAFFFFFEu32).unwrap(); // b .
+
+        let mut executor = Executor::new(TestHandlers::new(memory).coprocessor(15, |_| callbacks(Cp15 {
+            tpidrurw: AtomicU32::new(0),
+            tpidruro: Cell::new(0x1234),
+        })));
+        {
+            let context = executor.context();
+            context.set_cpsr(Cpsr::new(ProcessorMode::User));
+            context.regs_mut()[3] = 0x40;
+            context.regs_mut()[4] = 2;
+            context.set_pc(0);
+        }
+
+        for _ in 0..3 {
+            assert!(matches!(executor.step(), HaltReason::Stepped));
+        }
+        assert_eq!(executor.context().regs()[2], 0x42);
+        assert_eq!(executor.context().regs()[0], 0x1234);
+
+        // Left at Compiled::Undefined
+        assert!(matches!(executor.step(), HaltReason::Exception { pc: 0x0C, exception: Exception::UndefinedInstruction }));
+    }
+
+    #[test]
+    fn map_dispatch() {
+        use CoprocReg::*;
+
+        let tls = Arc::new(AtomicU32::new(0xDEAD0000));
+        let map = CoprocessorMap::new(15)
+            .read_only_word(OneWord::new(0, C13, C0, 3), 0x1234)
+            .shared_read_only_word(OneWord::new(0, C13, C0, 4), Arc::clone(&tls))
+            .get_words(TwoWords::new(1, C14), |_| (1, 2));
+
+        match map.compile_get_one_word(false, 0, C13, C0, 3) {
+            Compiled::Access(cell) => assert_eq!(cell.get(), 0x1234),
+            _ => panic!("Map-owned word isn't accessed directly"),
+        }
+        // Shared words go through a callback
+        assert!(matches!(map.compile_get_one_word(false, 0, C13, C0, 4), Compiled::Callback(_)));
+        assert!(matches!(map.compile_get_two_words(false, 1, C14), Compiled::Callback(_)));
+        // Unregistered encodings compile to a callback raising the exception
+        assert!(matches!(map.compile_send_one_word(false, 0, C13, C0, 3), Compiled::Callback(_)));
+        assert!(matches!(map.compile_get_one_word(true, 0, C13, C0, 3), Compiled::Callback(_)));
+
+        let mut state = Vec::new();
+        map.save_state(&mut state).unwrap();
+        if let Compiled::Access(cell) = map.compile_get_one_word(false, 0, C13, C0, 3) {
+            cell.set(0);
+        }
+        map.load_state(&mut state.as_slice()).unwrap();
+        match map.compile_get_one_word(false, 0, C13, C0, 3) {
+            Compiled::Access(cell) => assert_eq!(cell.get(), 0x1234),
+            _ => unreachable!(),
+        }
+
+        let map = map.unregistered(Unregistered::Ignore);
+        match map.compile_send_one_word(false, 0, C13, C0, 3) {
+            Compiled::Access(cell) => cell.set(1),
+            _ => panic!("Unregistered MCR isn't ignored"),
+        }
+        match map.compile_get_one_word(false, 0, C13, C0, 2) {
+            Compiled::Access(cell) => assert_eq!(cell.get(), 0),
+            _ => panic!("Unregistered MRC isn't ignored"),
+        }
+        assert_eq!(tls.load(Ordering::SeqCst), 0xDEAD0000);
+    }
+
+    #[test]
+    fn executor_map_undefined() {
+        use CoprocReg::*;
+
+        let mut memory = MemoryImpl::new();
+        memory.map_memory(0x0000, 1, false);
+        memory.write(0x00, 0xEE1D0F70u32).unwrap(); // mrc p15, 0, r0, c13, c0, 3
+        memory.write(0x04, 0xEE1D1F30u32).unwrap(); // mrc p15, 0, r1, c13, c0, 1
+        memory.write(0x08, 0xEAFFFFFEu32).unwrap(); // b .
+
+        let mut executor = Executor::new(TestHandlers::new(memory).coprocessor(15, |_| {
+            callbacks(CoprocessorMap::new(15).get_word(OneWord::new(0, C13, C0, 3), |_| 0x1234))
+        }));
+        executor.context().set_cpsr(Cpsr::new(ProcessorMode::User));
+        executor.context().set_pc(0);
+
+        assert!(matches!(executor.step(), HaltReason::Stepped));
+        assert_eq!(executor.context().regs()[0], 0x1234);
+        assert!(matches!(executor.step(), HaltReason::Exception { pc: 4, exception: Exception::UndefinedInstruction }));
+    }
+}
@@ -0,0 +1,221 @@
+// CP15 as a user-mode program sees it: thread ID registers, barriers, cache maintenance and the ID
+// registers. Everything is atomic so the host can change it from any thread, e.g. TPIDRURO on a
+// thread switch. Share it with an Arc to keep access after handing it to the executor:
+//
+//   cp[15] = Some(coprocessor::callbacks(Arc::clone(&self.cp15)));
+//
+// Dynarmic compiles without knowing the processor mode. Guest writes to TPIDRURO and TPIDRPRW are
+// undefined as in user mode, reads of privileged registers aren't checked. Encodings not listed
+// here are undefined.
+
+use std::cell::Cell;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{self, AtomicU32, Ordering};
+
+use crate::coproc::CoprocReg::{self, *};
+use crate::coprocessor::{Compiled, Coprocessor, GetOneWord, SendOneWord};
+use crate::savestate;
+
+// Cortex-A15 I-cache line, what ICIMVAU invalidates
+const ICACHE_LINE: u32 = 64;
+
+pub struct Cp15 {
+    tpidrurw: AtomicU32,
+    tpidruro: AtomicU32,
+    tpidrprw: AtomicU32,
+    midr: AtomicU32,
+    mpidr: AtomicU32,
+    id_isar: [AtomicU32; 6],
+}
+
+impl Cp15 {
+    // ID registers start out as a uniprocessor Cortex-A15
+    pub fn new() -> Cp15 {
+        Cp15 {
+            tpidrurw: AtomicU32::new(0),
+            tpidruro: AtomicU32::new(0),
+            tpidrprw: AtomicU32::new(0),
+            midr: AtomicU32::new(0x412FC0F1),
+            mpidr: AtomicU32::new(0xC0000000),
+            id_isar: [
+                AtomicU32::new(0x02101110),
+                AtomicU32::new(0x13112111),
+                AtomicU32::new(0x21232041),
+                AtomicU32::new(0x11112131),
+                AtomicU32::new(0x10011142),
+                AtomicU32::new(0x00000000),
+            ],
+        }
+    }
+
+    // User read/write thread ID (c13, c0, 2)
+    pub fn tpidrurw(&self) -> u32 {
+        self.tpidrurw.load(Ordering::SeqCst)
+    }
+
+    pub fn set_tpidrurw(&self, value: u32) {
+        self.tpidrurw.store(value, Ordering::SeqCst)
+    }
+
+    // User read-only thread ID (c13, c0, 3), where Linux keeps the TLS pointer
+    pub fn tpidruro(&self) -> u32 {
+        self.tpidruro.load(Ordering::SeqCst)
+    }
+
+    pub fn set_tpidruro(&self, value: u32) {
+        self.tpidruro.store(value, Ordering::SeqCst)
+    }
+
+    // Privileged thread ID (c13, c0, 4)
+    pub fn tpidrprw(&self) -> u32 {
+        self.tpidrprw.load(Ordering::SeqCst)
+    }
+
+    pub fn set_tpidrprw(&self, value: u32) {
+        self.tpidrprw.store(value, Ordering::SeqCst)
+    }
+
+    pub fn midr(&self) -> u32 {
+        self.midr.load(Ordering::SeqCst)
+    }
+
+    pub fn set_midr(&self, value: u32) {
+        self.midr.store(value, Ordering::SeqCst)
+    }
+
+    pub fn mpidr(&self) -> u32 {
+        self.mpidr.load(Ordering::SeqCst)
+    }
+
+    pub fn set_mpidr(&self, value: u32) {
+        self.mpidr.store(value, Ordering::SeqCst)
+    }
+
+    // ID_ISAR0 to ID_ISAR5
+    pub fn id_isar(&self, n: usize) -> u32 {
+        self.id_isar[n].load(Ordering::SeqCst)
+    }
+
+    pub fn set_id_isar(&self, n: usize, value: u32) {
+        self.id_isar[n].store(value, Ordering::SeqCst)
+    }
+
+    fn registers(&self) -> [&AtomicU32; 11] {
+        let isar = &self.id_isar;
+        [&self.tpidrurw, &self.tpidruro, &self.tpidrprw, &self.midr, &self.mpidr, &isar[0], &isar[1], &isar[2], &isar[3], &isar[4], &isar[5]]
+    }
+}
+
+// Dynarmic can only access Cells directly, atomics the host may touch mid-run go through callbacks
+fn read(register: &AtomicU32) -> Compiled<GetOneWord<'_>, &Cell<u32>> {
+    Compiled::Callback(Box::new(move |_| register.load(Ordering::SeqCst)))
+}
+
+fn write(register: &AtomicU32) -> Compiled<SendOneWord<'_>, &Cell<u32>> {
+    Compiled::Callback(Box::new(move |_, value| register.store(value, Ordering::SeqCst)))
+}
+
+impl Default for Cp15 {
+    fn default() -> Self {
+        Cp15::new()
+    }
+}
+
+impl Coprocessor for Cp15 {
+    fn compile_send_one_word(&self, two: bool, opc1: u32, cr_n: CoprocReg, cr_m: CoprocReg, opc2: u32) -> Compiled<SendOneWord<'_>, &Cell<u32>> {
+        if two || opc1 != 0 {
+            return Compiled::Undefined;
+        }
+
+        match (cr_n, cr_m, opc2) {
+            // ISB, nothing is in flight between instructions
+            (C7, C5, 4) => Compiled::Callback(Box::new(|_, _| {})),
+            // DSB and DMB
+            (C7, C10, 4) | (C7, C10, 5) => Compiled::Callback(Box::new(|_, _| atomic::fence(Ordering::SeqCst))),
+            // ICIALLUIS and ICIALLU drop every translation, ICIMVAU those of one line
+            (C7, C1, 0) | (C7, C5, 0) => Compiled::Callback(Box::new(|context, _| context.clear_cache())),
+            (C7, C5, 1) => Compiled::Callback(Box::new(|context, mva| context.invalidate_range(mva & !(ICACHE_LINE - 1), ICACHE_LINE))),
+            // BPIALLIS, BPIALL, BPIMVA. Like data cache maintenance they have nothing to do in a JIT.
+            (C7, C1, 6) | (C7, C5, 6) | (C7, C5, 7) => Compiled::Callback(Box::new(|_, _| {})),
+            // DCIMVAC, DCISW, DCCMVAC, DCCSW, DCCMVAU, DCCIMVAC, DCCISW
+            (C7, C6, 1) | (C7, C6, 2) | (C7, C10, 1) | (C7, C10, 2) | (C7, C11, 1) | (C7, C14, 1) | (C7, C14, 2) => Compiled::Callback(Box::new(|_, _| {})),
+            // TPIDRURO and TPIDRPRW are read-only from user mode, the host sets them
+            (C13, C0, 2) => write(&self.tpidrurw),
+            _ => Compiled::Undefined,
+        }
+    }
+
+    fn compile_get_one_word(&self, two: bool, opc1: u32, cr_n: CoprocReg, cr_m: CoprocReg, opc2: u32) -> Compiled<GetOneWord<'_>, &Cell<u32>> {
+        if two || opc1 != 0 {
+            return Compiled::Undefined;
+        }
+
+        match (cr_n, cr_m, opc2) {
+            (C0, C0, 0) => read(&self.midr),
+            (C0, C0, 5) => read(&self.mpidr),
+            (C0, C2, n) if n < 6 => read(&self.id_isar[n as usize]),
+            (C13, C0, 2) => read(&self.tpidrurw),
+            (C13, C0, 3) => read(&self.tpidruro),
+            (C13, C0, 4) => read(&self.tpidrprw),
+            _ => Compiled::Undefined,
+        }
+    }
+
+    fn save_state(&self, w: &mut dyn Write) -> io::Result<()> {
+        let words: Vec<u32> = self.registers().iter().map(|register| register.load(Ordering::SeqCst)).collect();
+        savestate::write_words(w, &words)
+    }
+
+    fn load_state(&self, r: &mut dyn Read) -> io::Result<()> {
+        let mut words = [0; 11];
+        savestate::read_words(r, &mut words)?;
+        for (register, &word) in self.registers().iter().zip(words.iter()) {
+            register.store(word, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn readable(cp15: &Cp15, cr_n: CoprocReg, cr_m: CoprocReg, opc2: u32) -> bool {
+        matches!(cp15.compile_get_one_word(false, 0, cr_n, cr_m, opc2), Compiled::Callback(_))
+    }
+
+    #[test]
+    fn registers_and_state() {
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+        let cp15 = Cp15::new();
+        assert_send_sync(&cp15);
+        cp15.set_tpidrurw(0x1234);
+        cp15.set_tpidruro(0xDEAD0000);
+        cp15.set_mpidr(0x80000001);
+        cp15.set_id_isar(5, 0x11);
+
+        assert!(readable(&cp15, C13, C0, 3));
+        assert!(readable(&cp15, C0, C0, 5));
+        assert!(readable(&cp15, C0, C2, 5));
+        assert!(!readable(&cp15, C0, C2, 6));
+        assert!(matches!(cp15.compile_get_one_word(false, 1, C13, C0, 3), Compiled::Undefined));
+
+        assert!(matches!(cp15.compile_send_one_word(false, 0, C13, C0, 2), Compiled::Callback(_)));
+        assert!(matches!(cp15.compile_send_one_word(false, 0, C7, C10, 5), Compiled::Callback(_)));
+        assert!(matches!(cp15.compile_send_one_word(false, 0, C7, C14, 1), Compiled::Callback(_)));
+        assert!(matches!(cp15.compile_send_one_word(false, 0, C0, C0, 0), Compiled::Undefined));
+        assert!(matches!(cp15.compile_send_one_word(false, 0, C13, C0, 3), Compiled::Undefined));
+        assert!(matches!(cp15.compile_send_one_word(false, 0, C13, C0, 4), Compiled::Undefined));
+        assert!(matches!(cp15.compile_send_one_word(false, 0, C7, C5, 1), Compiled::Callback(_)));
+
+        let mut state = Vec::new();
+        cp15.save_state(&mut state).unwrap();
+        let restored = Cp15::new();
+        restored.load_state(&mut &state[..]).unwrap();
+        assert_eq!(restored.tpidrurw(), 0x1234);
+        assert_eq!(restored.tpidruro(), 0xDEAD0000);
+        assert_eq!(restored.mpidr(), 0x80000001);
+        assert_eq!(restored.id_isar(5), 0x11);
+    }
+}
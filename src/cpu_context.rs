@@ -0,0 +1,91 @@
+use dynarmic_sys::*;
+use std::fmt;
+
+use crate::{Cpsr, Fpscr};
+
+// Everything dynarmic keeps per thread of execution: registers, CPSR, FPSCR including its mode
+// bits, and the local exclusive monitor state. See Executor::save_context and load_context.
+pub struct CpuContext {
+    raw: *mut RawContext,
+}
+
+// Plain data owned by this value
+unsafe impl Send for CpuContext {}
+
+impl CpuContext {
+    pub fn new() -> Self {
+        CpuContext {
+            raw: unsafe { dynarmic_context_new() },
+        }
+    }
+
+    pub(crate) fn raw(&self) -> *const RawContext {
+        self.raw
+    }
+
+    pub(crate) fn raw_mut(&mut self) -> *mut RawContext {
+        self.raw
+    }
+
+    pub fn regs(&self) -> &[u32; 16] {
+        unsafe { &*dynarmic_context_regs(self.raw) }
+    }
+
+    pub fn regs_mut(&mut self) -> &mut [u32; 16] {
+        unsafe { &mut *dynarmic_context_regs(self.raw) }
+    }
+
+    pub fn extregs(&self) -> &[u32; 64] {
+        unsafe { &*dynarmic_context_extregs(self.raw) }
+    }
+
+    pub fn extregs_mut(&mut self) -> &mut [u32; 64] {
+        unsafe { &mut *dynarmic_context_extregs(self.raw) }
+    }
+
+    pub fn cpsr(&self) -> Cpsr {
+        Cpsr(unsafe { dynarmic_context_cpsr(self.raw) })
+    }
+
+    pub fn set_cpsr(&mut self, cpsr: Cpsr) {
+        unsafe { dynarmic_context_set_cpsr(self.raw, cpsr.0) }
+    }
+
+    pub fn fpscr(&self) -> Fpscr {
+        Fpscr(unsafe { dynarmic_context_fpscr(self.raw) })
+    }
+
+    pub fn set_fpscr(&mut self, fpscr: Fpscr) {
+        unsafe { dynarmic_context_set_fpscr(self.raw, fpscr.0) }
+    }
+}
+
+impl Default for CpuContext {
+    fn default() -> Self {
+        CpuContext::new()
+    }
+}
+
+impl Clone for CpuContext {
+    fn clone(&self) -> Self {
+        CpuContext {
+            raw: unsafe { dynarmic_context_clone(self.raw) },
+        }
+    }
+}
+
+impl Drop for CpuContext {
+    fn drop(&mut self) {
+        unsafe { dynarmic_context_delete(self.raw) }
+    }
+}
+
+impl fmt::Debug for CpuContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CpuContext")
+            .field("regs", self.regs())
+            .field("cpsr", &self.cpsr())
+            .field("fpscr", &self.fpscr())
+            .finish()
+    }
+}
@@ -0,0 +1,399 @@
+// Loader for 32-bit little endian ARM ELF executables and shared objects. Only PT_LOAD segments
+// are mapped, relocations and dynamic linking are left to the caller.
+
+use byteorder::{LE, ByteOrder};
+use std::fmt;
+
+use crate::JitContext;
+use crate::memory::{Memory, MemoryImpl, PAGE_BITS};
+
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_ARM: u16 = 40;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+const SHT_SYMTAB: u32 = 2;
+const SHT_DYNSYM: u32 = 11;
+
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ElfError {
+    NotElf,
+    // Wrong class, endianness, machine or type
+    Unsupported(&'static str),
+    // Headers point outside of the file
+    Truncated,
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElfError::NotElf => write!(f, "not an ELF file"),
+            ElfError::Unsupported(what) => write!(f, "unsupported ELF: {}", what),
+            ElfError::Truncated => write!(f, "truncated ELF file"),
+        }
+    }
+}
+
+impl std::error::Error for ElfError {}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SymbolKind {
+    Function,
+    Object,
+    Other,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u32, // Load base applied, Thumb bit cleared
+    pub size: u32,
+    pub kind: SymbolKind,
+    pub thumb: bool,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub addr: u32,
+    pub size: u32, // In memory, including BSS
+    pub writable: bool,
+    pub executable: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct ElfImage {
+    pub entry: u32, // Thumb bit cleared
+    pub thumb: bool,
+    pub segments: Vec<Segment>,
+    pub symbols: Vec<Symbol>, // Defined symbols only, from .symtab or else .dynsym
+    // Where the program headers ended up, if a segment covers them. Needed for the Linux auxv.
+    pub phdr: Option<u32>,
+    pub phnum: u32,
+}
+
+impl ElfImage {
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    // Points PC at the entry point and picks ARM or Thumb state to match
+    pub fn set_entry(&self, context: &JitContext) {
+        context.set_pc(self.entry | self.thumb as u32);
+    }
+}
+
+fn slice(data: &[u8], offset: u32, len: u32) -> Result<&[u8], ElfError> {
+    let start = offset as usize;
+    let end = start.checked_add(len as usize).ok_or(ElfError::Truncated)?;
+    data.get(start..end).ok_or(ElfError::Truncated)
+}
+
+// Entry `index` of a header table at `offset`
+fn table_entry(data: &[u8], offset: u32, index: u32, entsize: u32, len: u32) -> Result<&[u8], ElfError> {
+    let start = index.checked_mul(entsize).and_then(|at| at.checked_add(offset)).ok_or(ElfError::Truncated)?;
+    slice(data, start, len)
+}
+
+// Pages covered by a segment as (first, end)
+fn page_range(segment: &Segment) -> (u32, u32) {
+    let end = segment.addr as u64 + segment.size as u64;
+    (segment.addr >> PAGE_BITS, ((end + (1 << PAGE_BITS) - 1) >> PAGE_BITS) as u32)
+}
+
+fn c_str(data: &[u8], offset: u32) -> String {
+    let bytes = data.get(offset as usize..).unwrap_or(&[]);
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len]).into_owned()
+}
+
+// Maps every PT_LOAD segment at its address plus `base` (0 for executables), read only unless the
+// segment is writable, and marks executable segments with MemoryImpl::set_executable. Segments
+// sharing a page share the mapping, which is then writable if either of them is.
+pub fn load(memory: &mut MemoryImpl, data: &[u8], base: u32) -> Result<ElfImage, ElfError> {
+    let header = slice(data, 0, 52).map_err(|_| ElfError::NotElf)?;
+    if &header[..4] != b"\x7FELF" {
+        return Err(ElfError::NotElf);
+    }
+    if header[4] != 1 {
+        return Err(ElfError::Unsupported("not 32-bit"));
+    }
+    if header[5] != 1 {
+        return Err(ElfError::Unsupported("not little endian"));
+    }
+    match LE::read_u16(&header[16..]) {
+        ET_EXEC | ET_DYN => {},
+        _ => return Err(ElfError::Unsupported("not an executable or shared object")),
+    }
+    if LE::read_u16(&header[18..]) != EM_ARM {
+        return Err(ElfError::Unsupported("not ARM"));
+    }
+
+    let entry = LE::read_u32(&header[24..]).wrapping_add(base);
+    let phoff = LE::read_u32(&header[28..]);
+    let shoff = LE::read_u32(&header[32..]);
+    let phentsize = LE::read_u16(&header[42..]) as u32;
+    let phnum = LE::read_u16(&header[44..]) as u32;
+    let shentsize = LE::read_u16(&header[46..]) as u32;
+    let shnum = LE::read_u16(&header[48..]) as u32;
+
+    struct Load<'a> {
+        segment: Segment,
+        bytes: &'a [u8],
+    }
+
+    let mut phdr = None;
+    let mut loads = Vec::new();
+    for i in 0..phnum {
+        let ph = table_entry(data, phoff, i, phentsize, 32)?;
+        if LE::read_u32(ph) != PT_LOAD {
+            continue;
+        }
+        let offset = LE::read_u32(&ph[4..]);
+        let vaddr = LE::read_u32(&ph[8..]);
+        let filesz = LE::read_u32(&ph[16..]);
+        let memsz = LE::read_u32(&ph[20..]);
+        let flags = LE::read_u32(&ph[24..]);
+        if memsz == 0 {
+            continue;
+        }
+        if vaddr.wrapping_add(base) as u64 + memsz as u64 > 1 << 32 {
+            return Err(ElfError::Unsupported("segment runs past the end of the address space"));
+        }
+        if phoff >= offset && phoff as u64 + (phnum * phentsize) as u64 <= offset as u64 + filesz as u64 {
+            phdr = Some(vaddr.wrapping_add(base).wrapping_add(phoff - offset));
+        }
+        loads.push(Load {
+            segment: Segment {
+                addr: vaddr.wrapping_add(base),
+                size: memsz,
+                writable: flags & PF_W != 0,
+                executable: flags & PF_X != 0,
+            },
+            bytes: slice(data, offset, filesz.min(memsz))?,
+        });
+    }
+
+    // Page ranges, merged where segments share pages
+    let mut ranges: Vec<(u32, u32, bool)> = loads.iter().map(|load| {
+        let (start, end) = page_range(&load.segment);
+        (start, end, load.segment.writable)
+    }).collect();
+    ranges.sort();
+    let mut merged: Vec<(u32, u32, bool)> = Vec::new();
+    for (start, end, writable) in ranges {
+        match merged.last_mut() {
+            Some(last) if start < last.1 => {
+                last.1 = last.1.max(end);
+                last.2 |= writable;
+            },
+            _ => merged.push((start, end, writable)),
+        }
+    }
+
+    // Fresh pages are zeroed, which takes care of BSS
+    for &(start, end, writable) in &merged {
+        memory.map_memory(start << PAGE_BITS, end - start, !writable);
+    }
+    for load in &loads {
+        memory.write_bytes(load.segment.addr, load.bytes)
+            .expect("Segment was just mapped");
+        if load.segment.executable {
+            let (start, end) = page_range(&load.segment);
+            memory.set_executable(start << PAGE_BITS, end - start, true);
+        }
+    }
+
+    let symbols = if shoff != 0 {
+        read_symbols(data, shoff, shentsize, shnum, base)?
+    } else {
+        Vec::new()
+    };
+
+    Ok(ElfImage {
+        entry: entry & !1,
+        thumb: entry & 1 != 0,
+        segments: loads.into_iter().map(|load| load.segment).collect(),
+        symbols,
+        phdr,
+        phnum,
+    })
+}
+
+fn read_symbols(data: &[u8], shoff: u32, shentsize: u32, shnum: u32, base: u32) -> Result<Vec<Symbol>, ElfError> {
+    let section = |i: u32| table_entry(data, shoff, i, shentsize, 40);
+
+    let mut table = None;
+    for i in 0..shnum {
+        let sh = section(i)?;
+        match LE::read_u32(&sh[4..]) {
+            SHT_SYMTAB => {
+                table = Some(sh);
+                break;
+            },
+            SHT_DYNSYM if table.is_none() => table = Some(sh),
+            _ => {},
+        }
+    }
+
+    let table = match table {
+        Some(table) => table,
+        None => return Ok(Vec::new()),
+    };
+
+    let symbols = slice(data, LE::read_u32(&table[16..]), LE::read_u32(&table[20..]))?;
+    let strtab = section(LE::read_u32(&table[24..]))?;
+    let strings = slice(data, LE::read_u32(&strtab[16..]), LE::read_u32(&strtab[20..]))?;
+
+    Ok(symbols.chunks_exact(16).filter_map(|sym| {
+        let shndx = LE::read_u16(&sym[14..]);
+        let name = c_str(strings, LE::read_u32(sym));
+        if shndx == 0 || name.is_empty() {
+            return None;
+        }
+        let value = LE::read_u32(&sym[4..]);
+        let kind = match sym[12] & 0xF {
+            STT_FUNC => SymbolKind::Function,
+            STT_OBJECT => SymbolKind::Object,
+            _ => SymbolKind::Other,
+        };
+        let thumb = kind == SymbolKind::Function && value & 1 != 0;
+        // Absolute symbols don't move with the image
+        let addr = if shndx == 0xFFF1 { value } else { value.wrapping_add(base) };
+        Some(Symbol {
+            name,
+            addr: if thumb { addr & !1 } else { addr },
+            size: LE::read_u32(&sym[8..]),
+            kind,
+            thumb,
+        })
+    }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_elf(machine: u16) -> Vec<u8> {
+        let mut elf = vec![0u8; 0x200 + 3 * 40];
+
+        elf[..7].copy_from_slice(b"\x7FELF\x01\x01\x01");
+        LE::write_u16(&mut elf[16..], ET_EXEC);
+        LE::write_u16(&mut elf[18..], machine);
+        LE::write_u32(&mut elf[24..], 0x8001); // Thumb entry
+        LE::write_u32(&mut elf[28..], 52);
+        LE::write_u32(&mut elf[32..], 0x200);
+        LE::write_u16(&mut elf[42..], 32);
+        LE::write_u16(&mut elf[44..], 2);
+        LE::write_u16(&mut elf[46..], 40);
+        LE::write_u16(&mut elf[48..], 3);
+
+        // Text, then data whose BSS runs into the next page
+        for (i, &(offset, vaddr, filesz, memsz, flags)) in [(0x100, 0x8000, 8, 8, 5), (0x108, 0x9FFC, 4, 0x10, 6)].iter().enumerate() {
+            let ph = &mut elf[52 + i * 32..];
+            LE::write_u32(ph, PT_LOAD);
+            LE::write_u32(&mut ph[4..], offset);
+            LE::write_u32(&mut ph[8..], vaddr);
+            LE::write_u32(&mut ph[16..], filesz);
+            LE::write_u32(&mut ph[20..], memsz);
+            LE::write_u32(&mut ph[24..], flags);
+        }
+        LE::write_u32(&mut elf[0x100..], 0xE7FE2001); // movs r0, #1; b .
+        LE::write_u32(&mut elf[0x104..], 0xAABBCCDD);
+        LE::write_u32(&mut elf[0x108..], 0x11223344);
+
+        // name, value, size, info, shndx
+        let symbols = [(0, 0, 0, 0, 0), (1, 0x8001, 8, 0x12, 1), (6, 0x9FFC, 4, 0x11, 2), (10, 0, 0, 0x10, 0)];
+        for (i, &(name, value, size, info, shndx)) in symbols.iter().enumerate() {
+            let sym = &mut elf[0x140 + i * 16..];
+            LE::write_u32(sym, name);
+            LE::write_u32(&mut sym[4..], value);
+            LE::write_u32(&mut sym[8..], size);
+            sym[12] = info;
+            LE::write_u16(&mut sym[14..], shndx);
+        }
+        elf[0x180..0x190].copy_from_slice(b"\0main\0buf\0undef\0");
+
+        // Null section, .symtab, .strtab
+        let sh = &mut elf[0x200 + 40..];
+        LE::write_u32(&mut sh[4..], SHT_SYMTAB);
+        LE::write_u32(&mut sh[16..], 0x140);
+        LE::write_u32(&mut sh[20..], 0x40);
+        LE::write_u32(&mut sh[24..], 2);
+        let sh = &mut elf[0x200 + 80..];
+        LE::write_u32(&mut sh[4..], 3);
+        LE::write_u32(&mut sh[16..], 0x180);
+        LE::write_u32(&mut sh[20..], 0x10);
+
+        elf
+    }
+
+    #[test]
+    fn loads_segments_and_symbols() {
+        let mut mem = MemoryImpl::new();
+        let image = load(&mut mem, &build_elf(EM_ARM), 0).unwrap();
+
+        assert_eq!(image.entry, 0x8000);
+        assert!(image.thumb);
+        assert_eq!(image.segments.len(), 2);
+        assert_eq!(image.phdr, None);
+        assert_eq!(image.phnum, 2);
+
+        assert_eq!(mem.read::<u32>(0x8000), Ok(0xE7FE2001));
+        assert!(mem.is_read_only(0x8000));
+        assert_eq!(mem.read::<u32>(0x9FFC), Ok(0x11223344));
+        assert!(!mem.is_read_only(0x9FFC));
+        assert_eq!(mem.read::<u32>(0xA000), Ok(0)); // BSS
+        assert!(!mem.is_read_only(0xA000));
+        assert!(mem.read::<u8>(0xB000).is_err());
+
+        let main = image.symbol("main").unwrap();
+        assert_eq!((main.addr, main.kind, main.thumb), (0x8000, SymbolKind::Function, true));
+        assert_eq!(image.symbol("buf").unwrap().addr, 0x9FFC);
+        assert!(image.symbol("undef").is_none());
+    }
+
+    #[test]
+    fn relocates_by_base() {
+        let mut mem = MemoryImpl::new();
+        let image = load(&mut mem, &build_elf(EM_ARM), 0x100000).unwrap();
+        assert_eq!(image.entry, 0x108000);
+        assert_eq!(image.symbol("buf").unwrap().addr, 0x109FFC);
+        assert_eq!(mem.read::<u32>(0x108000), Ok(0xE7FE2001));
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let mut mem = MemoryImpl::new();
+        assert_eq!(load(&mut mem, b"#!/bin/sh", 0).unwrap_err(), ElfError::NotElf);
+        assert_eq!(load(&mut mem, &build_elf(3), 0).unwrap_err(), ElfError::Unsupported("not ARM"));
+
+        let mut truncated = build_elf(EM_ARM);
+        truncated.truncate(0x104);
+        assert_eq!(load(&mut mem, &truncated, 0).unwrap_err(), ElfError::Truncated);
+
+        let mut wrapping = build_elf(EM_ARM);
+        LE::write_u32(&mut wrapping[32..], 0xFFFFFFF0);
+        assert_eq!(load(&mut mem, &wrapping, 0).unwrap_err(), ElfError::Truncated);
+    }
+
+    #[test]
+    fn executor_starts_at_entry() {
+        use crate::Executor;
+        use crate::testing::TestHandlers;
+
+        let mut mem = MemoryImpl::new();
+        let image = load(&mut mem, &build_elf(EM_ARM), 0).unwrap();
+
+        let mut executor = Executor::new(TestHandlers::new(mem));
+        image.set_entry(&executor.context());
+        executor.step();
+        assert_eq!(executor.context().regs()[0], 1);
+        assert_eq!(executor.context().regs()[15], 0x8002);
+    }
+}
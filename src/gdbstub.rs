@@ -0,0 +1,632 @@
+// GDB remote serial protocol stub, enough for gdb-multiarch to attach to an Executor:
+//
+//   let mut stub = GdbStub::listen_tcp("127.0.0.1:1234")?;
+//   stub.serve(&mut executor)?;
+//
+// Breakpoints go through Executor::add_breakpoint, so continuing runs in the JIT and only stops to
+// check for a ^C from GDB every few million instructions. Watchpoints compare memory values after
+// every instruction instead, so only write watchpoints are supported and writing an unchanged value
+// doesn't trigger them.
+
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use crate::{Cpsr, Exception, Executor, Fpscr, HaltReason, Handlers};
+use crate::memory::Memory;
+
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+// Instructions between checks for an interrupt from GDB while continuing. Each check costs a few
+// syscalls, so running uses a much longer interval than stepping.
+const RUN_POLL_INTERVAL: u64 = 1 << 22;
+const STEP_POLL_INTERVAL: u64 = 1024;
+
+// Advertised in qSupported, longer packets end the session
+const PACKET_SIZE: usize = 0x4000;
+// Most memory read or watched at once
+const MAX_MEMORY_TRANSFER: u32 = 0x1000;
+
+const TARGET_XML: &str = concat!(
+    r#"<?xml version="1.0"?><!DOCTYPE target SYSTEM "gdb-target.dtd"><target version="1.0">"#,
+    r#"<architecture>arm</architecture><feature name="org.gnu.gdb.arm.core">"#,
+    r#"<reg name="r0" bitsize="32"/><reg name="r1" bitsize="32"/><reg name="r2" bitsize="32"/>"#,
+    r#"<reg name="r3" bitsize="32"/><reg name="r4" bitsize="32"/><reg name="r5" bitsize="32"/>"#,
+    r#"<reg name="r6" bitsize="32"/><reg name="r7" bitsize="32"/><reg name="r8" bitsize="32"/>"#,
+    r#"<reg name="r9" bitsize="32"/><reg name="r10" bitsize="32"/><reg name="r11" bitsize="32"/>"#,
+    r#"<reg name="r12" bitsize="32"/><reg name="sp" bitsize="32" type="data_ptr"/>"#,
+    r#"<reg name="lr" bitsize="32"/><reg name="pc" bitsize="32" type="code_ptr"/>"#,
+    r#"<reg name="cpsr" bitsize="32"/></feature><feature name="org.gnu.gdb.arm.vfp">"#,
+    r#"<reg name="d0" bitsize="64" type="ieee_double"/><reg name="d1" bitsize="64" type="ieee_double"/>"#,
+    r#"<reg name="d2" bitsize="64" type="ieee_double"/><reg name="d3" bitsize="64" type="ieee_double"/>"#,
+    r#"<reg name="d4" bitsize="64" type="ieee_double"/><reg name="d5" bitsize="64" type="ieee_double"/>"#,
+    r#"<reg name="d6" bitsize="64" type="ieee_double"/><reg name="d7" bitsize="64" type="ieee_double"/>"#,
+    r#"<reg name="d8" bitsize="64" type="ieee_double"/><reg name="d9" bitsize="64" type="ieee_double"/>"#,
+    r#"<reg name="d10" bitsize="64" type="ieee_double"/><reg name="d11" bitsize="64" type="ieee_double"/>"#,
+    r#"<reg name="d12" bitsize="64" type="ieee_double"/><reg name="d13" bitsize="64" type="ieee_double"/>"#,
+    r#"<reg name="d14" bitsize="64" type="ieee_double"/><reg name="d15" bitsize="64" type="ieee_double"/>"#,
+    r#"<reg name="d16" bitsize="64" type="ieee_double"/><reg name="d17" bitsize="64" type="ieee_double"/>"#,
+    r#"<reg name="d18" bitsize="64" type="ieee_double"/><reg name="d19" bitsize="64" type="ieee_double"/>"#,
+    r#"<reg name="d20" bitsize="64" type="ieee_double"/><reg name="d21" bitsize="64" type="ieee_double"/>"#,
+    r#"<reg name="d22" bitsize="64" type="ieee_double"/><reg name="d23" bitsize="64" type="ieee_double"/>"#,
+    r#"<reg name="d24" bitsize="64" type="ieee_double"/><reg name="d25" bitsize="64" type="ieee_double"/>"#,
+    r#"<reg name="d26" bitsize="64" type="ieee_double"/><reg name="d27" bitsize="64" type="ieee_double"/>"#,
+    r#"<reg name="d28" bitsize="64" type="ieee_double"/><reg name="d29" bitsize="64" type="ieee_double"/>"#,
+    r#"<reg name="d30" bitsize="64" type="ieee_double"/><reg name="d31" bitsize="64" type="ieee_double"/>"#,
+    r#"<reg name="fpscr" bitsize="32" type="int" group="float"/></feature></target>"#,
+);
+
+// Register numbers as laid out by TARGET_XML
+const REG_CPSR: usize = 16;
+const REG_D0: usize = 17;
+const REG_FPSCR: usize = 49;
+
+// A socket GDB talks to. poll_interrupt checks for a ^C without blocking.
+pub trait Connection: Read + Write {
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+impl Connection for TcpStream {
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        self.set_nonblocking(true)?;
+        let mut byte = [0u8];
+        let result = self.peek(&mut byte);
+        self.set_nonblocking(false)?;
+        match result {
+            Ok(1) if byte[0] == 0x03 => {
+                self.read_exact(&mut byte)?;
+                Ok(true)
+            },
+            Ok(_) => Ok(false),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Connection for std::os::unix::net::UnixStream {
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        self.set_nonblocking(true)?;
+        let mut byte = [0u8];
+        let result = self.read(&mut byte);
+        self.set_nonblocking(false)?;
+        match result {
+            // A packet started instead, GDB doesn't send any while the target is running
+            Ok(1) => Ok(byte[0] == 0x03),
+            Ok(_) => Ok(false),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+struct Watchpoint {
+    addr: u32,
+    len: u32,
+    value: Vec<u8>,
+}
+
+enum Stop {
+    Signal(u8),
+    Watch(u32),
+}
+
+pub struct GdbStub<C: Connection> {
+    connection: C,
+    breakpoints: BTreeSet<u32>, // Added to the executor, removed again when the session ends
+    watchpoints: Vec<Watchpoint>,
+}
+
+impl GdbStub<TcpStream> {
+    // Blocks until GDB connects
+    pub fn listen_tcp<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let (stream, _) = TcpListener::bind(addr)?.accept()?;
+        stream.set_nodelay(true)?;
+        Ok(GdbStub::new(stream))
+    }
+}
+
+#[cfg(unix)]
+impl GdbStub<std::os::unix::net::UnixStream> {
+    pub fn listen_unix<P: AsRef<std::path::Path>>(path: P) -> io::Result<Self> {
+        let (stream, _) = std::os::unix::net::UnixListener::bind(path)?.accept()?;
+        Ok(GdbStub::new(stream))
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn parse_hex(s: &str) -> Option<u32> {
+    u32::from_str_radix(s, 16).ok()
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+impl<C: Connection> GdbStub<C> {
+    pub fn new(connection: C) -> Self {
+        GdbStub {
+            connection,
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0u8];
+        self.connection.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    // None once GDB hangs up
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // Skips acks and stray interrupts while stopped
+            match self.read_byte() {
+                Ok(b'$') => {},
+                Ok(_) => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    _ if data.len() == PACKET_SIZE => return Err(io::Error::new(io::ErrorKind::InvalidData, "GDB packet too long")),
+                    b => data.push(b),
+                }
+            }
+            let sum = [self.read_byte()?, self.read_byte()?];
+            let expected = std::str::from_utf8(&sum).ok().and_then(|s| u8::from_str_radix(s, 16).ok());
+
+            if expected == Some(checksum(&data)) {
+                self.connection.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            self.connection.write_all(b"-")?;
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        loop {
+            self.connection.write_all(packet.as_bytes())?;
+            self.connection.flush()?;
+            match self.read_byte()? {
+                b'-' => continue,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    // Handles packets until GDB detaches, kills the target or disconnects
+    pub fn serve<H: Handlers>(&mut self, executor: &mut Executor<H>) -> io::Result<()> {
+        let result = self.serve_packets(executor);
+        for addr in std::mem::take(&mut self.breakpoints) {
+            executor.remove_breakpoint(addr);
+        }
+        result
+    }
+
+    fn serve_packets<H: Handlers>(&mut self, executor: &mut Executor<H>) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            let reply = match packet.as_bytes().first() {
+                Some(b'D') => {
+                    self.write_packet("OK")?;
+                    return Ok(());
+                },
+                Some(b'k') => return Ok(()),
+                Some(b'c') => self.resume(executor, false)?,
+                Some(b's') => self.resume(executor, true)?,
+                Some(b'v') if packet.starts_with("vCont;") => {
+                    let step = packet[6..].starts_with('s');
+                    self.resume(executor, step)?
+                },
+                _ => self.handle(executor, &packet),
+            };
+            self.write_packet(&reply)?;
+        }
+        Ok(())
+    }
+
+    // Everything that doesn't run the target
+    fn handle<H: Handlers>(&mut self, executor: &mut Executor<H>, packet: &str) -> String {
+        let command = packet.as_bytes().first().copied().unwrap_or(0);
+        // Not a char boundary when the packet starts with something other than ASCII
+        let args = packet.get(1..).unwrap_or("");
+        match command {
+            b'?' => format!("S{:02x}", SIGTRAP),
+            b'g' => hex(&GdbStub::<C>::read_registers(executor)),
+            b'G' => match unhex(args) {
+                Some(bytes) => {
+                    GdbStub::<C>::write_registers(executor, &bytes);
+                    "OK".into()
+                },
+                None => "E01".into(),
+            },
+            b'p' => match parse_hex(args).and_then(|n| GdbStub::<C>::read_register(executor, n as usize)) {
+                Some(bytes) => hex(&bytes),
+                None => "E01".into(),
+            },
+            b'P' => {
+                let mut parts = args.splitn(2, '=');
+                match (parts.next().and_then(parse_hex), parts.next().and_then(unhex)) {
+                    (Some(n), Some(bytes)) if GdbStub::<C>::write_register(executor, n as usize, &bytes) => "OK".into(),
+                    _ => "E01".into(),
+                }
+            },
+            b'm' => {
+                let mut parts = args.splitn(2, ',');
+                match (parts.next().and_then(parse_hex), parts.next().and_then(parse_hex)) {
+                    (Some(addr), Some(len)) => {
+                        let memory = executor.handlers().memory();
+                        let bytes: Vec<u8> = (0..len.min(MAX_MEMORY_TRANSFER))
+                            .map_while(|i| memory.read::<u8>(addr.wrapping_add(i)).ok())
+                            .collect();
+                        if bytes.is_empty() && len != 0 { "E14".into() } else { hex(&bytes) }
+                    },
+                    _ => "E01".into(),
+                }
+            },
+            b'M' => {
+                let mut parts = args.splitn(2, ':');
+                let target = parts.next().unwrap_or("");
+                let mut target = target.splitn(2, ',');
+                match (target.next().and_then(parse_hex), parts.next().and_then(unhex)) {
+                    (Some(addr), Some(bytes)) => {
+                        match executor.handlers().memory().write_bytes(addr, &bytes) {
+                            Ok(()) => {
+                                // Might be code
+                                executor.invalidate_range(addr, bytes.len() as u32);
+                                "OK".into()
+                            },
+                            Err(_) => "E14".into(),
+                        }
+                    },
+                    _ => "E01".into(),
+                }
+            },
+            b'Z' | b'z' => self.handle_point(executor, command == b'Z', args),
+            b'H' | b'T' => "OK".into(),
+            b'q' => GdbStub::<C>::query(args),
+            _ => String::new(),
+        }
+    }
+
+    fn query(query: &str) -> String {
+        if query.starts_with("Supported") {
+            return format!("PacketSize={:x};qXfer:features:read+;swbreak+", PACKET_SIZE);
+        }
+        if let Some(range) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            let mut parts = range.splitn(2, ',');
+            return match (parts.next().and_then(parse_hex), parts.next().and_then(parse_hex)) {
+                (Some(offset), Some(len)) => {
+                    let offset = (offset as usize).min(TARGET_XML.len());
+                    let end = (offset + len as usize).min(TARGET_XML.len());
+                    let marker = if end == TARGET_XML.len() { "l" } else { "m" };
+                    format!("{}{}", marker, &TARGET_XML[offset..end])
+                },
+                _ => "E01".into(),
+            };
+        }
+        match query {
+            "Attached" => "1".into(),
+            "C" => "QC1".into(),
+            "fThreadInfo" => "m1".into(),
+            "sThreadInfo" => "l".into(),
+            _ => String::new(),
+        }
+    }
+
+    fn handle_point<H: Handlers>(&mut self, executor: &mut Executor<H>, insert: bool, args: &str) -> String {
+        let mut parts = args.split(',');
+        let (kind, addr, len) = match (parts.next(), parts.next().and_then(parse_hex), parts.next().and_then(parse_hex)) {
+            (Some(kind), Some(addr), Some(len)) => (kind, addr, len),
+            _ => return "E01".into(),
+        };
+
+        match kind {
+            // Software and hardware breakpoints are the same thing here
+            "0" | "1" => {
+                if insert {
+                    self.breakpoints.insert(addr);
+                    executor.add_breakpoint(addr);
+                } else if self.breakpoints.remove(&addr) {
+                    executor.remove_breakpoint(addr);
+                }
+                "OK".into()
+            },
+            "2" => {
+                if insert {
+                    if len > MAX_MEMORY_TRANSFER {
+                        return "E01".into();
+                    }
+                    let mut value = vec![0u8; len as usize];
+                    if executor.handlers().memory().read_bytes(addr, &mut value).is_err() {
+                        return "E14".into();
+                    }
+                    self.watchpoints.push(Watchpoint { addr, len, value });
+                } else {
+                    self.watchpoints.retain(|w| w.addr != addr || w.len != len);
+                }
+                "OK".into()
+            },
+            // Read and access watchpoints can't be told apart from ordinary reads
+            _ => String::new(),
+        }
+    }
+
+    fn check_watchpoints<M: Memory>(&mut self, memory: &M) -> Option<u32> {
+        for watchpoint in &mut self.watchpoints {
+            let mut value = vec![0u8; watchpoint.len as usize];
+            if memory.read_bytes(watchpoint.addr, &mut value).is_ok() && value != watchpoint.value {
+                watchpoint.value = value;
+                return Some(watchpoint.addr);
+            }
+        }
+        None
+    }
+
+    fn signal(reason: &HaltReason) -> Option<u8> {
+        match reason {
+            HaltReason::Stepped | HaltReason::TicksExhausted => None,
+            HaltReason::DataAbort(_) => Some(SIGSEGV),
+            HaltReason::Exception { exception: Exception::UndefinedInstruction, .. } |
+            HaltReason::Exception { exception: Exception::DecodeError, .. } => Some(SIGILL),
+            _ => Some(SIGTRAP),
+        }
+    }
+
+    fn resume<H: Handlers>(&mut self, executor: &mut Executor<H>, step: bool) -> io::Result<String> {
+        let stop = if step || !self.watchpoints.is_empty() {
+            self.step_until_stop(executor, step)?
+        } else {
+            loop {
+                let reason = executor.run_for(RUN_POLL_INTERVAL).reason;
+                if let Some(signal) = GdbStub::<C>::signal(&reason) {
+                    break Stop::Signal(signal);
+                }
+                if self.connection.poll_interrupt()? {
+                    break Stop::Signal(SIGTRAP);
+                }
+            }
+        };
+
+        Ok(match stop {
+            Stop::Signal(signal) => format!("S{:02x}", signal),
+            Stop::Watch(addr) => format!("T{:02x}watch:{:x};", SIGTRAP, addr),
+        })
+    }
+
+    // Watchpoints need checking after every instruction
+    fn step_until_stop<H: Handlers>(&mut self, executor: &mut Executor<H>, step: bool) -> io::Result<Stop> {
+        let mut steps = 0u64;
+        loop {
+            let reason = executor.step();
+            if let Some(addr) = self.check_watchpoints(executor.handlers().memory()) {
+                return Ok(Stop::Watch(addr));
+            }
+            if let Some(signal) = GdbStub::<C>::signal(&reason) {
+                return Ok(Stop::Signal(signal));
+            }
+            if step || self.breakpoints.contains(&executor.context().pc()) {
+                return Ok(Stop::Signal(SIGTRAP));
+            }
+
+            steps += 1;
+            if steps.is_multiple_of(STEP_POLL_INTERVAL) && self.connection.poll_interrupt()? {
+                return Ok(Stop::Signal(SIGTRAP));
+            }
+        }
+    }
+
+    // r0-r15, cpsr, d0-d31, fpscr as little endian bytes
+    fn read_registers<H: Handlers>(executor: &mut Executor<H>) -> Vec<u8> {
+        (0..=REG_FPSCR).flat_map(|n| GdbStub::<C>::read_register(executor, n).unwrap()).collect()
+    }
+
+    fn write_registers<H: Handlers>(executor: &mut Executor<H>, mut bytes: &[u8]) {
+        for n in 0..=REG_FPSCR {
+            let size = if (REG_D0..REG_FPSCR).contains(&n) { 8 } else { 4 };
+            if bytes.len() < size {
+                break;
+            }
+            GdbStub::<C>::write_register(executor, n, &bytes[..size]);
+            bytes = &bytes[size..];
+        }
+    }
+
+    fn read_register<H: Handlers>(executor: &mut Executor<H>, n: usize) -> Option<Vec<u8>> {
+        let context = executor.context();
+        Some(match n {
+            0..=15 => context.regs()[n].to_le_bytes().to_vec(),
+            REG_CPSR => context.cpsr().0.to_le_bytes().to_vec(),
+            REG_FPSCR => context.fpscr().0.to_le_bytes().to_vec(),
+            _ if (REG_D0..REG_FPSCR).contains(&n) => {
+                let extregs = context.extregs();
+                let i = (n - REG_D0) * 2;
+                let value = extregs[i] as u64 | (extregs[i + 1] as u64) << 32;
+                value.to_le_bytes().to_vec()
+            },
+            _ => return None,
+        })
+    }
+
+    fn write_register<H: Handlers>(executor: &mut Executor<H>, n: usize, bytes: &[u8]) -> bool {
+        let word = |bytes: &[u8]| bytes.iter().rev().fold(0u64, |value, &b| value << 8 | b as u64);
+        let context = executor.context();
+        match n {
+            0..=15 if bytes.len() == 4 => context.regs_mut()[n] = word(bytes) as u32,
+            REG_CPSR if bytes.len() == 4 => context.set_cpsr(Cpsr(word(bytes) as u32)),
+            REG_FPSCR if bytes.len() == 4 => context.set_fpscr(Fpscr(word(bytes) as u32)),
+            _ if (REG_D0..REG_FPSCR).contains(&n) && bytes.len() == 8 => {
+                let value = word(bytes);
+                let i = (n - REG_D0) * 2;
+                let mut extregs = context.extregs_mut();
+                extregs[i] = value as u32;
+                extregs[i + 1] = (value >> 32) as u32;
+            },
+            _ => return false,
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ProcessorMode;
+    use crate::memory::MemoryImpl;
+    use std::io::Cursor;
+
+    // Scripted GDB side
+    struct Script {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Script {
+        fn new(packets: &[&str]) -> Self {
+            let mut input = Vec::new();
+            for packet in packets {
+                input.extend(format!("${}#{:02x}", packet, checksum(packet.as_bytes())).bytes());
+                // Ack for the reply
+                input.push(b'+');
+            }
+            Script {
+                input: Cursor::new(input),
+                output: Vec::new(),
+            }
+        }
+
+        fn replies(&self) -> Vec<String> {
+            let output = String::from_utf8(self.output.clone()).unwrap();
+            output.split('$').skip(1).map(|packet| {
+                let (data, sum) = packet.split_at(packet.find('#').unwrap());
+                assert_eq!(u8::from_str_radix(&sum[1..3], 16).unwrap(), checksum(data.as_bytes()));
+                data.to_string()
+            }).collect()
+        }
+    }
+
+    impl Read for Script {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Script {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for Script {}
+
+    #[test]
+    fn packet_framing() {
+        let mut stub = GdbStub::new(Script::new(&[]));
+        stub.connection.input = Cursor::new(b"+\x03$qC#b4$qC#00$?#3f".to_vec());
+        assert_eq!(stub.read_packet().unwrap(), Some("qC".into()));
+        assert_eq!(stub.read_packet().unwrap(), Some("?".into()));
+        assert_eq!(stub.read_packet().unwrap(), None);
+        assert_eq!(stub.connection.output, b"+-+");
+
+        stub.connection.input = Cursor::new(format!("${}#00", "m".repeat(PACKET_SIZE + 1)).into_bytes());
+        assert_eq!(stub.read_packet().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn target_description() {
+        assert!(GdbStub::<Script>::query("Supported:multiprocess+").contains("qXfer:features:read+"));
+
+        let first = GdbStub::<Script>::query("Xfer:features:read:target.xml:0,20");
+        assert_eq!(first, format!("m{}", &TARGET_XML[..0x20]));
+        let rest = GdbStub::<Script>::query(&format!("Xfer:features:read:target.xml:20,{:x}", TARGET_XML.len()));
+        assert_eq!(rest, format!("l{}", &TARGET_XML[0x20..]));
+
+        // One register per number up to fpscr
+        assert_eq!(TARGET_XML.matches("<reg ").count(), REG_FPSCR + 1);
+    }
+
+    #[test]
+    fn executor_breakpoints_and_watchpoints() {
+        use crate::testing::TestHandlers;
+
+        let mut memory = MemoryImpl::new();
+        memory.map_memory(0x0000, 1, false);
+        for (i, word) in [
+            0xE3A00000u32, // mov r0, #0
+            0xE2800001,    // add r0, r0, #1
+            0xE5810000,    // str r0, [r1]
+            0xE3500005,    // cmp r0, #5
+            0x1AFFFFFB,    // bne 4
+            0xEAFFFFFE,    // b .
+        ].iter().enumerate() {
+            memory.write(i as u32 * 4, *word).unwrap();
+        }
+
+        let mut executor = Executor::new(TestHandlers::new(memory));
+        executor.context().set_cpsr(Cpsr::new(ProcessorMode::User));
+        executor.context().regs_mut()[1] = 0x800;
+        executor.context().regs_mut()[15] = 0;
+
+        let mut stub = GdbStub::new(Script::new(&[
+            "Z0,c,4",
+            "c",
+            "p0",
+            "z0,c,4",
+            "Z2,800,4",
+            "c",
+            "p0",
+            "s",
+            "pf",
+            "m800,4",
+            "",
+            "\u{e9}1",
+            "Z2,0,100000",
+            "P2=78563412",
+            "D",
+        ]));
+        stub.serve(&mut executor).unwrap();
+
+        assert_eq!(stub.connection.replies(), vec![
+            "OK",
+            "S05",
+            "01000000",
+            "OK",
+            "OK",
+            "T05watch:800;",
+            "02000000",
+            "S05",
+            "0c000000",
+            "02000000",
+            "",
+            "",
+            "E01",
+            "OK",
+            "OK",
+        ]);
+        assert_eq!(executor.context().regs()[2], 0x12345678);
+    }
+}
@@ -0,0 +1,109 @@
+use dynarmic_sys::{Jit, dynarmic_halt};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use crate::Interrupt;
+
+struct RawJit(*mut Jit);
+
+// Only used for dynarmic_halt, which just raises a flag checked between blocks
+unsafe impl Send for RawJit {}
+
+// Interrupt line states. A declined interrupt stays pending without being offered again.
+const CLEAR: u8 = 0;
+const RAISED: u8 = 1;
+const DECLINED: u8 = 2;
+
+struct Shared {
+    requested: AtomicBool,
+    irq: AtomicU8,
+    fiq: AtomicU8,
+    // None until the JIT exists and again once the executor is gone
+    jit: Mutex<Option<RawJit>>,
+}
+
+// Stops an Executor or interrupts it from any thread, see Executor::halt_handle
+#[derive(Clone)]
+pub struct HaltHandle {
+    shared: Arc<Shared>,
+}
+
+impl HaltHandle {
+    pub(crate) fn new() -> Self {
+        HaltHandle {
+            shared: Arc::new(Shared {
+                requested: AtomicBool::new(false),
+                irq: AtomicU8::new(CLEAR),
+                fiq: AtomicU8::new(CLEAR),
+                jit: Mutex::new(None),
+            }),
+        }
+    }
+
+    fn line(&self, interrupt: Interrupt) -> &AtomicU8 {
+        match interrupt {
+            Interrupt::Irq => &self.shared.irq,
+            Interrupt::Fiq => &self.shared.fiq,
+        }
+    }
+
+    fn halt_jit(&self) {
+        if let Some(jit) = &*self.shared.jit.lock().unwrap() {
+            unsafe { dynarmic_halt(&*jit.0) }
+        }
+    }
+
+    // Ends the current run at the next block boundary with HaltReason::External. Without a run in
+    // progress the next one stops before executing anything. Does nothing after the executor is
+    // dropped.
+    pub fn halt(&self) {
+        let jit = self.shared.jit.lock().unwrap();
+        if let Some(jit) = &*jit {
+            self.shared.requested.store(true, Ordering::SeqCst);
+            unsafe { dynarmic_halt(&*jit.0) }
+        }
+    }
+
+    // Leaves the interrupt pending until Handlers::handle_interrupt takes it. The current run
+    // carries on once it's been offered at the next block boundary. Once declined it's only
+    // offered again after being raised again.
+    pub fn raise(&self, interrupt: Interrupt) {
+        self.line(interrupt).store(RAISED, Ordering::SeqCst);
+        self.halt_jit();
+    }
+
+    pub fn is_pending(&self, interrupt: Interrupt) -> bool {
+        self.line(interrupt).load(Ordering::SeqCst) != CLEAR
+    }
+
+    // Withdraws an interrupt that hasn't been taken yet
+    pub fn clear(&self, interrupt: Interrupt) {
+        self.line(interrupt).store(CLEAR, Ordering::SeqCst);
+    }
+
+    // Raised and not declined since
+    pub(crate) fn needs_offer(&self, interrupt: Interrupt) -> bool {
+        self.line(interrupt).load(Ordering::SeqCst) == RAISED
+    }
+
+    // After clear, so a raise in the meantime wins
+    pub(crate) fn decline(&self, interrupt: Interrupt) {
+        let _ = self.line(interrupt).compare_exchange(CLEAR, DECLINED, Ordering::SeqCst, Ordering::SeqCst);
+    }
+
+    pub(crate) fn attach(&self, jit: *mut Jit) {
+        *self.shared.jit.lock().unwrap() = Some(RawJit(jit));
+    }
+
+    pub(crate) fn is_requested(&self) -> bool {
+        self.shared.requested.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn take_request(&self) -> bool {
+        self.shared.requested.swap(false, Ordering::SeqCst)
+    }
+
+    pub(crate) fn detach(&self) {
+        self.shared.jit.lock().unwrap().take();
+    }
+}
@@ -0,0 +1,56 @@
+// Host side plumbing shared by the guest OS personalities
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+pub(crate) enum HostFile {
+    Reader(Box<dyn Read>),
+    Writer(Box<dyn Write>),
+    File(File),
+}
+
+impl HostFile {
+    pub(crate) fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            HostFile::Reader(r) => r.read(buf),
+            HostFile::File(f) => f.read(buf),
+            HostFile::Writer(_) => Err(io::Error::new(io::ErrorKind::PermissionDenied, "Not readable")),
+        }
+    }
+
+    pub(crate) fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            HostFile::Writer(w) => w.write_all(buf),
+            HostFile::File(f) => f.write_all(buf),
+            HostFile::Reader(_) => Err(io::Error::new(io::ErrorKind::PermissionDenied, "Not writable")),
+        }
+    }
+
+    pub(crate) fn file(&mut self) -> Option<&mut File> {
+        match self {
+            HostFile::File(f) => Some(f),
+            _ => None,
+        }
+    }
+}
+
+// Linux and newlib agree on these
+pub(crate) fn io_errno(error: &io::Error) -> i32 {
+    match error.kind() {
+        io::ErrorKind::NotFound => 2,          // ENOENT
+        io::ErrorKind::PermissionDenied => 13, // EACCES
+        io::ErrorKind::AlreadyExists => 17,    // EEXIST
+        io::ErrorKind::InvalidInput => 22,     // EINVAL
+        _ => 5,                                // EIO
+    }
+}
+
+// Guest paths, absolute or not, resolve below root. None if the path tries to climb out of it.
+pub(crate) fn sandboxed_path(root: &Path, path: &str) -> Option<PathBuf> {
+    let path = Path::new(path);
+    if path.components().any(|c| c == Component::ParentDir) {
+        return None;
+    }
+    Some(root.join(path.strip_prefix("/").unwrap_or(path)))
+}
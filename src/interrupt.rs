@@ -0,0 +1,196 @@
+use crate::{Cpsr, JitContext, ProcessorMode, Reg};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Interrupt {
+    Irq,
+    Fiq,
+}
+
+impl Interrupt {
+    // Whether the CPSR I or F bit holds it off
+    pub fn is_masked(self, cpsr: Cpsr) -> bool {
+        match self {
+            Interrupt::Irq => cpsr.irq_masked(),
+            Interrupt::Fiq => cpsr.fiq_masked(),
+        }
+    }
+
+    pub fn mode(self) -> ProcessorMode {
+        match self {
+            Interrupt::Irq => ProcessorMode::Irq,
+            Interrupt::Fiq => ProcessorMode::Fiq,
+        }
+    }
+
+    // Offset into the vector table
+    pub fn vector_offset(self) -> u32 {
+        match self {
+            Interrupt::Irq => 0x18,
+            Interrupt::Fiq => 0x1C,
+        }
+    }
+}
+
+const USR_BANK: usize = 0;
+const FIQ_BANK: usize = 1;
+
+fn bank(mode: ProcessorMode) -> usize {
+    match mode {
+        ProcessorMode::User | ProcessorMode::System => USR_BANK,
+        ProcessorMode::Fiq => FIQ_BANK,
+        ProcessorMode::Irq => 2,
+        ProcessorMode::Supervisor => 3,
+        ProcessorMode::Abort => 4,
+        ProcessorMode::Undefined => 5,
+        ProcessorMode::Monitor => 6,
+        ProcessorMode::Hyp => 7,
+    }
+}
+
+// Dynarmic only has the current mode's registers and no SPSRs. This keeps the rest, swapping SP and
+// LR (and R8-R12 around FIQ mode) in and out of the JIT when the mode changes. The mode in the
+// CPSR has to agree with what the bank was last switched to.
+pub struct BankedRegisters {
+    vector_base: u32,
+    sp: [u32; 8],
+    lr: [u32; 8],
+    spsr: [u32; 8],
+    r8_r12: [[u32; 5]; 2], // User and FIQ
+}
+
+impl BankedRegisters {
+    // 0 for low vectors, 0xFFFF0000 for high ones or wherever VBAR points
+    pub fn new(vector_base: u32) -> BankedRegisters {
+        BankedRegisters {
+            vector_base,
+            sp: [0; 8],
+            lr: [0; 8],
+            spsr: [0; 8],
+            r8_r12: [[0; 5]; 2],
+        }
+    }
+
+    pub fn vector_base(&self) -> u32 {
+        self.vector_base
+    }
+
+    pub fn set_vector_base(&mut self, vector_base: u32) {
+        self.vector_base = vector_base;
+    }
+
+    // Banked copies of modes other than the current one, the current one's live in the JIT
+    pub fn sp(&self, mode: ProcessorMode) -> u32 {
+        self.sp[bank(mode)]
+    }
+
+    pub fn set_sp(&mut self, mode: ProcessorMode, sp: u32) {
+        self.sp[bank(mode)] = sp;
+    }
+
+    pub fn lr(&self, mode: ProcessorMode) -> u32 {
+        self.lr[bank(mode)]
+    }
+
+    pub fn set_lr(&mut self, mode: ProcessorMode, lr: u32) {
+        self.lr[bank(mode)] = lr;
+    }
+
+    // User and System mode have no SPSR, theirs reads as 0
+    pub fn spsr(&self, mode: ProcessorMode) -> Cpsr {
+        Cpsr(self.spsr[bank(mode)])
+    }
+
+    pub fn set_spsr(&mut self, mode: ProcessorMode, spsr: Cpsr) {
+        if bank(mode) != USR_BANK {
+            self.spsr[bank(mode)] = spsr.0;
+        }
+    }
+
+    // Like CPS, banks the current mode's registers and brings in those of mode. Only the mode bits
+    // of the CPSR change.
+    pub fn switch_mode(&mut self, context: &JitContext, mode: ProcessorMode) {
+        let mut cpsr = context.cpsr();
+        let from = bank(cpsr.mode().unwrap_or(ProcessorMode::User));
+        let to = bank(mode);
+
+        if from != to {
+            let mut regs = context.regs_mut();
+            self.sp[from] = regs[Reg::Sp];
+            self.lr[from] = regs[Reg::Lr];
+            regs[Reg::Sp] = self.sp[to];
+            regs[Reg::Lr] = self.lr[to];
+
+            if (from == FIQ_BANK) != (to == FIQ_BANK) {
+                let (from, to) = if to == FIQ_BANK { (0, 1) } else { (1, 0) };
+                self.r8_r12[from].copy_from_slice(&regs[8..13]);
+                regs[8..13].copy_from_slice(&self.r8_r12[to]);
+            }
+        }
+
+        cpsr.set_mode(mode);
+        context.set_cpsr(cpsr);
+    }
+
+    // Takes the interrupt unless the CPSR masks it: banks LR and SPSR, masks further interrupts and
+    // jumps to the ARM vector. Meant for Handlers::handle_interrupt, at a block boundary the PC is
+    // the next instruction to execute.
+    pub fn take_interrupt(&mut self, context: &JitContext, interrupt: Interrupt) -> bool {
+        let cpsr = context.cpsr();
+        if interrupt.is_masked(cpsr) {
+            return false;
+        }
+
+        // Returned from with SUBS PC, LR, #4
+        let return_address = context.pc().wrapping_add(4);
+        let mode = interrupt.mode();
+        self.switch_mode(context, mode);
+        self.set_spsr(mode, cpsr);
+        context.set_lr(return_address);
+
+        let mut entered = Cpsr(cpsr.0 & !(1 << 24)); // J
+        entered.set_mode(mode);
+        entered.set_irq_masked(true);
+        entered.set_fiq_masked(cpsr.fiq_masked() || interrupt == Interrupt::Fiq);
+        entered.set_async_abort_masked(true);
+        entered.set_thumb(false);
+        entered.set_it(0);
+        entered.set_big_endian(false);
+        context.set_cpsr(entered);
+        context.set_pc(self.vector_base.wrapping_add(interrupt.vector_offset()));
+        true
+    }
+
+    // What an exception return like SUBS PC, LR, #4 does, with pc being LR minus the offset: the
+    // CPSR comes back from the SPSR, along with the registers of the mode it names.
+    pub fn return_from_exception(&mut self, context: &JitContext, pc: u32) {
+        let mode = context.cpsr().mode().unwrap_or(ProcessorMode::User);
+        let spsr = self.spsr(mode);
+        self.switch_mode(context, spsr.mode().unwrap_or(ProcessorMode::User));
+        context.set_cpsr(spsr);
+        let thumb = spsr.is_thumb() as u32;
+        context.set_pc(pc & !1 | thumb);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masks_and_vectors() {
+        let mut cpsr = Cpsr::new(ProcessorMode::User);
+        assert!(!Interrupt::Irq.is_masked(cpsr));
+        cpsr.set_irq_masked(true);
+        assert!(Interrupt::Irq.is_masked(cpsr));
+        assert!(!Interrupt::Fiq.is_masked(cpsr));
+
+        let mut banked = BankedRegisters::new(0xFFFF0000);
+        banked.set_spsr(ProcessorMode::User, cpsr);
+        assert_eq!(banked.spsr(ProcessorMode::System).0, 0);
+        banked.set_spsr(ProcessorMode::Irq, cpsr);
+        assert_eq!(banked.spsr(ProcessorMode::Irq), cpsr);
+        banked.set_sp(ProcessorMode::System, 0x8000);
+        assert_eq!(banked.sp(ProcessorMode::User), 0x8000);
+        assert_eq!(banked.vector_base() + Interrupt::Fiq.vector_offset(), 0xFFFF001C);
+    }
+}
@@ -1,9 +1,77 @@
+pub mod a64;
+mod builder;
+pub mod coprocessor;
+mod cp15;
+mod cpu_context;
+pub mod elf;
+pub mod gdbstub;
+mod halt;
+mod host;
+mod interrupt;
+#[cfg(feature = "linux")]
+pub mod linux;
 pub mod memory;
+mod monitor;
+mod regs;
+pub mod savestate;
+pub mod semihosting;
+#[cfg(test)]
+mod testing;
 
 use dynarmic_sys::*;
-use std::cell::{RefCell, Ref, RefMut};
+use dynarmic_sys::unwind;
+use std::any::Any;
+use std::cell::{Cell, RefCell, Ref, RefMut};
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+use std::panic;
+use std::sync::Arc;
+
+use memory::{Memory, MemoryFault, WatchHit};
+
+pub use dynarmic_sys::Exception;
+pub use builder::{ExecutorBuilder, Optimizations};
+pub use cpu_context::CpuContext;
+pub use halt::HaltHandle;
+pub use interrupt::{BankedRegisters, Interrupt};
+pub use monitor::ExclusiveMonitor;
+pub use regs::{Cpsr, Fpscr, ProcessorMode, Reg, RoundingMode};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DataAbort<A = u32> {
+    // Dynarmic only syncs the PC at block boundaries, so this is the start of the faulting block
+    pub pc: A,
+    pub fault: MemoryFault<A>,
+}
+
+// Parameterized so the A64 executor can share it, see a64::HaltReason64
+#[derive(Debug)]
+pub enum HaltReason<A = u32, E = Exception> {
+    TicksExhausted,
+    // Executor::step finished its instruction
+    Stepped,
+    // JitContext::halt was called
+    Halted,
+    // JitContext::halt_with was called, the payload is up to the handlers
+    User(Box<dyn Any + Send>),
+    Exception {
+        pc: A,
+        exception: E,
+    },
+    DataAbort(DataAbort<A>),
+    // Reached an address passed to Executor::add_breakpoint, the instruction there hasn't executed
+    Breakpoint(A),
+    // Default for Handlers::handle_watchpoint
+    Watchpoint(WatchHit<A>),
+    // HaltHandle::halt was called
+    External,
+}
 
-use memory::Memory;
+#[derive(Debug)]
+pub struct RunResult<A = u32, E = Exception> {
+    pub reason: HaltReason<A, E>,
+    pub ticks_executed: u64,
+}
 
 pub trait Handlers: Sized {
     type Memory: Memory;
@@ -12,14 +80,160 @@ pub trait Handlers: Sized {
     
     fn handle_svc(&mut self, _context: JitContext, _swi: u32) {}
 
+    // Called for undefined/unpredictable instructions, BKPT and (if hooked) hint instructions.
+    // By default hints are ignored and everything else halts execution.
+    fn handle_exception(&mut self, context: JitContext, pc: u32, exception: Exception) {
+        if !exception.is_hint() {
+            context.halt_with_reason(HaltReason::Exception { pc, exception });
+        }
+    }
+
+    // Called after an access to memory watched with MemoryImpl::add_watchpoint. Halts by default,
+    // like data aborts that happens at the end of the current block. hit.pc is the start of that
+    // block, which is only the accessing instruction when running with Executor::step.
+    fn handle_watchpoint(&mut self, context: JitContext, hit: WatchHit) {
+        context.halt_with_reason(HaltReason::Watchpoint(hit));
+    }
+
+    // Offered an interrupt raised with Executor::raise_irq or raise_fiq that the CPSR doesn't mask,
+    // between blocks of a run. Returning true takes it, otherwise it stays pending but isn't
+    // offered again until it's raised again. Usually BankedRegisters::take_interrupt does the work.
+    fn handle_interrupt(&mut self, _context: JitContext, _interrupt: Interrupt) -> bool {
+        false
+    }
+
     fn make_coprocessors<'jit>(&'jit mut self) -> Option<[Option<coproc::CoprocessorCallbacks<'jit>>; 16]> {
         None
     }
+
+    // Loading a savestate replaces memory, which needs this
+    fn memory_mut(&mut self) -> Option<&mut Self::Memory> {
+        None
+    }
+
+    // Extra state for savestates, written after memory
+    fn save_state(&self, _w: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn load_state(&mut self, _r: &mut dyn Read) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Executor state that doesn't depend on the handler type
+pub(crate) struct RunState<A = u32, E = Exception> {
+    halt_reason: RefCell<Option<HaltReason<A, E>>>,
+    ticks_remaining: Cell<u64>,
+    ticks_executed: Cell<u64>, // Since the executor was created
+    resume: Cell<bool>, // Dynarmic was halted mid-run to invalidate code or offer interrupts
+}
+
+impl<A, E> RunState<A, E> {
+    pub(crate) fn new() -> Self {
+        RunState {
+            halt_reason: RefCell::new(None),
+            ticks_remaining: Cell::new(0),
+            ticks_executed: Cell::new(0),
+            resume: Cell::new(false),
+        }
+    }
+
+    // The first reason recorded during a run wins, later halts only stop the JIT
+    pub(crate) fn record_halt(&self, reason: HaltReason<A, E>) {
+        let mut halt_reason = self.halt_reason.borrow_mut();
+        if halt_reason.is_none() {
+            *halt_reason = Some(reason);
+        }
+    }
+
+    pub(crate) fn has_halt_reason(&self) -> bool {
+        self.halt_reason.borrow().is_some()
+    }
+
+    pub(crate) fn take_halt_reason(&self, default: HaltReason<A, E>) -> HaltReason<A, E> {
+        self.halt_reason.borrow_mut().take().unwrap_or(default)
+    }
+
+    pub(crate) fn add_ticks(&self, ticks: u64) {
+        self.ticks_remaining.set(self.ticks_remaining.get().saturating_sub(ticks));
+        self.ticks_executed.set(self.ticks_executed.get() + ticks);
+    }
+
+    pub(crate) fn ticks_remaining(&self) -> u64 {
+        // Dynarmic treats the budget as signed
+        self.ticks_remaining.get().min(std::i64::MAX as u64)
+    }
+
+    pub(crate) fn set_ticks_remaining(&self, ticks: u64) {
+        self.ticks_remaining.set(ticks);
+    }
+
+    pub(crate) fn ticks_executed(&self) -> u64 {
+        self.ticks_executed.get()
+    }
+
+    // A panic left over from outside a run, e.g. another executor on this thread, isn't ours to
+    // rethrow
+    pub(crate) fn discard_panic(&self) {
+        let _ = unwind::take_panic();
+    }
+
+    // Rethrows a panic caught in a callback, the JIT has returned so it's safe to unwind
+    pub(crate) fn resume_panic(&self) {
+        if let Some(payload) = unwind::take_panic() {
+            self.set_ticks_remaining(0);
+            self.resume.set(false);
+            self.halt_reason.borrow_mut().take();
+            panic::resume_unwind(payload)
+        }
+    }
+
+    pub(crate) fn request_resume(&self) {
+        self.resume.set(true);
+    }
+
+    // Whether a run stopped only to invalidate code or offer interrupts and can carry on
+    pub(crate) fn take_resume(&self) -> bool {
+        self.resume.replace(false) && self.can_resume()
+    }
+
+    pub(crate) fn can_resume(&self) -> bool {
+        !self.has_halt_reason() && self.ticks_remaining() > 0
+    }
 }
 
+type ExceptionRaised = extern fn(&mut Jit, u32, Exception);
+
+// How every Context<H> starts, so JitContext can find these without knowing H
+#[repr(C)]
+struct ContextHeader {
+    state: RunState,
+    exception_raised: ExceptionRaised,
+}
+
+#[repr(C)]
 pub struct Context<H: Handlers> {
+    state: RunState,
+    exception_raised: ExceptionRaised,
     handlers: H,
-    ticks: u64,
+    breakpoints: BTreeSet<u32>,
+    // Breakpoint being stepped over, translated without its BKPT
+    suppressed: Option<u32>,
+    halt: HaltHandle,
+}
+
+const ARM_BKPT: u32 = 0xE1200070;
+const THUMB_BKPT: u32 = 0xBE00;
+
+// Runs a callback's body. A panic halts the JIT and is resumed by Executor once dynarmic returns,
+// see dynarmic_sys::unwind.
+fn guard<R: Default, F: FnOnce(&mut Jit) -> R>(jit: &mut Jit, f: F) -> R {
+    let value = unwind::catch_panic(|| f(&mut *jit));
+    if unwind::panic_pending() {
+        unsafe { dynarmic_halt(jit) }
+    }
+    value.unwrap_or_default()
 }
 
 pub struct JitContext<'a> {
@@ -27,6 +241,23 @@ pub struct JitContext<'a> {
 }
 
 impl<'a> JitContext<'a> {
+    fn header(&self) -> &ContextHeader {
+        unsafe { &*(dynarmic_get_userdata(*self.jit.borrow()) as *const ContextHeader) }
+    }
+
+    fn state(&self) -> &RunState {
+        &self.header().state
+    }
+
+    // Hands an exception to Handlers::handle_exception the way dynarmic would, for callbacks that
+    // decide an instruction is undefined. Dynarmic only syncs the PC at block boundaries, so the
+    // handler sees the start of the block, and the rest of the block still runs.
+    pub(crate) fn raise_exception(&self, exception: Exception) {
+        let pc = self.pc();
+        let exception_raised = self.header().exception_raised;
+        exception_raised(&mut self.jit.borrow_mut(), pc, exception)
+    }
+
     pub fn regs(&self) -> Ref<[u32; 16]> {
         Ref::map(self.jit.borrow(), |jit| unsafe { dynarmic_regs(jit) })
     }
@@ -43,23 +274,114 @@ impl<'a> JitContext<'a> {
         RefMut::map(self.jit.borrow_mut(), |jit| unsafe { dynarmic_extregs_mut(jit) })
     }
 
-    pub fn cpsr(&self) -> u32 {
-        unsafe { dynarmic_cpsr(*self.jit.borrow()) }
+    pub fn pc(&self) -> u32 {
+        self.regs()[Reg::Pc]
+    }
+
+    // Interworking like BX, bit 0 of addr picks Thumb state
+    pub fn set_pc(&self, addr: u32) {
+        let mut cpsr = self.cpsr();
+        cpsr.set_thumb(addr & 1 != 0);
+        self.set_cpsr(cpsr);
+        self.regs_mut()[Reg::Pc] = addr & !1;
+    }
+
+    pub fn sp(&self) -> u32 {
+        self.regs()[Reg::Sp]
+    }
+
+    pub fn set_sp(&self, sp: u32) {
+        self.regs_mut()[Reg::Sp] = sp;
+    }
+
+    pub fn lr(&self) -> u32 {
+        self.regs()[Reg::Lr]
+    }
+
+    pub fn set_lr(&self, lr: u32) {
+        self.regs_mut()[Reg::Lr] = lr;
+    }
+
+    // VFP/NEON registers alias within extregs: S2n and S2n+1 make up Dn, D2n and D2n+1 make up Qn
+    pub fn s(&self, n: usize) -> f32 {
+        assert!(n < 32, "No register S{}", n);
+        f32::from_bits(self.extregs()[n])
+    }
+
+    pub fn set_s(&self, n: usize, value: f32) {
+        assert!(n < 32, "No register S{}", n);
+        self.extregs_mut()[n] = value.to_bits();
+    }
+
+    pub fn d(&self, n: usize) -> f64 {
+        assert!(n < 32, "No register D{}", n);
+        let extregs = self.extregs();
+        f64::from_bits(extregs[n * 2] as u64 | (extregs[n * 2 + 1] as u64) << 32)
+    }
+
+    pub fn set_d(&self, n: usize, value: f64) {
+        assert!(n < 32, "No register D{}", n);
+        let bits = value.to_bits();
+        let mut extregs = self.extregs_mut();
+        extregs[n * 2] = bits as u32;
+        extregs[n * 2 + 1] = (bits >> 32) as u32;
+    }
+
+    pub fn q(&self, n: usize) -> u128 {
+        assert!(n < 16, "No register Q{}", n);
+        let extregs = self.extregs();
+        (0..4).rev().fold(0, |value, i| value << 32 | extregs[n * 4 + i] as u128)
+    }
+
+    pub fn set_q(&self, n: usize, value: u128) {
+        assert!(n < 16, "No register Q{}", n);
+        let mut extregs = self.extregs_mut();
+        for i in 0..4 {
+            extregs[n * 4 + i] = (value >> (i * 32)) as u32;
+        }
+    }
+
+    pub fn cpsr(&self) -> Cpsr {
+        Cpsr(unsafe { dynarmic_cpsr(*self.jit.borrow()) })
+    }
+
+    pub fn set_cpsr(&self, cpsr: Cpsr) {
+        unsafe { dynarmic_set_cpsr(*self.jit.borrow(), cpsr.0) }
+    }
+
+    pub fn fpscr(&self) -> Fpscr {
+        Fpscr(unsafe { dynarmic_fpscr(*self.jit.borrow()) })
+    }
+
+    pub fn set_fpscr(&self, fpscr: Fpscr) {
+        unsafe { dynarmic_set_fpscr(*self.jit.borrow(), fpscr.0) }
     }
 
-    pub fn set_cpsr(&self, cpsr: u32) {
-        unsafe { dynarmic_set_cpsr(*self.jit.borrow(), cpsr) }
+    pub fn ticks_executed(&self) -> u64 {
+        self.state().ticks_executed()
     }
 
-    pub fn fpscr(&self) -> u32 {
-        unsafe { dynarmic_fpscr(*self.jit.borrow()) }
+    // Takes effect at the end of the current block, the run carries on afterwards
+    pub fn invalidate_range(&self, addr: u32, len: u32) {
+        self.state().request_resume();
+        unsafe { dynarmic_invalidate_cache_range(*self.jit.borrow(), addr, len as usize) }
     }
 
-    pub fn set_fpscr(&self, fpscr: u32) {
-        unsafe { dynarmic_set_fpscr(*self.jit.borrow(), fpscr) }
+    pub fn clear_cache(&self) {
+        self.state().request_resume();
+        unsafe { dynarmic_clear_cache(*self.jit.borrow()) }
     }
 
     pub fn halt(&self) {
+        self.halt_with_reason(HaltReason::Halted)
+    }
+
+    pub fn halt_with<P: Any + Send>(&self, payload: P) {
+        self.halt_with_reason(HaltReason::User(Box::new(payload)))
+    }
+
+    pub fn halt_with_reason(&self, reason: HaltReason) {
+        self.state().record_halt(reason);
         unsafe { dynarmic_halt(*self.jit.borrow()) }
     }
 }
@@ -72,42 +394,172 @@ impl<H: Handlers> Context<H> {
         unsafe { std::mem::transmute(ud) }
     }
 
+    // Stops the JIT at the end of the current block
+    fn data_abort(&mut self, jit: &mut Jit, fault: MemoryFault) {
+        let pc = unsafe { dynarmic_regs(jit) }[15];
+        self.state.record_halt(HaltReason::DataAbort(DataAbort { pc, fault }));
+        unsafe { dynarmic_halt(jit) }
+    }
+
+    fn is_breakpoint(&self, addr: u32) -> bool {
+        self.breakpoints.contains(&addr) && self.suppressed != Some(addr)
+    }
+
+    fn flush_invalidations(&self, jit: &mut Jit) {
+        for (addr, len) in self.handlers.memory().take_invalidations() {
+            self.state.request_resume();
+            unsafe { dynarmic_invalidate_cache_range(jit, addr, len as usize) }
+        }
+    }
+
+    fn interrupt_deliverable(&self, jit: &mut Jit) -> bool {
+        let cpsr = Cpsr(unsafe { dynarmic_cpsr(jit) });
+        let deliverable = |interrupt: Interrupt| self.halt.needs_offer(interrupt) && !interrupt.is_masked(cpsr);
+        deliverable(Interrupt::Fiq) || deliverable(Interrupt::Irq)
+    }
+
+    // Handlers may unmask a pending interrupt, it's offered at the end of the block
+    fn check_interrupts(&self, jit: &mut Jit) {
+        if self.interrupt_deliverable(jit) {
+            unsafe { dynarmic_halt(jit) }
+        }
+    }
+
+    fn flush_watch_hits(&mut self, jit: &mut Jit) {
+        for hit in self.handlers.memory().take_watch_hits() {
+            let pc = unsafe { dynarmic_regs(jit) }[15];
+            let jit_context = JitContext {
+                jit: RefCell::new(jit),
+            };
+            self.handlers.handle_watchpoint(jit_context, WatchHit { pc, ..hit });
+        }
+    }
+
     extern fn read<T: memory::Primitive>(jit: &mut Jit, addr: u32) -> T {
-        let context = Self::from_jit(jit);
-        // println!("Read {:X} at PC {:X?}", addr, JitContext { jit: RefCell::new(jit) }.regs());
-        context.handlers.memory().read(addr)
+        guard(jit, |jit| {
+            let context = Self::from_jit(jit);
+            // println!("Read {:X} at PC {:X?}", addr, JitContext { jit: RefCell::new(jit) }.regs());
+            let value = match context.handlers.memory().read(addr) {
+                Ok(value) => value,
+                Err(fault) => {
+                    context.data_abort(jit, fault);
+                    T::default()
+                }
+            };
+            context.flush_watch_hits(jit);
+            value
+        })
+    }
+
+    // Breakpoints are BKPTs patched in while translating. Dynarmic fetches Thumb code a word at a
+    // time, so either halfword can hold one.
+    extern fn read_code(jit: &mut Jit, addr: u32) -> u32 {
+        guard(jit, |jit| {
+            let context = Self::from_jit(jit);
+            let word = match context.handlers.memory().read(addr) {
+                Ok(word) => word,
+                Err(fault) => {
+                    context.data_abort(jit, fault);
+                    0
+                }
+            };
+            // Fetches aren't data reads
+            context.handlers.memory().take_watch_hits();
+            let thumb = Cpsr(unsafe { dynarmic_cpsr(jit) }).is_thumb();
+
+            if !thumb {
+                return if context.is_breakpoint(addr) { ARM_BKPT } else { word };
+            }
+            let mut word = word;
+            if context.is_breakpoint(addr) {
+                word = word & 0xFFFF0000 | THUMB_BKPT;
+            }
+            if context.is_breakpoint(addr.wrapping_add(2)) {
+                word = word & 0x0000FFFF | THUMB_BKPT << 16;
+            }
+            word
+        })
     }
 
     extern fn write<T: memory::Primitive>(jit: &mut Jit, addr: u32, value: T) {
-        let context = Self::from_jit(jit);
-        // println!("Write {:X} at PC {:X?}", addr, JitContext { jit: RefCell::new(jit) }.regs());
-        context.handlers.memory().write(addr, value)
+        guard(jit, |jit| {
+            let context = Self::from_jit(jit);
+            // println!("Write {:X} at PC {:X?}", addr, JitContext { jit: RefCell::new(jit) }.regs());
+            if let Err(fault) = context.handlers.memory().write(addr, value) {
+                context.data_abort(jit, fault);
+            }
+            context.flush_invalidations(jit);
+            context.flush_watch_hits(jit);
+        })
+    }
+
+    extern fn write_exclusive<T: memory::Primitive>(jit: &mut Jit, addr: u32, value: T, expected: T) -> bool {
+        guard(jit, |jit| {
+            let context = Self::from_jit(jit);
+            let stored = match context.handlers.memory().write_exclusive(addr, value, expected) {
+                Ok(stored) => stored,
+                Err(fault) => {
+                    context.data_abort(jit, fault);
+                    false
+                }
+            };
+            context.flush_invalidations(jit);
+            context.flush_watch_hits(jit);
+            stored
+        })
     }
 
     extern fn is_read_only_memory(jit: &mut Jit, addr: u32) -> bool {
-        Self::from_jit(jit).handlers.memory().is_read_only(addr)
+        guard(jit, |jit| {
+            Self::from_jit(jit).handlers.memory().is_read_only(addr)
+        })
     }
 
     extern fn call_svc(jit: &mut Jit, svc: u32) {
-        let context = Self::from_jit(jit);
-        let jit_context = JitContext {
-            jit: RefCell::new(jit),
-        };
-        context.handlers.handle_svc(jit_context, svc);
+        guard(jit, |jit| {
+            let context = Self::from_jit(jit);
+            let jit_context = JitContext {
+                jit: RefCell::new(jit),
+            };
+            context.handlers.handle_svc(jit_context, svc);
+            context.flush_invalidations(jit);
+            context.flush_watch_hits(jit);
+            context.check_interrupts(jit);
+        })
     }
 
-    extern fn exception_raised(jit: &mut Jit, addr: u32, ex: Exception) {
-        unimplemented!()
+    extern fn exception_raised(jit: &mut Jit, pc: u32, exception: Exception) {
+        guard(jit, |jit| {
+            let context = Self::from_jit(jit);
+            if exception == Exception::Breakpoint && context.is_breakpoint(pc) {
+                // Dynarmic has already moved past the BKPT
+                unsafe { dynarmic_regs_mut(jit)[15] = pc };
+                context.state.record_halt(HaltReason::Breakpoint(pc));
+                unsafe { dynarmic_halt(jit) }
+                return;
+            }
+            let jit_context = JitContext {
+                jit: RefCell::new(jit),
+            };
+            context.handlers.handle_exception(jit_context, pc, exception);
+            context.flush_invalidations(jit);
+            context.flush_watch_hits(jit);
+            context.check_interrupts(jit);
+        })
     }
 
     extern fn add_ticks(jit: &mut Jit, ticks: u64) {
-        let ctx = Self::from_jit(jit);
-        ctx.ticks = ctx.ticks.saturating_sub(ticks);
+        Self::from_jit(jit).state.add_ticks(ticks)
     }
 
+    // Dynarmic forgets halts at the start of a run, one requested or an interrupt raised just
+    // before it has to end the run after the first block instead
     extern fn get_ticks_remaining(jit: &mut Jit) -> u64 {
-        let ctx = Self::from_jit(jit);
-        ctx.ticks
+        let context = Self::from_jit(jit);
+        if context.halt.is_requested() || context.interrupt_deliverable(jit) {
+            return 0;
+        }
+        context.state.ticks_remaining()
     }
 
     fn callbacks() -> Callbacks {
@@ -116,10 +568,15 @@ impl<H: Handlers> Context<H> {
             read16: Self::read,
             read32: Self::read,
             read64: Self::read,
+            read_code: Self::read_code,
             write8: Self::write,
             write16: Self::write,
             write32: Self::write,
             write64: Self::write,
+            write_exclusive8: Self::write_exclusive,
+            write_exclusive16: Self::write_exclusive,
+            write_exclusive32: Self::write_exclusive,
+            write_exclusive64: Self::write_exclusive,
             is_read_only_memory: Self::is_read_only_memory,
             call_svc: Self::call_svc,
             exception_raised: Self::exception_raised,
@@ -131,50 +588,353 @@ impl<H: Handlers> Context<H> {
 
 pub mod coproc {
     pub use dynarmic_sys::coprocessor::*;
+    pub use crate::cp15::Cp15;
 }
 
 pub struct Executor<H: Handlers> {
     jit: &'static mut Jit,
     context: *mut Context<H>,
+    // Keeps the monitor alive as long as the JIT refers to it
+    _monitor: Arc<ExclusiveMonitor>,
+    coprocessors: [Option<coproc::CoprocessorState>; 16], // Owned by the JIT
+    halt: HaltHandle,
 }
 
+// Context is only reached through the executor or callbacks on the thread running it. The JIT owns
+// the coprocessors, which CoprocessorCallbacks::callbacks_from requires to be Send.
+unsafe impl<H: Handlers + Send> Send for Executor<H> {}
+
 impl<H: Handlers> Executor<H> {
+    // Uses dynarmic's default configuration, see ExecutorBuilder for the alternative
     pub fn new(handlers: H) -> Self {
+        ExecutorBuilder::new(handlers).build()
+    }
+
+    fn with_config(handlers: H, config: &Config, monitor: Arc<ExclusiveMonitor>) -> Self {
         let mut context = Box::leak(Box::new(Context {
+            state: RunState::new(),
+            exception_raised: Context::<H>::exception_raised,
             handlers,
-            ticks: std::u64::MAX,
+            breakpoints: BTreeSet::new(),
+            suppressed: None,
+            halt: HaltHandle::new(),
         }));
 
         let context_ptr = context as *mut Context<H>;
 
         let callbacks = Context::<H>::callbacks();
 
+        let page_table = context.handlers.memory().page_table()
+            .map_or(std::ptr::null(), |page_table| page_table as *const _);
+
         let cp = context.handlers.make_coprocessors();
 
-        let cp_callbacks = cp.as_ref().map(|cp| [
-            cp[0].as_ref(), cp[1].as_ref(), cp[2].as_ref(), cp[3].as_ref(),
-            cp[4].as_ref(), cp[5].as_ref(), cp[6].as_ref(), cp[7].as_ref(),
-            cp[8].as_ref(), cp[9].as_ref(), cp[10].as_ref(), cp[11].as_ref(),
-            cp[12].as_ref(), cp[13].as_ref(), cp[14].as_ref(), cp[15].as_ref(),
-        ]);
+        let cp_callbacks = cp.as_ref().map(|cp| {
+            let mut raw: [Option<&coproc::RawCoprocessorCallbacks>; 16] = Default::default();
+            for (raw, cp) in raw.iter_mut().zip(cp.iter()) {
+                *raw = cp.as_ref().map(|cp| cp.raw());
+            }
+            raw
+        });
+
+        let mut coprocessors: [Option<coproc::CoprocessorState>; 16] = Default::default();
+        if let Some(cp) = &cp {
+            for (state, cp) in coprocessors.iter_mut().zip(cp.iter()) {
+                *state = cp.as_ref().map(|cp| cp.state());
+            }
+        }
 
         let jit = unsafe {
             dynarmic_new(
                 context_ptr as *mut _,
                 &callbacks,
-                std::ptr::null(),
+                config,
+                page_table,
                 cp_callbacks.as_ref(),
             )
         };
 
+        let halt = context.halt.clone();
+        halt.attach(&mut *jit);
+
         Executor {
             jit,
             context: context_ptr,
+            _monitor: monitor,
+            coprocessors,
+            halt,
+        }
+    }
+
+    fn state(&self) -> &RunState {
+        unsafe { &(*self.context).state }
+    }
+
+    // Runs until halted. Halts requested from callbacks take effect at the end of the current
+    // block, so the rest of a faulting block still executes.
+    pub fn run(&mut self) -> HaltReason {
+        self.run_for(u64::MAX).reason
+    }
+
+    // Runs until halted or the budget is used up. Ticks are only checked between blocks, so a run
+    // can overshoot its budget by a few instructions.
+    pub fn run_for(&mut self, ticks: u64) -> RunResult {
+        self.state().discard_panic();
+        if ticks == 0 {
+            return RunResult {
+                reason: HaltReason::TicksExhausted,
+                ticks_executed: 0,
+            };
+        }
+
+        if self.halt.take_request() {
+            return RunResult {
+                reason: HaltReason::External,
+                ticks_executed: 0,
+            };
+        }
+
+        self.deliver_interrupts();
+        if self.state().has_halt_reason() {
+            return RunResult {
+                reason: self.state().take_halt_reason(HaltReason::Halted),
+                ticks_executed: 0,
+            };
+        }
+
+        let start = self.ticks_executed();
+
+        // Resuming from a breakpoint, the instruction it stopped at has to run first
+        if self.at_breakpoint() {
+            let reason = self.step();
+            let ticks_executed = self.ticks_executed() - start;
+            match reason {
+                HaltReason::Stepped if ticks_executed < ticks => {},
+                HaltReason::Stepped => return RunResult {
+                    reason: HaltReason::TicksExhausted,
+                    ticks_executed,
+                },
+                reason => return RunResult { reason, ticks_executed },
+            }
+        }
+
+        self.flush_invalidations();
+        self.state().set_ticks_remaining(ticks - (self.ticks_executed() - start));
+        loop {
+            unsafe { dynarmic_run(self.jit) }
+            self.take_halt_request();
+            if unwind::panic_pending() {
+                break;
+            }
+            if self.state().can_resume() {
+                self.deliver_interrupts();
+            }
+            if !self.state().take_resume() {
+                break;
+            }
+        }
+        self.state().resume_panic();
+        self.state().set_ticks_remaining(0);
+
+        RunResult {
+            // Every halt goes through RunState, so dynarmic only stops on its own when out of ticks
+            reason: self.state().take_halt_reason(HaltReason::TicksExhausted),
+            ticks_executed: self.ticks_executed() - start,
+        }
+    }
+
+    // Executes a single instruction, even if there's a breakpoint on it
+    pub fn step(&mut self) -> HaltReason {
+        if !self.at_breakpoint() {
+            return self.step_instruction();
+        }
+
+        let pc = self.context().pc();
+        unsafe { (*self.context).suppressed = Some(pc) };
+        self.invalidate_range(pc & !3, 4);
+        let reason = self.step_instruction();
+        unsafe { (*self.context).suppressed = None };
+        self.invalidate_range(pc & !3, 4);
+        reason
+    }
+
+    fn step_instruction(&mut self) -> HaltReason {
+        self.state().discard_panic();
+        self.flush_invalidations();
+        self.state().set_ticks_remaining(1);
+        unsafe { dynarmic_step(self.jit) }
+        self.take_halt_request();
+        self.state().resume_panic();
+        self.state().set_ticks_remaining(0);
+        self.state().take_resume();
+        self.state().take_halt_reason(HaltReason::Stepped)
+    }
+
+    pub fn ticks_executed(&self) -> u64 {
+        self.state().ticks_executed()
+    }
+
+    // For stopping runs from other threads
+    pub fn halt_handle(&self) -> HaltHandle {
+        self.halt.clone()
+    }
+
+    // Raises an interrupt, offered to Handlers::handle_interrupt between blocks of a run. HaltHandle
+    // does the same from other threads.
+    pub fn raise_irq(&self) {
+        self.halt.raise(Interrupt::Irq)
+    }
+
+    pub fn raise_fiq(&self) {
+        self.halt.raise(Interrupt::Fiq)
+    }
+
+    // A safe point, FIQs go first. Whatever the handlers do has to be picked up by the JIT
+    // before it carries on.
+    fn deliver_interrupts(&mut self) {
+        for &interrupt in &[Interrupt::Fiq, Interrupt::Irq] {
+            if !self.halt.needs_offer(interrupt) {
+                continue;
+            }
+            self.state().request_resume();
+            if interrupt.is_masked(self.context().cpsr()) {
+                continue;
+            }
+            // Cleared first so raises from other threads meanwhile aren't lost
+            self.halt.clear(interrupt);
+            let handlers = unsafe { &mut (*self.context).handlers };
+            if !handlers.handle_interrupt(self.context(), interrupt) {
+                self.halt.decline(interrupt);
+            }
+        }
+        self.flush_invalidations();
+    }
+
+    fn take_halt_request(&self) {
+        if self.halt.take_request() {
+            self.state().record_halt(HaltReason::External);
+        }
+    }
+
+    // Stops runs before the instruction at addr executes, in either ARM or Thumb state. addr has
+    // to be the start of an instruction, the JIT only looks for breakpoints while translating.
+    pub fn add_breakpoint(&mut self, addr: u32) {
+        if unsafe { (*self.context).breakpoints.insert(addr) } {
+            self.invalidate_range(addr & !3, 4);
+        }
+    }
+
+    // Returns whether there was a breakpoint at addr
+    pub fn remove_breakpoint(&mut self, addr: u32) -> bool {
+        let removed = unsafe { (*self.context).breakpoints.remove(&addr) };
+        if removed {
+            self.invalidate_range(addr & !3, 4);
         }
+        removed
+    }
+
+    fn at_breakpoint(&mut self) -> bool {
+        let pc = self.context().pc();
+        unsafe { (*self.context).breakpoints.contains(&pc) }
+    }
+
+    // Drops translated blocks overlapping the range, needed after changing guest code behind the
+    // JIT's back. MemoryImpl can do this automatically, see MemoryImpl::set_invalidate_on_write.
+    pub fn invalidate_range(&mut self, addr: u32, len: u32) {
+        unsafe { dynarmic_invalidate_cache_range(self.jit, addr, len as usize) }
+    }
+
+    pub fn clear_cache(&mut self) {
+        unsafe { dynarmic_clear_cache(self.jit) }
+    }
+
+    // Picks up writes made to guest code outside of a run. Watched accesses made outside of a run
+    // weren't the guest's doing, so those are dropped.
+    fn flush_invalidations(&mut self) {
+        let context = unsafe { &*self.context };
+        context.flush_invalidations(self.jit);
+        context.handlers.memory().take_watch_hits();
+        self.state().take_resume();
     }
 
-    pub fn run(&mut self) {
-        unsafe { dynarmic_run(self.jit) }
+    // Context switching for guest threads, none of these allocate once the CpuContext exists
+    pub fn save_context(&mut self, context: &mut CpuContext) {
+        unsafe { dynarmic_save_context(self.jit, context.raw_mut()) }
+    }
+
+    pub fn load_context(&mut self, context: &CpuContext) {
+        unsafe { dynarmic_load_context(self.jit, context.raw()) }
+    }
+
+    // Loads `context` and leaves the previous state in it
+    pub fn swap_context(&mut self, context: &mut CpuContext) {
+        unsafe { dynarmic_swap_context(self.jit, context.raw_mut()) }
+    }
+
+    // See the savestate module for the layout
+    pub fn save_state(&mut self, w: &mut dyn Write) -> io::Result<()> {
+        savestate::write_header(w)?;
+
+        {
+            let context = self.context();
+            savestate::write_words(w, &*context.regs())?;
+            savestate::write_words(w, &*context.extregs())?;
+            savestate::write_words(w, &[context.cpsr().0, context.fpscr().0])?;
+        }
+
+        let handlers = unsafe { &(*self.context).handlers };
+        savestate::write_blob(w, |w| handlers.memory().save_state(w))?;
+        savestate::write_blob(w, |w| handlers.save_state(w))?;
+
+        for cp in &self.coprocessors {
+            savestate::write_blob(w, |w| match cp {
+                Some(cp) => unsafe { cp.save(w) },
+                None => Ok(()),
+            })?;
+        }
+        Ok(())
+    }
+
+    // Needs Handlers::memory_mut. Translated code is thrown away since memory changed underneath it.
+    pub fn load_state(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        savestate::read_header(r)?;
+
+        let mut regs = [0u32; 16];
+        let mut extregs = [0u32; 64];
+        let mut control = [0u32; 2];
+        savestate::read_words(r, &mut regs)?;
+        savestate::read_words(r, &mut extregs)?;
+        savestate::read_words(r, &mut control)?;
+
+        let memory = savestate::read_blob(r)?;
+        let handler_state = savestate::read_blob(r)?;
+        let mut cp_state = Vec::with_capacity(16);
+        for _ in 0..16 {
+            cp_state.push(savestate::read_blob(r)?);
+        }
+
+        let handlers = unsafe { &mut (*self.context).handlers };
+        handlers.memory_mut()
+            .ok_or_else(|| io::Error::other("Handlers don't allow replacing memory"))?
+            .load_state(&mut &memory[..])?;
+        handlers.load_state(&mut &handler_state[..])?;
+
+        for (cp, state) in self.coprocessors.iter().zip(cp_state.iter()) {
+            if let Some(cp) = cp {
+                unsafe { cp.load(&mut &state[..])? };
+            }
+        }
+
+        {
+            let context = self.context();
+            *context.regs_mut() = regs;
+            *context.extregs_mut() = extregs;
+            context.set_cpsr(Cpsr(control[0]));
+            context.set_fpscr(Fpscr(control[1]));
+        }
+
+        self.clear_cache();
+        Ok(())
     }
 
     pub fn context(&mut self) -> JitContext {
@@ -182,10 +942,21 @@ impl<H: Handlers> Executor<H> {
             jit: RefCell::new(self.jit),
         }
     }
+
+    // Coprocessors may hold on to parts of the handlers, so only shared access is handed out
+    pub fn handlers(&self) -> &H {
+        unsafe { &(*self.context).handlers }
+    }
+
+    // Through Handlers::memory_mut, e.g. for MemoryImpl::add_watchpoint between runs
+    pub fn memory_mut(&mut self) -> Option<&mut H::Memory> {
+        unsafe { (*self.context).handlers.memory_mut() }
+    }
 }
 
 impl<H: Handlers> Drop for Executor<H> {
     fn drop(&mut self) {
+        self.halt.detach();
         unsafe { dynarmic_delete(self.jit) }
         unsafe { Box::from_raw(self.context); }
     }
@@ -196,6 +967,7 @@ mod tests {
     use std::rc::Rc;
     use std::cell::Cell;
     use super::*;
+    use crate::testing::*;
     #[test]
     fn it_works() {
         struct TestHandlers {
@@ -234,10 +1006,10 @@ mod tests {
         let mut mem = memory::MemoryImpl::new();
 
         mem.map_memory(0x00000000, 1, true);
-        mem.write(0, 0x0088u16);
-        mem.write(2, 0xE7FEu16);
-        mem.write(4, 0xEE1D0F50u32); // mrc p15, 0, r0, c13, c0, 2
-        mem.write(8, 0xEAFFFFFEu32); // b 0
+        mem.write(0, 0x0088u16).unwrap();
+        mem.write(2, 0xE7FEu16).unwrap();
+        mem.write(4, 0xEE1D0F50u32).unwrap(); // mrc p15, 0, r0, c13, c0, 2
+        mem.write(8, 0xEAFFFFFEu32).unwrap(); // b 0
 
         let handlers = TestHandlers {
             memory: Rc::new(mem)
@@ -248,7 +1020,9 @@ mod tests {
         {
             let context = executor.context();
 
-            context.set_cpsr(0x30); // Thumb mode
+            let mut cpsr = Cpsr::new(ProcessorMode::User);
+            cpsr.set_thumb(true);
+            context.set_cpsr(cpsr);
 
             let mut regs = context.regs_mut();
             regs[0] = 1;
@@ -256,7 +1030,7 @@ mod tests {
             regs[15] = 0; // PC = 0
         }
 
-        executor.run();
+        executor.run_for(1);
 
         {
             let context = executor.context();
@@ -268,14 +1042,14 @@ mod tests {
         {
             let context = executor.context();
 
-            context.set_cpsr(0x10); // ARM mode
+            context.set_cpsr(Cpsr::new(ProcessorMode::User));
 
             let mut regs = context.regs_mut();
             regs[0] = 0;
             regs[15] = 4; // PC = 4
         }
 
-        executor.run();
+        executor.run_for(1);
 
         {
             let context = executor.context();
@@ -284,4 +1058,687 @@ mod tests {
             assert_eq!(regs[0], 0xAFFFA);
         }
     }
+
+    // For tests that only need memory
+    #[test]
+    fn exception_reaches_handler() {
+        struct RecordingHandlers {
+            memory: memory::MemoryImpl,
+            raised: Rc<RefCell<Vec<(u32, Exception)>>>,
+        }
+
+        impl Handlers for RecordingHandlers {
+            type Memory = memory::MemoryImpl;
+
+            fn memory(&self) -> &Self::Memory {
+                &self.memory
+            }
+
+            fn handle_exception(&mut self, context: JitContext, pc: u32, exception: Exception) {
+                self.raised.borrow_mut().push((pc, exception));
+                context.halt();
+            }
+        }
+
+        let raised = Rc::new(RefCell::new(vec![]));
+
+        let mut executor = Executor::new(RecordingHandlers {
+            memory: memory_with(true, &[
+                (0, 0xE3A00001), // mov r0, #1
+                (4, 0xE7F000F0), // udf #0
+                (8, 0xEAFFFFFE), // b 8
+            ]),
+            raised: raised.clone(),
+        });
+        reset(&mut executor);
+
+        assert!(matches!(executor.run(), HaltReason::Halted));
+
+        assert_eq!(executor.context().regs()[0], 1);
+        assert_eq!(&*raised.borrow(), &[(4, Exception::UndefinedInstruction)]);
+    }
+
+    #[test]
+    fn unmapped_read_faults() {
+        let mut executor = executor_with(&[
+            (0, 0xE5910000), // ldr r0, [r1]
+            (4, 0xEAFFFFFE), // b 4
+        ]);
+        executor.context().regs_mut()[1] = 0x10000000;
+
+        match executor.run() {
+            HaltReason::DataAbort(abort) => assert_eq!(abort, DataAbort {
+                pc: 0,
+                fault: MemoryFault {
+                    addr: 0x10000000,
+                    size: 4,
+                    kind: memory::AccessKind::Read,
+                },
+            }),
+            reason => panic!("Unexpected halt: {:?}", reason),
+        }
+    }
+
+    #[test]
+    fn halt_with_payload() {
+        struct SvcHandlers {
+            memory: memory::MemoryImpl,
+        }
+
+        impl Handlers for SvcHandlers {
+            type Memory = memory::MemoryImpl;
+
+            fn memory(&self) -> &Self::Memory {
+                &self.memory
+            }
+
+            fn handle_svc(&mut self, context: JitContext, swi: u32) {
+                let code = context.regs()[0];
+                context.halt_with((swi, code));
+            }
+        }
+
+        let mut executor = Executor::new(SvcHandlers {
+            memory: memory_with(true, &[
+                (0, 0xE3A00003), // mov r0, #3
+                (4, 0xEF000001), // svc #1
+                (8, 0xEAFFFFFE), // b 8
+            ]),
+        });
+        reset(&mut executor);
+
+        match executor.run() {
+            HaltReason::User(payload) => assert_eq!(payload.downcast_ref::<(u32, u32)>(), Some(&(1, 3))),
+            reason => panic!("Unexpected halt: {:?}", reason),
+        }
+    }
+
+    #[test]
+    fn handler_panics_are_resumed() {
+        struct PanickingHandlers {
+            memory: memory::MemoryImpl,
+        }
+
+        impl Handlers for PanickingHandlers {
+            type Memory = memory::MemoryImpl;
+
+            fn memory(&self) -> &Self::Memory {
+                &self.memory
+            }
+
+            fn handle_svc(&mut self, _context: JitContext, swi: u32) {
+                panic!("svc {}", swi);
+            }
+        }
+
+        let mut executor = Executor::new(PanickingHandlers {
+            memory: memory_with(true, &[
+                (0, 0xEF000001), // svc #1
+                (4, 0xEAFFFFFE), // b 4
+            ]),
+        });
+        reset(&mut executor);
+
+        let payload = panic::catch_unwind(panic::AssertUnwindSafe(|| executor.run())).unwrap_err();
+        assert_eq!(payload.downcast_ref::<String>().map(String::as_str), Some("svc 1"));
+
+        // Still usable afterwards
+        assert!(matches!(executor.run_for(10).reason, HaltReason::TicksExhausted));
+    }
+
+    #[test]
+    fn halt_handle_stops_other_thread() {
+        use std::sync::mpsc::{self, Sender};
+
+        struct SignalHandlers {
+            memory: memory::MemoryImpl,
+            running: Option<Sender<()>>,
+        }
+
+        impl Handlers for SignalHandlers {
+            type Memory = memory::MemoryImpl;
+
+            fn memory(&self) -> &Self::Memory {
+                &self.memory
+            }
+
+            fn handle_svc(&mut self, _context: JitContext, _swi: u32) {
+                if let Some(running) = self.running.take() {
+                    running.send(()).unwrap();
+                }
+            }
+        }
+
+        fn assert_send<T: Send>(_: &T) {}
+
+        let (running, started) = mpsc::channel();
+        let mut executor = Executor::new(SignalHandlers {
+            memory: memory_with(true, &[
+                (0, 0xEF000000), // svc #0
+                (4, 0xEAFFFFFD), // b 0
+            ]),
+            running: Some(running),
+        });
+        reset(&mut executor);
+        assert_send(&executor);
+
+        // Requested before the run starts
+        let halt = executor.halt_handle();
+        halt.halt();
+        assert!(matches!(executor.run(), HaltReason::External));
+
+        let worker = std::thread::spawn(move || {
+            let reason = executor.run();
+            (reason, executor.ticks_executed())
+        });
+        started.recv().unwrap();
+        halt.halt();
+        let (reason, ticks) = worker.join().unwrap();
+        assert!(matches!(reason, HaltReason::External));
+        assert!(ticks > 0);
+    }
+
+    #[test]
+    fn interrupts_are_delivered() {
+        struct InterruptHandlers {
+            memory: memory::MemoryImpl,
+            banked: BankedRegisters,
+            // Mode, LR and SP in the IRQ handler
+            seen: Option<(Option<ProcessorMode>, u32, u32)>,
+        }
+
+        impl Handlers for InterruptHandlers {
+            type Memory = memory::MemoryImpl;
+
+            fn memory(&self) -> &Self::Memory {
+                &self.memory
+            }
+
+            fn handle_interrupt(&mut self, context: JitContext, interrupt: Interrupt) -> bool {
+                self.banked.take_interrupt(&context, interrupt)
+            }
+
+            // Stands in for subs pc, lr, #4
+            fn handle_svc(&mut self, context: JitContext, _swi: u32) {
+                self.seen = Some((context.cpsr().mode(), context.lr(), context.sp()));
+                self.banked.return_from_exception(&context, context.lr() - 4);
+                context.halt();
+            }
+        }
+
+        let mut executor = Executor::new(InterruptHandlers {
+            memory: memory_with(true, &[
+                (0x00, 0xEAFFFFFE), // b 0
+                (0x18, 0xE3A0D902), // mov sp, #0x8000
+                (0x1C, 0xEF000000), // svc #0
+            ]),
+            banked: BankedRegisters::new(0),
+            seen: None,
+        });
+
+        {
+            let context = executor.context();
+            let mut cpsr = Cpsr::new(ProcessorMode::User);
+            cpsr.set_irq_masked(true);
+            context.set_cpsr(cpsr);
+            context.set_sp(0x1000);
+            context.set_pc(0);
+        }
+
+        // Masked, so it stays pending
+        executor.raise_irq();
+        assert!(matches!(executor.run_for(100).reason, HaltReason::TicksExhausted));
+        assert_eq!(executor.context().pc(), 0);
+        assert!(executor.halt_handle().is_pending(Interrupt::Irq));
+
+        let mut cpsr = executor.context().cpsr();
+        cpsr.set_irq_masked(false);
+        executor.context().set_cpsr(cpsr);
+        assert!(matches!(executor.run(), HaltReason::Halted));
+        assert!(!executor.halt_handle().is_pending(Interrupt::Irq));
+
+        assert_eq!(executor.handlers().seen, Some((Some(ProcessorMode::Irq), 4, 0x8000)));
+        assert_eq!(executor.handlers().banked.sp(ProcessorMode::Irq), 0x8000);
+
+        let context = executor.context();
+        assert_eq!(context.pc(), 0);
+        assert_eq!(context.sp(), 0x1000);
+        assert_eq!(context.cpsr().mode(), Some(ProcessorMode::User));
+        assert!(!context.cpsr().irq_masked());
+    }
+
+    #[test]
+    fn declined_interrupts_stay_pending() {
+        struct DecliningHandlers {
+            memory: memory::MemoryImpl,
+            offered: u32,
+        }
+
+        impl Handlers for DecliningHandlers {
+            type Memory = memory::MemoryImpl;
+
+            fn memory(&self) -> &Self::Memory {
+                &self.memory
+            }
+
+            fn handle_interrupt(&mut self, _context: JitContext, _interrupt: Interrupt) -> bool {
+                self.offered += 1;
+                false
+            }
+        }
+
+        let mut executor = Executor::new(DecliningHandlers {
+            memory: memory_with(true, &[
+                (0, 0xEF000000), // svc #0
+                (4, 0xEAFFFFFD), // b 0
+            ]),
+            offered: 0,
+        });
+        reset(&mut executor);
+
+        // Not offered again after every SVC
+        executor.raise_irq();
+        assert!(matches!(executor.run_for(100).reason, HaltReason::TicksExhausted));
+        assert_eq!(executor.handlers().offered, 1);
+        assert!(executor.halt_handle().is_pending(Interrupt::Irq));
+
+        executor.raise_irq();
+        assert!(matches!(executor.run_for(100).reason, HaltReason::TicksExhausted));
+        assert_eq!(executor.handlers().offered, 2);
+    }
+
+    #[test]
+    fn run_for_and_step() {
+        let mut executor = executor_with(&[
+            (0, 0xE3A00000), // mov r0, #0
+            (4, 0xE2800001), // add r0, r0, #1
+            (8, 0xEAFFFFFD), // b 4
+        ]);
+
+        assert!(matches!(executor.step(), HaltReason::Stepped));
+        assert!(matches!(executor.step(), HaltReason::Stepped));
+        assert_eq!(executor.context().regs()[0], 1);
+        assert_eq!(executor.context().regs()[15], 8);
+        assert_eq!(executor.ticks_executed(), 2);
+
+        let result = executor.run_for(100);
+        assert!(matches!(result.reason, HaltReason::TicksExhausted));
+        assert!(result.ticks_executed >= 100);
+        assert_eq!(executor.ticks_executed(), 2 + result.ticks_executed);
+        assert!(executor.context().regs()[0] >= 50);
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut executor = executor_with(&[
+            (0, 0xE3A00000), // mov r0, #0
+            (4, 0xE2800001), // add r0, r0, #1
+            (8, 0xEAFFFFFD), // b 4
+            (0x100, 0x30012000), // movs r0, #0; adds r0, #1
+            (0x104, 0x0000E7FD), // b 0x102
+        ]);
+
+        executor.add_breakpoint(8);
+        for i in 1..3 {
+            assert!(matches!(executor.run(), HaltReason::Breakpoint(8)));
+            assert_eq!(executor.context().regs()[0], i);
+            assert_eq!(executor.context().regs()[15], 8);
+        }
+
+        // Stepping executes the instruction under the breakpoint
+        assert!(matches!(executor.step(), HaltReason::Stepped));
+        assert_eq!(executor.context().regs()[15], 4);
+
+        assert!(executor.remove_breakpoint(8));
+        assert!(matches!(executor.run_for(10).reason, HaltReason::TicksExhausted));
+
+        {
+            let context = executor.context();
+            let mut cpsr = Cpsr::new(ProcessorMode::User);
+            cpsr.set_thumb(true);
+            context.set_cpsr(cpsr);
+            context.regs_mut()[15] = 0x100;
+        }
+
+        // Both halves of a fetched word
+        executor.add_breakpoint(0x104);
+        assert!(matches!(executor.run(), HaltReason::Breakpoint(0x104)));
+        assert_eq!(executor.context().regs()[0], 1);
+        assert!(executor.remove_breakpoint(0x104));
+
+        executor.add_breakpoint(0x102);
+        for i in 1..3 {
+            assert!(matches!(executor.run(), HaltReason::Breakpoint(0x102)));
+            assert_eq!(executor.context().regs()[0], i);
+            assert!(executor.context().cpsr().is_thumb());
+        }
+    }
+
+    #[test]
+    fn watchpoints_halt() {
+        let mut mem = memory_with(false, &[
+            (0x00, 0xE3A01B02), // mov r1, #0x800
+            (0x04, 0xE3A00005), // mov r0, #5
+            (0x08, 0xE5810004), // str r0, [r1, #4]
+            (0x0C, 0xE5912004), // ldr r2, [r1, #4]
+            (0x10, 0xEAFFFFFE), // b .
+        ]);
+        mem.add_watchpoint(0x804, 4, memory::WatchKind::Write);
+
+        let mut executor = Executor::new(TestHandlers::new(mem));
+        reset(&mut executor);
+
+        match executor.run() {
+            HaltReason::Watchpoint(hit) => assert_eq!(hit, memory::WatchHit {
+                addr: 0x804,
+                size: 4,
+                kind: memory::AccessKind::Write,
+                value: 5,
+                pc: 0,
+            }),
+            reason => panic!("Unexpected halt {:?}", reason),
+        }
+        // The rest of the block still ran
+        assert_eq!(executor.context().regs()[2], 5);
+
+        assert!(executor.memory_mut().unwrap().remove_watchpoint(0x804, 4, memory::WatchKind::Write));
+        executor.context().regs_mut()[15] = 0;
+        assert!(matches!(executor.run_for(10).reason, HaltReason::TicksExhausted));
+    }
+
+    #[test]
+    fn named_registers() {
+        let mut executor = executor_with(&[
+            (0, 0xEE302B01), // vadd.f64 d2, d0, d1
+            (4, 0xEAFFFFFE), // b .
+        ]);
+
+        {
+            let context = executor.context();
+
+            context.set_pc(0x101);
+            assert!(context.cpsr().is_thumb());
+            assert_eq!(context.pc(), 0x100);
+            context.set_pc(0);
+            assert!(!context.cpsr().is_thumb());
+
+            context.set_sp(0x8000);
+            assert_eq!(context.regs()[13], 0x8000);
+            context.regs_mut()[Reg::Lr] = 4;
+            assert_eq!(context.lr(), 4);
+
+            context.set_d(0, 1.5);
+            context.set_d(1, 2.0);
+            assert_eq!(context.s(1).to_bits(), (1.5f64.to_bits() >> 32) as u32);
+            assert_eq!(context.q(0), 1.5f64.to_bits() as u128 | (2.0f64.to_bits() as u128) << 64);
+            context.set_s(4, 1.0);
+            assert_eq!(context.extregs()[4], 1.0f32.to_bits());
+        }
+
+        assert!(matches!(executor.step(), HaltReason::Stepped));
+
+        let context = executor.context();
+        assert_eq!(context.d(2), 3.5);
+        context.set_q(1, !0);
+        assert_eq!(context.extregs()[4..8], [!0u32; 4]);
+    }
+
+    #[test]
+    fn hint_instructions_are_hooked() {
+        struct WfiHandlers {
+            memory: memory::MemoryImpl,
+        }
+
+        impl Handlers for WfiHandlers {
+            type Memory = memory::MemoryImpl;
+
+            fn memory(&self) -> &Self::Memory {
+                &self.memory
+            }
+
+            fn handle_exception(&mut self, context: JitContext, pc: u32, exception: Exception) {
+                if exception == Exception::WaitForInterrupt {
+                    context.halt_with(pc);
+                }
+            }
+        }
+
+        let memory = memory_with(true, &[
+            (0, 0xE320F004), // sev
+            (4, 0xE320F003), // wfi
+            (8, 0xEAFFFFFE), // b 8
+        ]);
+
+        let mut executor = ExecutorBuilder::new(WfiHandlers { memory })
+            .hook_hint_instructions(true)
+            .optimizations(Optimizations::ALL_SAFE)
+            .enable_fast_dispatch(false)
+            .build();
+        reset(&mut executor);
+
+        match executor.run() {
+            HaltReason::User(payload) => assert_eq!(payload.downcast_ref::<u32>(), Some(&4)),
+            reason => panic!("Unexpected halt: {:?}", reason),
+        }
+        assert_eq!(executor.context().regs()[15], 8);
+    }
+
+    #[test]
+    fn exclusive_monitor_by_default() {
+        let mut mem = memory_with(true, &[
+            (0x0, 0xE1910F9F), // ldrex r0, [r1]
+            (0x4, 0xE1812F93), // strex r2, r3, [r1]
+            (0x8, 0xE1814F93), // strex r4, r3, [r1]
+            (0xC, 0xEAFFFFFE), // b .
+        ]);
+        mem.map_memory(0x00001000, 1, false);
+
+        let mut executor = Executor::new(TestHandlers::new(mem));
+        reset(&mut executor);
+        {
+            let context = executor.context();
+            let mut regs = context.regs_mut();
+            regs[1] = 0x1000;
+            regs[3] = 7;
+        }
+
+        for _ in 0..3 {
+            assert!(matches!(executor.step(), HaltReason::Stepped));
+        }
+        // The second STREX has no reservation left
+        assert_eq!(executor.context().regs()[2], 0);
+        assert_eq!(executor.context().regs()[4], 1);
+        assert_eq!(executor.handlers().memory.read::<u32>(0x1000), Ok(7));
+    }
+
+    #[test]
+    fn exclusive_monitor_is_shared() {
+        struct SharedHandlers {
+            memory: Rc<memory::MemoryImpl>,
+        }
+
+        impl Handlers for SharedHandlers {
+            type Memory = memory::MemoryImpl;
+
+            fn memory(&self) -> &Self::Memory {
+                &self.memory
+            }
+        }
+
+        let mut mem = memory_with(true, &[
+            (0, 0xE1910F9F), // ldrex r0, [r1]
+            (4, 0xE1812F93), // strex r2, r3, [r1]
+            (8, 0xEAFFFFFE), // b 8
+        ]);
+        mem.map_memory(0x00001000, 1, false);
+
+        let mem = Rc::new(mem);
+        let monitor = Arc::new(ExclusiveMonitor::new(2));
+
+        let mut cores: Vec<_> = (0..2).map(|id| {
+            let mut executor = ExecutorBuilder::new(SharedHandlers { memory: mem.clone() })
+                .exclusive_monitor(monitor.clone(), id)
+                .build();
+            reset(&mut executor);
+            {
+                let context = executor.context();
+                let mut regs = context.regs_mut();
+                regs[1] = 0x1000;
+                regs[3] = id as u32 + 1;
+            }
+            executor
+        }).collect();
+
+        // Core 1 takes the reservation away from core 0
+        cores[0].step();
+        cores[1].step();
+        cores[1].step();
+        cores[0].step();
+
+        assert_eq!(cores[1].context().regs()[2], 0);
+        assert_eq!(cores[0].context().regs()[2], 1);
+        assert_eq!(mem.read::<u32>(0x1000), Ok(2));
+    }
+
+    #[test]
+    fn code_writes_invalidate_translations() {
+        struct SharedHandlers {
+            memory: Rc<memory::MemoryImpl>,
+        }
+
+        impl Handlers for SharedHandlers {
+            type Memory = memory::MemoryImpl;
+
+            fn memory(&self) -> &Self::Memory {
+                &self.memory
+            }
+
+            fn handle_svc(&mut self, context: JitContext, _swi: u32) {
+                context.halt();
+            }
+        }
+
+        let mut mem = memory_with(false, &[
+            (0, 0xE3A00001), // mov r0, #1
+            (4, 0xEF000000), // svc #0
+        ]);
+        mem.set_executable(0x00000000, 1, true);
+        mem.set_invalidate_on_write(true);
+
+        let mem = Rc::new(mem);
+        let mut executor = Executor::new(SharedHandlers { memory: mem.clone() });
+
+        reset(&mut executor);
+        assert!(matches!(executor.run(), HaltReason::Halted));
+        assert_eq!(executor.context().regs()[0], 1);
+
+        mem.write(0, 0xE3A00002u32).unwrap(); // mov r0, #2
+        executor.context().regs_mut()[15] = 0;
+        assert!(matches!(executor.run(), HaltReason::Halted));
+        assert_eq!(executor.context().regs()[0], 2);
+
+        executor.clear_cache();
+        executor.context().regs_mut()[15] = 0;
+        assert!(matches!(executor.run(), HaltReason::Halted));
+        assert_eq!(executor.context().regs()[0], 2);
+    }
+
+    #[test]
+    fn context_switching() {
+        let mut executor = executor_with(&[
+            (0, 0xE2800001), // add r0, r0, #1
+            (4, 0xEAFFFFFD), // b 0
+        ]);
+
+        let mut fpscr = Fpscr::default();
+        fpscr.set_rounding_mode(RoundingMode::TowardsZero);
+        fpscr.set_flush_to_zero(true);
+
+        let mut thread = CpuContext::new();
+        thread.set_cpsr(Cpsr::new(ProcessorMode::User));
+        thread.set_fpscr(fpscr);
+        thread.regs_mut()[0] = 100;
+        thread.regs_mut()[15] = 0;
+
+        executor.context().regs_mut()[0] = 0;
+
+        executor.step();
+        executor.swap_context(&mut thread);
+        assert_eq!(thread.regs()[0], 1);
+        assert_eq!(executor.context().regs()[0], 100);
+        assert_eq!(executor.context().fpscr(), fpscr);
+
+        executor.step();
+        let mut saved = CpuContext::new();
+        executor.save_context(&mut saved);
+        assert_eq!(saved.regs()[0], 101);
+        assert_eq!(saved.regs()[15], 4);
+
+        executor.load_context(&thread);
+        assert_eq!(executor.context().regs()[0], 1);
+        assert_eq!(saved.clone().regs()[0], 101);
+    }
+
+    #[test]
+    fn savestate_round_trip() {
+        struct CountingHandlers {
+            memory: memory::MemoryImpl,
+            svc_count: u32,
+        }
+
+        impl Handlers for CountingHandlers {
+            type Memory = memory::MemoryImpl;
+
+            fn memory(&self) -> &Self::Memory {
+                &self.memory
+            }
+
+            fn memory_mut(&mut self) -> Option<&mut Self::Memory> {
+                Some(&mut self.memory)
+            }
+
+            fn handle_svc(&mut self, context: JitContext, _swi: u32) {
+                self.svc_count += 1;
+                context.halt();
+            }
+
+            fn save_state(&self, w: &mut dyn Write) -> io::Result<()> {
+                w.write_all(&self.svc_count.to_le_bytes())
+            }
+
+            fn load_state(&mut self, r: &mut dyn Read) -> io::Result<()> {
+                let mut bytes = [0u8; 4];
+                r.read_exact(&mut bytes)?;
+                self.svc_count = u32::from_le_bytes(bytes);
+                Ok(())
+            }
+        }
+
+        let memory = memory_with(false, &[
+            (0, 0xE2800001), // add r0, r0, #1
+            (4, 0xE5810000), // str r0, [r1]
+            (8, 0xEF000000), // svc #0
+            (12, 0xEAFFFFFB), // b 0
+        ]);
+
+        let mut executor = Executor::new(CountingHandlers { memory, svc_count: 0 });
+        reset(&mut executor);
+        executor.context().regs_mut()[1] = 0x100;
+
+        executor.run();
+        let mut state = Vec::new();
+        executor.save_state(&mut state).unwrap();
+
+        executor.run();
+        executor.run();
+        assert_eq!(executor.context().regs()[0], 3);
+
+        executor.load_state(&mut &state[..]).unwrap();
+        assert_eq!(executor.context().regs()[0], 1);
+        assert_eq!(executor.context().regs()[15], 12);
+        executor.run();
+        assert_eq!(executor.context().regs()[0], 2);
+        assert_eq!(executor.handlers().svc_count, 2);
+        assert_eq!(executor.handlers().memory.read::<u32>(0x100), Ok(2));
+    }
 }
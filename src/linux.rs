@@ -0,0 +1,694 @@
+// ARM EABI Linux user mode personality: the syscall number is in r7, arguments in r0-r6 and the
+// result goes back in r0, negative errno on failure. Only what statically linked programs commonly
+// need is implemented, everything else fails with ENOSYS.
+
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{JitContext, Reg};
+use crate::elf::ElfImage;
+use crate::host::{HostFile, io_errno, sandboxed_path};
+use crate::memory::{Memory, MemoryImpl, PAGE_BITS, PAGE_SIZE};
+
+pub const STACK_TOP: u32 = 0xC0000000;
+pub const STACK_SIZE: u32 = 8 << 20;
+pub const MMAP_BASE: u32 = 0x40000000;
+
+pub mod errno {
+    pub const ENOENT: i32 = 2;
+    pub const EIO: i32 = 5;
+    pub const EBADF: i32 = 9;
+    pub const ENOMEM: i32 = 12;
+    pub const EACCES: i32 = 13;
+    pub const EFAULT: i32 = 14;
+    pub const EEXIST: i32 = 17;
+    pub const EINVAL: i32 = 22;
+    pub const ENOTTY: i32 = 25;
+    pub const ENAMETOOLONG: i32 = 36;
+    pub const ENOSYS: i32 = 38;
+}
+
+pub mod nr {
+    pub const EXIT: u32 = 1;
+    pub const READ: u32 = 3;
+    pub const WRITE: u32 = 4;
+    pub const CLOSE: u32 = 6;
+    pub const GETPID: u32 = 20;
+    pub const BRK: u32 = 45;
+    pub const IOCTL: u32 = 54;
+    pub const MUNMAP: u32 = 91;
+    pub const UNAME: u32 = 122;
+    pub const MPROTECT: u32 = 125;
+    pub const WRITEV: u32 = 146;
+    pub const RT_SIGACTION: u32 = 174;
+    pub const RT_SIGPROCMASK: u32 = 175;
+    pub const MMAP2: u32 = 192;
+    pub const GETTID: u32 = 224;
+    pub const EXIT_GROUP: u32 = 248;
+    pub const SET_TID_ADDRESS: u32 = 256;
+    pub const CLOCK_GETTIME: u32 = 263;
+    pub const OPENAT: u32 = 322;
+    pub const SET_TLS: u32 = 0xF0005; // ARM private
+}
+
+const PROT_WRITE: u32 = 2;
+const PROT_EXEC: u32 = 4;
+const MAP_FIXED: u32 = 0x10;
+const MAP_ANONYMOUS: u32 = 0x20;
+
+const O_ACCMODE: u32 = 3;
+const O_CREAT: u32 = 0o100;
+const O_EXCL: u32 = 0o200;
+const O_TRUNC: u32 = 0o1000;
+const O_APPEND: u32 = 0o2000;
+const AT_FDCWD: i32 = -100;
+const UIO_MAXIOV: u32 = 1024;
+// Largest host buffer a single read or write goes through
+const MAX_CHUNK: u32 = 1 << 20;
+
+// Payload of the halt after exit or exit_group, see JitContext::halt_with
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Exit(pub i32);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SyscallResult {
+    // Raw r0, negative errnos included
+    Return(u32),
+    Exit(i32),
+}
+
+type SysResult = Result<u32, i32>;
+
+fn pages_for(len: u32) -> u32 {
+    ((len as u64 + PAGE_SIZE as u64 - 1) >> PAGE_BITS) as u32
+}
+
+// In u64, addresses in the last page round up to 1 << 32
+fn page_align(addr: u32) -> u64 {
+    (pages_for(addr) as u64) << PAGE_BITS
+}
+
+// Length of the pages covering [addr, addr + len), if they fit in the address space
+fn mapping_len(addr: u32, len: u32) -> Option<u32> {
+    let mapped = (pages_for(len) as u64) << PAGE_BITS;
+    if addr as u64 + mapped > 1 << 32 || mapped > u32::MAX as u64 {
+        return None;
+    }
+    Some(mapped as u32)
+}
+
+pub struct Linux {
+    root: PathBuf, // Guest paths resolve below this
+    files: BTreeMap<i32, HostFile>,
+    brk_start: u32,
+    brk: u32,
+    tls: Cell<u32>,
+    started: Instant,
+}
+
+impl Linux {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        let mut files = BTreeMap::new();
+        files.insert(0, HostFile::Reader(Box::new(io::stdin())));
+        files.insert(1, HostFile::Writer(Box::new(io::stdout())));
+        files.insert(2, HostFile::Writer(Box::new(io::stderr())));
+
+        Linux {
+            root: root.into(),
+            files,
+            brk_start: 0,
+            brk: 0,
+            tls: Cell::new(0),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn set_stdin<R: Read + 'static>(&mut self, stdin: R) {
+        self.files.insert(0, HostFile::Reader(Box::new(stdin)));
+    }
+
+    pub fn set_stdout<W: Write + 'static>(&mut self, stdout: W) {
+        self.files.insert(1, HostFile::Writer(Box::new(stdout)));
+    }
+
+    pub fn set_stderr<W: Write + 'static>(&mut self, stderr: W) {
+        self.files.insert(2, HostFile::Writer(Box::new(stderr)));
+    }
+
+    // Initial program break, normally right after the image
+    pub fn set_brk(&mut self, brk: u32) {
+        self.brk_start = brk;
+        self.brk = brk;
+    }
+
+    pub fn brk(&self) -> u32 {
+        self.brk
+    }
+
+    // Value set by set_tls. Guests read it back through CP15 TPIDRURO (c13, c0, 3), so a CP15
+    // coprocessor should expose this cell.
+    pub fn tls(&self) -> &Cell<u32> {
+        &self.tls
+    }
+
+    // Maps the stack, sets the program break after the image and points the executor at the entry
+    // point with argc, argv, envp and the auxiliary vector on the stack.
+    pub fn start(&mut self, memory: &mut MemoryImpl, context: &JitContext, image: &ElfImage, args: &[&str], env: &[&str]) {
+        let image_end = image.segments.iter()
+            .map(|segment| segment.addr.saturating_add(segment.size))
+            .max()
+            .unwrap_or(0);
+        self.set_brk(page_align(image_end).min(u32::MAX as u64) as u32);
+
+        let sp = Linux::build_stack(memory, image, args, env);
+        image.set_entry(context);
+        context.regs_mut()[Reg::R0] = 0; // No rtld_fini
+        context.set_sp(sp);
+    }
+
+    // Maps STACK_SIZE bytes below STACK_TOP and lays out the initial process stack. Returns SP.
+    pub fn build_stack(memory: &mut MemoryImpl, image: &ElfImage, args: &[&str], env: &[&str]) -> u32 {
+        const AT_NULL: u32 = 0;
+        const AT_PHDR: u32 = 3;
+        const AT_PHENT: u32 = 4;
+        const AT_PHNUM: u32 = 5;
+        const AT_PAGESZ: u32 = 6;
+        const AT_ENTRY: u32 = 9;
+        const AT_UID: u32 = 11;
+        const AT_EUID: u32 = 12;
+        const AT_GID: u32 = 13;
+        const AT_EGID: u32 = 14;
+        const AT_HWCAP: u32 = 16;
+        const AT_CLKTCK: u32 = 17;
+        const AT_RANDOM: u32 = 25;
+
+        // SWP, HALF, THUMB, FAST_MULT, VFP, EDSP, NEON, VFPv3, TLS, VFPv4, IDIVA, IDIVT
+        const HWCAP: u32 = 1 | 2 | 4 | 16 | 64 | 128 | 4096 | 8192 | 32768 | 65536 | 131072 | 262144;
+
+        memory.map_memory(STACK_TOP - STACK_SIZE, STACK_SIZE >> PAGE_BITS, false);
+
+        let mut sp = STACK_TOP;
+        let mut push = |bytes: &[u8]| {
+            sp -= bytes.len() as u32;
+            memory.write_bytes(sp, bytes).unwrap();
+            sp
+        };
+
+        let mut strings = |strings: &[&str]| -> Vec<u32> {
+            strings.iter().map(|s| {
+                push(&[0]);
+                push(s.as_bytes())
+            }).collect()
+        };
+        let argv = strings(args);
+        let envp = strings(env);
+        // Deterministic, runs should be reproducible
+        let random = push(b"dynarmic-random!");
+
+        let mut auxv = vec![
+            (AT_PHENT, 32),
+            (AT_PHNUM, image.phnum),
+            (AT_PAGESZ, PAGE_SIZE as u32),
+            (AT_ENTRY, image.entry | image.thumb as u32),
+            (AT_UID, 0),
+            (AT_EUID, 0),
+            (AT_GID, 0),
+            (AT_EGID, 0),
+            (AT_HWCAP, HWCAP),
+            (AT_CLKTCK, 100),
+            (AT_RANDOM, random),
+        ];
+        if let Some(phdr) = image.phdr {
+            auxv.push((AT_PHDR, phdr));
+        }
+        auxv.push((AT_NULL, 0));
+
+        let mut words = vec![argv.len() as u32];
+        words.extend(&argv);
+        words.push(0);
+        words.extend(&envp);
+        words.push(0);
+        for (key, value) in auxv {
+            words.push(key);
+            words.push(value);
+        }
+
+        let sp = (sp - words.len() as u32 * 4) & !15;
+        for (i, word) in words.iter().enumerate() {
+            memory.write(sp + i as u32 * 4, *word).unwrap();
+        }
+        sp
+    }
+
+    // Call from Handlers::handle_svc. Exiting halts with an Exit payload.
+    pub fn handle_svc(&mut self, memory: &mut MemoryImpl, context: &JitContext) {
+        let (nr, args) = {
+            let regs = context.regs();
+            (regs[7], [regs[0], regs[1], regs[2], regs[3], regs[4], regs[5], regs[6]])
+        };
+
+        match self.syscall(memory, nr, args) {
+            SyscallResult::Return(value) => context.regs_mut()[0] = value,
+            SyscallResult::Exit(code) => context.halt_with(Exit(code)),
+        }
+    }
+
+    pub fn syscall(&mut self, memory: &mut MemoryImpl, nr: u32, args: [u32; 7]) -> SyscallResult {
+        let result = match nr {
+            nr::EXIT | nr::EXIT_GROUP => return SyscallResult::Exit(args[0] as i32),
+            nr::READ => self.read(memory, args[0] as i32, args[1], args[2]),
+            nr::WRITE => self.write(memory, args[0] as i32, args[1], args[2]),
+            nr::WRITEV => self.writev(memory, args[0] as i32, args[1], args[2]),
+            nr::OPENAT => self.openat(memory, args[0] as i32, args[1], args[2]),
+            nr::CLOSE => self.files.remove(&(args[0] as i32)).map(|_| 0).ok_or(errno::EBADF),
+            nr::BRK => Ok(self.set_program_break(memory, args[0])),
+            nr::MMAP2 => self.mmap(memory, args[0], args[1], args[2], args[3], args[4] as i32, args[5]),
+            nr::MUNMAP => self.munmap(memory, args[0], args[1]),
+            nr::MPROTECT => self.mprotect(memory, args[0], args[1], args[2]),
+            nr::UNAME => Linux::uname(memory, args[0]),
+            nr::CLOCK_GETTIME => self.clock_gettime(memory, args[0], args[1]),
+            nr::SET_TLS => {
+                self.tls.set(args[0]);
+                Ok(0)
+            },
+            // Single process, single thread
+            nr::GETPID | nr::GETTID | nr::SET_TID_ADDRESS => Ok(1),
+            // Signals are never delivered
+            nr::RT_SIGACTION | nr::RT_SIGPROCMASK => Ok(0),
+            nr::IOCTL => Err(errno::ENOTTY),
+            _ => Err(errno::ENOSYS),
+        };
+
+        SyscallResult::Return(result.unwrap_or_else(|errno| (-errno) as u32))
+    }
+
+    fn read(&mut self, memory: &MemoryImpl, fd: i32, buf: u32, count: u32) -> SysResult {
+        let mut bytes = vec![0u8; count.min(MAX_CHUNK) as usize];
+        let file = self.files.get_mut(&fd).ok_or(errno::EBADF)?;
+        let read = file.read(&mut bytes).map_err(|e| io_errno(&e))?;
+        memory.write_bytes(buf, &bytes[..read]).map_err(|_| errno::EFAULT)?;
+        Ok(read as u32)
+    }
+
+    fn write(&mut self, memory: &MemoryImpl, fd: i32, buf: u32, count: u32) -> SysResult {
+        let mut bytes = vec![0u8; count.min(MAX_CHUNK) as usize];
+        let mut written = 0;
+        while written < count {
+            let chunk = &mut bytes[..(count - written).min(MAX_CHUNK) as usize];
+            memory.read_bytes(buf.wrapping_add(written), chunk).map_err(|_| errno::EFAULT)?;
+            let file = self.files.get_mut(&fd).ok_or(errno::EBADF)?;
+            file.write_all(chunk).map_err(|e| io_errno(&e))?;
+            written += chunk.len() as u32;
+        }
+        Ok(count)
+    }
+
+    fn writev(&mut self, memory: &MemoryImpl, fd: i32, iov: u32, count: u32) -> SysResult {
+        if count > UIO_MAXIOV {
+            return Err(errno::EINVAL);
+        }
+        let mut total = 0u32;
+        for i in 0..count {
+            let entry = iov.checked_add(i * 8).ok_or(errno::EFAULT)?;
+            let base = memory.read::<u32>(entry).map_err(|_| errno::EFAULT)?;
+            let len = memory.read::<u32>(entry.checked_add(4).ok_or(errno::EFAULT)?).map_err(|_| errno::EFAULT)?;
+            total = total.checked_add(len).filter(|&total| total <= i32::MAX as u32).ok_or(errno::EINVAL)?;
+            self.write(memory, fd, base, len)?;
+        }
+        Ok(total)
+    }
+
+    fn read_path(memory: &MemoryImpl, mut addr: u32) -> Result<String, i32> {
+        let mut bytes = Vec::new();
+        loop {
+            let byte = memory.read::<u8>(addr).map_err(|_| errno::EFAULT)?;
+            if byte == 0 {
+                return Ok(String::from_utf8_lossy(&bytes).into_owned());
+            }
+            if bytes.len() >= 4096 {
+                return Err(errno::ENAMETOOLONG);
+            }
+            bytes.push(byte);
+            addr = addr.wrapping_add(1);
+        }
+    }
+
+    fn openat(&mut self, memory: &MemoryImpl, dirfd: i32, path: u32, flags: u32) -> SysResult {
+        let path = Linux::read_path(memory, path)?;
+        if dirfd != AT_FDCWD && !path.starts_with('/') {
+            return Err(errno::EINVAL);
+        }
+        // Relative paths are taken from the root as well, there is no working directory
+        let host_path = sandboxed_path(&self.root, &path).ok_or(errno::EACCES)?;
+
+        let file = OpenOptions::new()
+            .read(flags & O_ACCMODE != 1)
+            .write(flags & O_ACCMODE != 0)
+            .append(flags & O_APPEND != 0)
+            .truncate(flags & O_TRUNC != 0)
+            .create(flags & O_CREAT != 0)
+            .create_new(flags & O_CREAT != 0 && flags & O_EXCL != 0)
+            .open(host_path)
+            .map_err(|e| io_errno(&e))?;
+
+        let fd = (3..).find(|fd| !self.files.contains_key(fd)).unwrap();
+        self.files.insert(fd, HostFile::File(file));
+        Ok(fd as u32)
+    }
+
+    // Fails by returning the old break, like the kernel
+    fn set_program_break(&mut self, memory: &mut MemoryImpl, brk: u32) -> u32 {
+        if brk < self.brk_start {
+            return self.brk;
+        }
+
+        let old_end = page_align(self.brk);
+        let new_end = page_align(brk);
+        if new_end > old_end {
+            let stack_bottom = (STACK_TOP - STACK_SIZE) as u64;
+            if old_end < STACK_TOP as u64 && new_end > stack_bottom {
+                return self.brk;
+            }
+            if (old_end..new_end).step_by(PAGE_SIZE).any(|addr| memory.is_mapped(addr as u32)) {
+                return self.brk;
+            }
+            memory.map_memory(old_end as u32, ((new_end - old_end) >> PAGE_BITS) as u32, false);
+        } else if new_end < old_end {
+            memory.unmap_memory(new_end as u32, ((old_end - new_end) >> PAGE_BITS) as u32);
+        }
+
+        self.brk = brk;
+        brk
+    }
+
+    fn find_free(memory: &MemoryImpl, start: u32, pages: u32) -> Option<u32> {
+        let mut page = start >> PAGE_BITS;
+        let mut run = 0;
+        while (page + run) < (STACK_TOP - STACK_SIZE) >> PAGE_BITS {
+            if memory.is_mapped((page + run) << PAGE_BITS) {
+                page += run + 1;
+                run = 0;
+            } else {
+                run += 1;
+                if run == pages {
+                    return Some(page << PAGE_BITS);
+                }
+            }
+        }
+        None
+    }
+
+    // Takes mmap2's arguments as they are
+    #[allow(clippy::too_many_arguments)]
+    fn mmap(&mut self, memory: &mut MemoryImpl, addr: u32, len: u32, prot: u32, flags: u32, fd: i32, pgoff: u32) -> SysResult {
+        if len == 0 {
+            return Err(errno::EINVAL);
+        }
+        let mapped = mapping_len(0, len).ok_or(errno::ENOMEM)?;
+        let pages = mapped >> PAGE_BITS;
+
+        let addr = if flags & MAP_FIXED != 0 {
+            if addr & (PAGE_SIZE as u32 - 1) != 0 {
+                return Err(errno::EINVAL);
+            }
+            mapping_len(addr, len).ok_or(errno::ENOMEM)?;
+            addr
+        } else {
+            let hint = if addr != 0 { addr & !(PAGE_SIZE as u32 - 1) } else { MMAP_BASE };
+            Linux::find_free(memory, hint, pages)
+                .or_else(|| Linux::find_free(memory, MMAP_BASE, pages))
+                .ok_or(errno::ENOMEM)?
+        };
+
+        let mut contents = Vec::new();
+        if flags & MAP_ANONYMOUS == 0 {
+            let file = self.files.get_mut(&fd).and_then(HostFile::file).ok_or(errno::EBADF)?;
+            file.seek(SeekFrom::Start((pgoff as u64) << PAGE_BITS)).map_err(|e| io_errno(&e))?;
+            file.take(len as u64).read_to_end(&mut contents).map_err(|e| io_errno(&e))?;
+        }
+
+        memory.map_memory(addr, pages, prot & PROT_WRITE == 0);
+        memory.write_bytes(addr, &contents).unwrap();
+        memory.set_executable(addr, pages, prot & PROT_EXEC != 0);
+        // Whatever was translated from the old mapping is gone
+        memory.invalidate(addr, mapped);
+        Ok(addr)
+    }
+
+    fn munmap(&mut self, memory: &mut MemoryImpl, addr: u32, len: u32) -> SysResult {
+        if addr & (PAGE_SIZE as u32 - 1) != 0 || len == 0 {
+            return Err(errno::EINVAL);
+        }
+        let mapped = mapping_len(addr, len).ok_or(errno::EINVAL)?;
+        memory.unmap_memory(addr, mapped >> PAGE_BITS);
+        memory.invalidate(addr, mapped);
+        Ok(0)
+    }
+
+    fn mprotect(&mut self, memory: &mut MemoryImpl, addr: u32, len: u32, prot: u32) -> SysResult {
+        if addr & (PAGE_SIZE as u32 - 1) != 0 {
+            return Err(errno::EINVAL);
+        }
+        let mapped = mapping_len(addr, len).ok_or(errno::ENOMEM)?;
+        let pages = mapped >> PAGE_BITS;
+        memory.protect(addr, pages, prot & PROT_WRITE == 0);
+        memory.set_executable(addr, pages, prot & PROT_EXEC != 0);
+        Ok(0)
+    }
+
+    fn uname(memory: &MemoryImpl, buf: u32) -> SysResult {
+        let fields = ["Linux", "dynarmic", "5.4.0", "#1", "armv7l", ""];
+        for (i, field) in fields.iter().enumerate() {
+            let mut bytes = [0u8; 65];
+            bytes[..field.len()].copy_from_slice(field.as_bytes());
+            let field_addr = buf.checked_add(i as u32 * 65).ok_or(errno::EFAULT)?;
+            memory.write_bytes(field_addr, &bytes).map_err(|_| errno::EFAULT)?;
+        }
+        Ok(0)
+    }
+
+    fn clock_gettime(&self, memory: &MemoryImpl, clock: u32, tp: u32) -> SysResult {
+        const CLOCK_REALTIME: u32 = 0;
+        const CLOCK_REALTIME_COARSE: u32 = 5;
+
+        let time = match clock {
+            CLOCK_REALTIME | CLOCK_REALTIME_COARSE => SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default(),
+            0..=11 => self.started.elapsed(),
+            _ => return Err(errno::EINVAL),
+        };
+        memory.write(tp, time.as_secs() as u32).map_err(|_| errno::EFAULT)?;
+        let nsec = tp.checked_add(4).ok_or(errno::EFAULT)?;
+        memory.write(nsec, time.subsec_nanos()).map_err(|_| errno::EFAULT)?;
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Captured, TestHandlers};
+
+    fn call(linux: &mut Linux, memory: &mut MemoryImpl, nr: u32, args: &[u32]) -> i32 {
+        let mut regs = [0u32; 7];
+        regs[..args.len()].copy_from_slice(args);
+        match linux.syscall(memory, nr, regs) {
+            SyscallResult::Return(value) => value as i32,
+            SyscallResult::Exit(code) => panic!("Unexpected exit {}", code),
+        }
+    }
+
+    #[test]
+    fn brk_grows_and_shrinks() {
+        let mut memory = MemoryImpl::new();
+        let mut linux = Linux::new(".");
+        linux.set_brk(0x10000);
+
+        assert_eq!(call(&mut linux, &mut memory, nr::BRK, &[0]), 0x10000);
+        assert_eq!(call(&mut linux, &mut memory, nr::BRK, &[0x12345]), 0x12345);
+        assert!(memory.write(0x12FFF, 1u8).is_ok());
+        assert_eq!(call(&mut linux, &mut memory, nr::BRK, &[0x11000]), 0x11000);
+        assert!(memory.write(0x11000, 1u8).is_err());
+        assert_eq!(call(&mut linux, &mut memory, nr::BRK, &[0x8000]), 0x11000);
+
+        // Ends past the stack or at the top of the address space leave the break alone
+        assert_eq!(call(&mut linux, &mut memory, nr::BRK, &[STACK_TOP - STACK_SIZE + 1]), 0x11000);
+        assert_eq!(call(&mut linux, &mut memory, nr::BRK, &[0xFFFFF001]), 0x11000);
+        assert!(memory.write(0x10000, 1u8).is_ok());
+    }
+
+    #[test]
+    fn anonymous_mmap() {
+        let mut memory = MemoryImpl::new();
+        let mut linux = Linux::new(".");
+
+        let first = call(&mut linux, &mut memory, nr::MMAP2, &[0, 0x1800, 3, 0x22, -1i32 as u32, 0]) as u32;
+        let second = call(&mut linux, &mut memory, nr::MMAP2, &[0, 0x1000, 1, 0x22, -1i32 as u32, 0]) as u32;
+        assert_eq!(first, MMAP_BASE);
+        assert_eq!(second, MMAP_BASE + 0x2000);
+        assert!(memory.is_read_only(second));
+        assert_eq!(memory.read::<u32>(first + 0x1FFC), Ok(0));
+
+        assert_eq!(call(&mut linux, &mut memory, nr::MUNMAP, &[first, 0x1800]), 0);
+        assert!(!memory.is_mapped(first));
+        assert_eq!(call(&mut linux, &mut memory, nr::MPROTECT, &[second, 0x1000, 3]), 0);
+        assert!(!memory.is_read_only(second));
+        assert_eq!(call(&mut linux, &mut memory, nr::MMAP2, &[0, 0, 3, 0x22, -1i32 as u32, 0]), -errno::EINVAL);
+
+        // Nothing may run past the end of the address space
+        assert_eq!(call(&mut linux, &mut memory, nr::MMAP2, &[0xFFFFF000, 0x2000, 3, 0x32, -1i32 as u32, 0]), -errno::ENOMEM);
+        assert_eq!(call(&mut linux, &mut memory, nr::MMAP2, &[0, 0xFFFFFFFF, 3, 0x32, -1i32 as u32, 0]), -errno::ENOMEM);
+        assert_eq!(call(&mut linux, &mut memory, nr::MUNMAP, &[0xFFFFF000, 0x2000]), -errno::EINVAL);
+        assert_eq!(call(&mut linux, &mut memory, nr::MPROTECT, &[0xFFFFF000, 0x2000, 3]), -errno::ENOMEM);
+        assert_eq!(call(&mut linux, &mut memory, nr::MMAP2, &[0xFFFFF000, 0x1000, 3, 0x32, -1i32 as u32, 0]), 0xFFFFF000u32 as i32);
+
+        // Translations of code that was mapped over are dropped
+        memory.take_invalidations();
+        assert_eq!(call(&mut linux, &mut memory, nr::MMAP2, &[second, 0x1000, 5, 0x32, -1i32 as u32, 0]), second as i32);
+        assert_eq!(memory.take_invalidations(), vec![(second, 0x1000)]);
+    }
+
+    #[test]
+    fn files_stay_below_root() {
+        let root = std::env::temp_dir().join(format!("dynarmic-linux-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("hello.txt"), b"hello").unwrap();
+
+        let mut memory = MemoryImpl::new();
+        memory.map_memory(0x1000, 1, false);
+        let mut linux = Linux::new(&root);
+        let stdout = Captured::default();
+        linux.set_stdout(stdout.clone());
+
+        memory.write_bytes(0x1000, b"/hello.txt\0../secret\0").unwrap();
+        let fd = call(&mut linux, &mut memory, nr::OPENAT, &[AT_FDCWD as u32, 0x1000, 0]);
+        assert_eq!(fd, 3);
+        assert_eq!(call(&mut linux, &mut memory, nr::OPENAT, &[AT_FDCWD as u32, 0x100B, 0]), -errno::EACCES);
+
+        assert_eq!(call(&mut linux, &mut memory, nr::READ, &[3, 0x1800, 100]), 5);
+        assert_eq!(call(&mut linux, &mut memory, nr::WRITE, &[1, 0x1800, 5]), 5);
+        assert_eq!(&stdout.0.borrow()[..], b"hello");
+        assert_eq!(call(&mut linux, &mut memory, nr::CLOSE, &[3]), 0);
+        assert_eq!(call(&mut linux, &mut memory, nr::CLOSE, &[3]), -errno::EBADF);
+        assert_eq!(call(&mut linux, &mut memory, nr::WRITE, &[1, 0x9000, 1]), -errno::EFAULT);
+        assert_eq!(call(&mut linux, &mut memory, nr::WRITEV, &[1, 0xFFFFFFF8, 2]), -errno::EFAULT);
+        assert_eq!(call(&mut linux, &mut memory, nr::WRITEV, &[1, 0x1000, 0x20000000]), -errno::EINVAL);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn misc_syscalls() {
+        let mut memory = MemoryImpl::new();
+        memory.map_memory(0x1000, 1, false);
+        let mut linux = Linux::new(".");
+
+        assert_eq!(call(&mut linux, &mut memory, nr::UNAME, &[0x1000]), 0);
+        let mut machine = [0u8; 6];
+        memory.read_bytes(0x1000 + 4 * 65, &mut machine).unwrap();
+        assert_eq!(&machine, b"armv7l");
+
+        assert_eq!(call(&mut linux, &mut memory, nr::SET_TLS, &[0xDEAD0000]), 0);
+        assert_eq!(linux.tls().get(), 0xDEAD0000);
+        assert_eq!(call(&mut linux, &mut memory, nr::CLOCK_GETTIME, &[0, 0x1800]), 0);
+        memory.map_memory(0xFFFFF000, 1, false);
+        assert_eq!(call(&mut linux, &mut memory, nr::CLOCK_GETTIME, &[0, 0xFFFFFFFC]), -errno::EFAULT);
+        assert!(memory.read::<u32>(0x1800).unwrap() > 1_500_000_000);
+        assert_eq!(call(&mut linux, &mut memory, 9999, &[]), -errno::ENOSYS);
+        assert_eq!(linux.syscall(&mut memory, nr::EXIT_GROUP, [3, 0, 0, 0, 0, 0, 0]), SyscallResult::Exit(3));
+    }
+
+    #[test]
+    fn initial_stack_layout() {
+        let mut memory = MemoryImpl::new();
+        let image = ElfImage {
+            entry: 0x8000,
+            thumb: true,
+            segments: Vec::new(),
+            symbols: Vec::new(),
+            phdr: Some(0x8034),
+            phnum: 2,
+        };
+
+        let sp = Linux::build_stack(&mut memory, &image, &["prog", "-v"], &["A=1"]);
+        assert_eq!(sp % 16, 0);
+
+        let word = |i: u32| memory.read::<u32>(sp + i * 4).unwrap();
+        let string = |addr: u32| {
+            let mut bytes = Vec::new();
+            let mut addr = addr;
+            while let Ok(byte) = memory.read::<u8>(addr) {
+                if byte == 0 {
+                    break;
+                }
+                bytes.push(byte);
+                addr += 1;
+            }
+            String::from_utf8(bytes).unwrap()
+        };
+
+        assert_eq!(word(0), 2);
+        assert_eq!(string(word(1)), "prog");
+        assert_eq!(string(word(2)), "-v");
+        assert_eq!(word(3), 0);
+        assert_eq!(string(word(4)), "A=1");
+        assert_eq!(word(5), 0);
+
+        let auxv: Vec<(u32, u32)> = (0..).map(|i| (word(6 + i * 2), word(7 + i * 2)))
+            .take_while(|&(key, _)| key != 0)
+            .collect();
+        assert!(auxv.contains(&(9, 0x8001)));
+        assert!(auxv.contains(&(3, 0x8034)));
+        assert!(auxv.contains(&(6, 4096)));
+    }
+
+    #[test]
+    fn executor_hello_world() {
+        use crate::{Executor, HaltReason};
+
+        let mut memory = MemoryImpl::new();
+        memory.map_memory(0x8000, 1, true);
+        let program = [
+            0xE3A00001u32, // mov r0, #1
+            0xE28F1018,    // add r1, pc, #24 (msg)
+            0xE3A02006,    // mov r2, #6
+            0xE3A07004,    // mov r7, #4 (write)
+            0xEF000000,    // svc #0
+            0xE3A00003,    // mov r0, #3
+            0xE3A070F8,    // mov r7, #248 (exit_group)
+            0xEF000000,    // svc #0
+        ];
+        for (i, word) in program.iter().enumerate() {
+            memory.write(0x8000 + i as u32 * 4, *word).unwrap();
+        }
+        memory.write_bytes(0x8024, b"hello\n").unwrap();
+
+        let image = ElfImage {
+            entry: 0x8000,
+            thumb: false,
+            segments: Vec::new(),
+            symbols: Vec::new(),
+            phdr: None,
+            phnum: 0,
+        };
+
+        let mut linux = Linux::new(".");
+        let stdout = Captured::default();
+        linux.set_stdout(stdout.clone());
+        let sp = Linux::build_stack(&mut memory, &image, &["hello"], &[]);
+
+        let mut executor = Executor::new(TestHandlers::with(memory, linux)
+            .on_svc(|linux, memory, context, _swi| linux.handle_svc(memory, &context)));
+        image.set_entry(&executor.context());
+        executor.context().regs_mut()[13] = sp;
+
+        match executor.run() {
+            HaltReason::User(payload) => assert_eq!(payload.downcast_ref::<Exit>(), Some(&Exit(3))),
+            other => panic!("Unexpected halt {:?}", other),
+        }
+        assert_eq!(&stdout.0.borrow()[..], b"hello\n");
+    }
+}
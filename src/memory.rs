@@ -1,14 +1,20 @@
-use std::collections::BTreeMap;
-use byteorder::{LE, ByteOrder};
-use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryInto;
+use byteorder::{LE, ByteOrder, ReadBytesExt, WriteBytesExt};
+use std::io::{self, Read, Write};
+use std::cell::{Cell, RefCell};
 
-const PAGE_BITS: u32 = 12;
+use crate::savestate::{invalid_data, read_blob, read_bytes, write_blob};
+
+pub use dynarmic_sys::PageTable;
+
+pub const PAGE_BITS: u32 = 12;
 const NUM_PAGE_TABLE_ENTRIES: u32 = 1 << (32 - PAGE_BITS);
 const PAGE_LOWER_MASK: u32 = (1 << PAGE_BITS) - 1;
 const PAGE_UPPER_MASK: u32 = !PAGE_LOWER_MASK;
-const PAGE_SIZE: usize = 1 << PAGE_BITS;
+pub const PAGE_SIZE: usize = 1 << PAGE_BITS;
 
-pub trait Primitive: Sized {
+pub trait Primitive: Sized + Copy + Default + PartialEq {
     const ALIGN: usize = Self::SIZE - 1;
     const SIZE: usize = std::mem::size_of::<Self>();
     fn read(b: &[u8]) -> Self;
@@ -51,7 +57,16 @@ impl Primitive for u64 {
     }
 }
 
-impl<T: Primitive + Copy + Default> Primitive for [T; 2] {
+impl Primitive for u128 {
+    fn read(b: &[u8]) -> Self {
+        LE::read_u128(b)
+    }
+    fn write(self, b: &mut [u8]) {
+        LE::write_u128(b, self)
+    }
+}
+
+impl<T: Primitive + Copy + Default + PartialEq> Primitive for [T; 2] {
     fn read(b: &[u8]) -> Self {
         let mut out = Self::default();
         for (i, out) in out.iter_mut().enumerate() {
@@ -66,10 +81,106 @@ impl<T: Primitive + Copy + Default> Primitive for [T; 2] {
     }
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MemoryFault<A = u32> {
+    pub addr: A,
+    pub size: usize,
+    pub kind: AccessKind,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access, // Either
+}
+
+impl WatchKind {
+    fn matches(self, kind: AccessKind) -> bool {
+        matches!((self, kind),
+            (WatchKind::Access, _) |
+            (WatchKind::Read, AccessKind::Read) |
+            (WatchKind::Write, AccessKind::Write))
+    }
+}
+
+// An access overlapping a watched range, see MemoryImpl::add_watchpoint
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WatchHit<A = u32> {
+    pub addr: A,
+    pub size: usize,
+    pub kind: AccessKind,
+    pub value: u128, // Read or written, zero extended
+    // Filled in by the executor. Dynarmic only syncs the PC at block boundaries, so like
+    // DataAbort::pc this is the start of the block holding the access, not the accessing instruction.
+    pub pc: A,
+}
+
 pub trait Memory {
-    fn read<T: Primitive>(&self, addr: u32) -> T;
-    fn write<T: Primitive>(&self, addr: u32, value: T);
+    fn read<T: Primitive>(&self, addr: u32) -> Result<T, MemoryFault>;
+    fn write<T: Primitive>(&self, addr: u32, value: T) -> Result<(), MemoryFault>;
     fn is_read_only(&self, addr: u32) -> bool;
+
+    // Byte at a time, fails at the first unmapped byte. Bytes before it have been transferred.
+    fn read_bytes(&self, addr: u32, bytes: &mut [u8]) -> Result<(), MemoryFault> {
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.read(addr.wrapping_add(i as u32))?;
+        }
+        Ok(())
+    }
+
+    fn write_bytes(&self, addr: u32, bytes: &[u8]) -> Result<(), MemoryFault> {
+        for (i, &byte) in bytes.iter().enumerate() {
+            self.write(addr.wrapping_add(i as u32), byte)?;
+        }
+        Ok(())
+    }
+
+    // STREX with a global monitor. Stores value only if memory still holds expected and returns
+    // whether it did. Override this if memory is shared with something other than executors on the
+    // same thread.
+    fn write_exclusive<T: Primitive>(&self, addr: u32, value: T, expected: T) -> Result<bool, MemoryFault> {
+        let current: T = self.read(addr)
+            .map_err(|fault| MemoryFault { kind: AccessKind::Write, ..fault })?;
+        if current != expected {
+            return Ok(false);
+        }
+        self.write(addr, value)?;
+        Ok(true)
+    }
+
+    // Guest code ranges (addr, len) written since the last call. The executor drops any translated
+    // blocks covering them before running further.
+    fn take_invalidations(&self) -> Vec<(u32, u32)> {
+        Vec::new()
+    }
+
+    // Watched accesses since the last call. The executor hands them to Handlers::handle_watchpoint
+    // after each access.
+    fn take_watch_hits(&self) -> Vec<WatchHit> {
+        Vec::new()
+    }
+
+    // Savestate support, see the savestate module
+    fn save_state(&self, _w: &mut dyn Write) -> io::Result<()> {
+        Err(io::Error::other("Memory doesn't support savestates"))
+    }
+
+    fn load_state(&mut self, _r: &mut dyn Read) -> io::Result<()> {
+        Err(io::Error::other("Memory doesn't support savestates"))
+    }
+
+    // Host pointers for each guest page, handed to dynarmic so plain RAM accesses skip the callbacks.
+    // Null entries fall back to read/write.
+    fn page_table(&self) -> Option<&PageTable> {
+        None
+    }
 }
 
 pub enum PageSpanKind {
@@ -81,9 +192,21 @@ pub enum PageSpanKind {
     }
 }
 
-pub trait IOPage {
+// Send so a MemoryImpl can move to the thread running its executor. Devices that can't be Send,
+// e.g. ones holding an Rc, need a Memory implementation of their own.
+pub trait IOPage: Send {
     fn read(&mut self, o: usize, b: &mut [u8]);
     fn write(&mut self, o: usize, b: &[u8]);
+
+    // Device state for savestates. The device itself isn't saved, loading expects the same kind of
+    // page to be mapped at the same place already.
+    fn save_state(&self, _w: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn load_state(&mut self, _r: &mut dyn Read) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 impl PageSpanKind {
@@ -98,7 +221,7 @@ impl PageSpanKind {
             },
             PageSpanKind::MMIO { handler } => {
                 let mut h = handler.take().expect("Attempt to reentrantly read IO page");
-                let mut src = [0u8; 8];
+                let mut src = [0u8; 16];
                 h.read(offset, &mut src[..T::SIZE]);
                 handler.set(Some(h));
                 T::read(&src[..])
@@ -116,7 +239,7 @@ impl PageSpanKind {
             },
             PageSpanKind::MMIO { handler } => {
                 let mut h = handler.take().expect("Attempt to reentrantly write IO page");
-                let mut dest = [0u8; 8];
+                let mut dest = [0u8; 16];
                 T::write(value, &mut dest[..T::SIZE]);
                 h.write(offset, &mut dest[..T::SIZE]);
                 handler.set(Some(h));
@@ -131,8 +254,57 @@ pub struct PageSpan {
     read_only: bool,
 }
 
+impl PageSpan {
+    fn host_ptr(&mut self) -> Option<*mut u8> {
+        match &mut self.kind {
+            PageSpanKind::Normal { backing } => Some(backing.get_mut().as_mut_ptr()),
+            PageSpanKind::MMIO { .. } => None,
+        }
+    }
+
+    // Copies out `pages` pages starting at page offset `start`. IO spans can't be split, so they are dropped.
+    fn slice(&mut self, start: u32, pages: u32) -> Option<PageSpan> {
+        match &mut self.kind {
+            PageSpanKind::Normal { backing } => {
+                let bytes = &backing.get_mut()[((start << PAGE_BITS) as usize)..(((start + pages) << PAGE_BITS) as usize)];
+                Some(PageSpan {
+                    size: pages,
+                    kind: PageSpanKind::Normal {
+                        backing: Cell::new(bytes.to_vec().into_boxed_slice()),
+                    },
+                    read_only: self.read_only,
+                })
+            },
+            PageSpanKind::MMIO { .. } => None,
+        }
+    }
+}
+
 pub struct MemoryImpl {
     pages: BTreeMap<u32, PageSpan>, // Page -> PageSpan mapping
+    page_table: Box<PageTable>,
+    executable: BTreeSet<u32>, // Pages, independent of what is mapped there
+    invalidate_on_write: bool,
+    invalidations: RefCell<Vec<(u32, u32)>>,
+    watchpoints: Vec<Watchpoint>,
+    watch_hits: RefCell<Vec<WatchHit>>,
+}
+
+// The page table only points into pages MemoryImpl owns
+unsafe impl Send for MemoryImpl {}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+struct Watchpoint {
+    addr: u32,
+    len: u32,
+    kind: WatchKind,
+}
+
+impl Watchpoint {
+    fn overlaps(&self, addr: u32, len: u32) -> bool {
+        let (start, end) = (self.addr as u64, self.addr as u64 + self.len as u64);
+        (addr as u64) < end && addr as u64 + len as u64 > start
+    }
 }
 
 struct MemoryLookup<T> {
@@ -142,8 +314,17 @@ struct MemoryLookup<T> {
 
 impl MemoryImpl {
     pub fn new() -> MemoryImpl {
+        // Built on the heap, the table is too large for the stack
+        let page_table = vec![std::ptr::null_mut(); NUM_PAGE_TABLE_ENTRIES as usize].into_boxed_slice();
+
         MemoryImpl {
-            pages: Default::default()
+            pages: Default::default(),
+            page_table: page_table.try_into().unwrap(),
+            executable: Default::default(),
+            invalidate_on_write: false,
+            invalidations: RefCell::new(Vec::new()),
+            watchpoints: Vec::new(),
+            watch_hits: RefCell::new(Vec::new()),
         }
     }
 
@@ -173,48 +354,447 @@ impl MemoryImpl {
         }
     }
 
-    fn is_mapped(&self, addr: u32) -> bool {
+    // Accesses that run off the end of a span are split into bytes, which may land in the next span
+    fn read_split<T: Primitive>(&self, addr: u32) -> Result<T, MemoryFault> {
+        let mut bytes = [0u8; 16];
+        for (i, byte) in bytes[..T::SIZE].iter_mut().enumerate() {
+            *byte = self.read_unwatched(addr.wrapping_add(i as u32)).map_err(|_| MemoryFault {
+                addr,
+                size: T::SIZE,
+                kind: AccessKind::Read,
+            })?;
+        }
+        Ok(T::read(&bytes[..T::SIZE]))
+    }
+
+    fn write_split<T: Primitive>(&self, addr: u32, value: T) -> Result<(), MemoryFault> {
+        let fault = MemoryFault {
+            addr,
+            size: T::SIZE,
+            kind: AccessKind::Write,
+        };
+
+        // Check everything up front so a faulting write doesn't land halfway
+        if (0..T::SIZE as u32).any(|i| !self.is_mapped(addr.wrapping_add(i))) {
+            return Err(fault);
+        }
+
+        let mut bytes = [0u8; 16];
+        T::write(value, &mut bytes[..T::SIZE]);
+        for (i, byte) in bytes[..T::SIZE].iter().enumerate() {
+            self.write_unwatched(addr.wrapping_add(i as u32), *byte)?;
+        }
+        Ok(())
+    }
+
+    pub fn is_mapped(&self, addr: u32) -> bool {
         self.lookup((addr & PAGE_UPPER_MASK) >> PAGE_BITS).is_some()
     }
 
-    pub fn map_memory(&mut self, addr: u32, pages: u32, read_only: bool) {
-        let page_span = PageSpan {
-            size: pages,
-            kind: PageSpanKind::Normal {
-                backing: Cell::new(vec![0u8; (pages << PAGE_BITS) as usize].into_boxed_slice()),
+    fn insert_span(&mut self, page: u32, span: PageSpan) {
+        let size = span.size;
+        self.pages.insert(page, span);
+        self.update_page_table(page, size);
+    }
+
+    // Writes to code pages and accesses to watched pages have to reach read()/write() to be seen,
+    // so those are kept out of the table
+    fn update_page_table(&mut self, start: u32, pages: u32) {
+        for page in start..(start + pages) {
+            let watched = self.watchpoints.iter().any(|w| w.overlaps(page << PAGE_BITS, PAGE_SIZE as u32));
+            let hide = watched || (self.invalidate_on_write && self.executable.contains(&page));
+            let host_ptr = match self.lookup_mut(page) {
+                Some(MemoryLookup { item, offset }) if !hide => item.host_ptr()
+                    .map(|ptr| unsafe { ptr.add(offset as usize * PAGE_SIZE) }),
+                _ => None,
+            };
+            self.page_table[page as usize] = host_ptr.unwrap_or(std::ptr::null_mut());
+        }
+    }
+
+    // First page of [addr, addr + pages << PAGE_BITS), which has to end within the address space
+    fn page_range(addr: u32, pages: u32) -> u32 {
+        let start = addr >> PAGE_BITS;
+        assert!(start as u64 + pages as u64 <= NUM_PAGE_TABLE_ENTRIES as u64,
+                "{} pages at 0x{:X} run past the end of the address space", pages, addr);
+        start
+    }
+
+    // Marks pages as holding guest code, see set_invalidate_on_write
+    pub fn set_executable(&mut self, addr: u32, pages: u32, executable: bool) {
+        let start = Self::page_range(addr, pages);
+        for page in start..(start + pages) {
+            if executable {
+                self.executable.insert(page);
+            } else {
+                self.executable.remove(&page);
+            }
+        }
+        self.update_page_table(start, pages);
+    }
+
+    // Makes writes to executable pages invalidate the JIT's translations of them. Executor picks
+    // these up through Memory::take_invalidations after each access and before each run.
+    pub fn set_invalidate_on_write(&mut self, enable: bool) {
+        self.invalidate_on_write = enable;
+        let pages: Vec<u32> = self.executable.iter().cloned().collect();
+        for page in pages {
+            self.update_page_table(page, 1);
+        }
+    }
+
+    // Queues [addr, addr + len) for Memory::take_invalidations, for when code was replaced some other
+    // way than by writing it, e.g. by remapping its pages
+    pub fn invalidate(&self, addr: u32, len: u32) {
+        self.invalidations.borrow_mut().push((addr, len));
+    }
+
+    // Reports accesses overlapping [addr, addr + len) through Memory::take_watch_hits. Pages holding
+    // watched ranges are left out of the page table, which slows down everything else on them.
+    pub fn add_watchpoint(&mut self, addr: u32, len: u32, kind: WatchKind) {
+        assert!(len > 0, "Empty watchpoint at 0x{:X}", addr);
+        self.watchpoints.push(Watchpoint { addr, len, kind });
+        self.update_watched_pages(addr, len);
+    }
+
+    // Returns whether a matching watchpoint existed
+    pub fn remove_watchpoint(&mut self, addr: u32, len: u32, kind: WatchKind) -> bool {
+        let watchpoint = Watchpoint { addr, len, kind };
+        match self.watchpoints.iter().position(|w| *w == watchpoint) {
+            Some(index) => {
+                self.watchpoints.remove(index);
+                self.update_watched_pages(addr, len);
+                true
             },
-            read_only,
-        };
+            None => false,
+        }
+    }
 
-        self.pages.insert(addr >> PAGE_BITS, page_span);
+    // Ranges running past 0xFFFFFFFF stop at the last page
+    fn update_watched_pages(&mut self, addr: u32, len: u32) {
+        if len == 0 {
+            return;
+        }
+        let start = addr >> PAGE_BITS;
+        let last = ((addr as u64 + len as u64 - 1) >> PAGE_BITS).min(NUM_PAGE_TABLE_ENTRIES as u64 - 1) as u32;
+        self.update_page_table(start, last - start + 1);
     }
-}
 
-impl Memory for MemoryImpl {
-    fn read<T: Primitive>(&self, addr: u32) -> T {
+    fn record_watch_hit<T: Primitive>(&self, addr: u32, value: T, kind: AccessKind) {
+        let watched = self.watchpoints.iter()
+            .any(|w| w.kind.matches(kind) && w.overlaps(addr, T::SIZE as u32));
+        if !watched {
+            return;
+        }
+
+        let mut bytes = [0u8; 16];
+        T::write(value, &mut bytes[..T::SIZE]);
+        self.watch_hits.borrow_mut().push(WatchHit {
+            addr,
+            size: T::SIZE,
+            kind,
+            value: u128::from_le_bytes(bytes),
+            pc: 0,
+        });
+    }
+
+    fn read_unwatched<T: Primitive>(&self, addr: u32) -> Result<T, MemoryFault> {
         let page = (addr & !PAGE_LOWER_MASK) >> PAGE_BITS;
         let MemoryLookup { item, offset } = match self.lookup(page) {
             Some(l) => l,
             None => {
-                panic!("Unmapped memory read at {:X}", addr);
+                return Err(MemoryFault {
+                    addr,
+                    size: T::SIZE,
+                    kind: AccessKind::Read,
+                });
             }
         };
-        item.kind.read(((offset << PAGE_BITS) as usize) + (addr & PAGE_LOWER_MASK) as usize)
+        let offset = ((offset << PAGE_BITS) as usize) + (addr & PAGE_LOWER_MASK) as usize;
+        if offset + T::SIZE > (item.size << PAGE_BITS) as usize {
+            return self.read_split(addr);
+        }
+        Ok(item.kind.read(offset))
     }
 
-    fn write<T: Primitive>(&self, addr: u32, value: T) {
+    fn write_unwatched<T: Primitive>(&self, addr: u32, value: T) -> Result<(), MemoryFault> {
         let page = (addr & !PAGE_LOWER_MASK) >> PAGE_BITS;
         let MemoryLookup { item, offset } = match self.lookup(page) {
             Some(l) => l,
             None => {
-                panic!("Unmapped memory write at {:X}", addr);
+                return Err(MemoryFault {
+                    addr,
+                    size: T::SIZE,
+                    kind: AccessKind::Write,
+                });
             }
         };
-        item.kind.write(((offset << PAGE_BITS) as usize) + (addr & PAGE_LOWER_MASK) as usize, value)
+        let offset = ((offset << PAGE_BITS) as usize) + (addr & PAGE_LOWER_MASK) as usize;
+        if offset + T::SIZE > (item.size << PAGE_BITS) as usize {
+            return self.write_split(addr, value);
+        }
+        item.kind.write(offset, value);
+        let last_page = (addr.wrapping_add(T::SIZE as u32 - 1) & !PAGE_LOWER_MASK) >> PAGE_BITS;
+        if self.invalidate_on_write && (self.executable.contains(&page) || self.executable.contains(&last_page)) {
+            self.invalidations.borrow_mut().push((addr, T::SIZE as u32));
+        }
+        Ok(())
+    }
+
+    pub fn map_memory(&mut self, addr: u32, pages: u32, read_only: bool) {
+        Self::page_range(addr, pages);
+        let page_span = PageSpan {
+            size: pages,
+            kind: PageSpanKind::Normal {
+                backing: Cell::new(vec![0u8; (pages as usize) << PAGE_BITS].into_boxed_slice()),
+            },
+            read_only,
+        };
+
+        self.unmap_memory(addr, pages);
+        self.insert_span(addr >> PAGE_BITS, page_span);
+    }
+
+    // Changes the read only flag of every mapped page in the range, splitting spans as needed. IO
+    // spans only change when fully covered.
+    pub fn protect(&mut self, addr: u32, pages: u32, read_only: bool) {
+        let start = Self::page_range(addr, pages);
+        let end = start + pages;
+
+        let overlapping: Vec<u32> = self.pages.range(..end)
+            .filter(|(&page, span)| page + span.size > start && span.read_only != read_only)
+            .map(|(&page, _)| page)
+            .collect();
+
+        for page in overlapping {
+            let span = self.pages.get_mut(&page).unwrap();
+            let span_end = page + span.size;
+
+            if page >= start && span_end <= end {
+                span.read_only = read_only;
+                continue;
+            }
+
+            let (mid_start, mid_end) = (page.max(start), span_end.min(end));
+            let mid = match span.slice(mid_start - page, mid_end - mid_start) {
+                Some(mid) => mid,
+                None => continue,
+            };
+            let head = if page < start { span.slice(0, start - page) } else { None };
+            let tail = if span_end > end { span.slice(end - page, span_end - end) } else { None };
+
+            self.pages.remove(&page);
+            if let Some(head) = head {
+                self.insert_span(page, head);
+            }
+            self.insert_span(mid_start, PageSpan { read_only, ..mid });
+            if let Some(tail) = tail {
+                self.insert_span(end, tail);
+            }
+        }
+    }
+
+    // Accesses to these pages go to the handler, the span can't be partially unmapped later
+    pub fn map_io(&mut self, addr: u32, pages: u32, handler: Box<dyn IOPage>) {
+        Self::page_range(addr, pages);
+        let page_span = PageSpan {
+            size: pages,
+            kind: PageSpanKind::MMIO {
+                handler: Cell::new(Some(handler)),
+            },
+            read_only: false,
+        };
+
+        self.unmap_memory(addr, pages);
+        self.insert_span(addr >> PAGE_BITS, page_span);
+    }
+
+    // Unmaps every page in the range, splitting spans that only partially overlap it.
+    pub fn unmap_memory(&mut self, addr: u32, pages: u32) {
+        let start = Self::page_range(addr, pages);
+        let end = start + pages;
+
+        let overlapping: Vec<u32> = self.pages.range(..end)
+            .filter(|(&page, span)| page + span.size > start)
+            .map(|(&page, _)| page)
+            .collect();
+
+        for page in overlapping {
+            let mut span = self.pages.remove(&page).unwrap();
+            let span_end = page + span.size;
+
+            for i in page.max(start)..span_end.min(end) {
+                self.page_table[i as usize] = std::ptr::null_mut();
+            }
+
+            if page < start {
+                if let Some(head) = span.slice(0, start - page) {
+                    self.insert_span(page, head);
+                }
+            }
+
+            if span_end > end {
+                if let Some(tail) = span.slice(end - page, span_end - end) {
+                    self.insert_span(end, tail);
+                }
+            }
+        }
+    }
+}
+
+impl Memory for MemoryImpl {
+    fn read<T: Primitive>(&self, addr: u32) -> Result<T, MemoryFault> {
+        let value = self.read_unwatched(addr)?;
+        if !self.watchpoints.is_empty() {
+            self.record_watch_hit(addr, value, AccessKind::Read);
+        }
+        Ok(value)
+    }
+
+    fn write<T: Primitive>(&self, addr: u32, value: T) -> Result<(), MemoryFault> {
+        self.write_unwatched(addr, value)?;
+        if !self.watchpoints.is_empty() {
+            self.record_watch_hit(addr, value, AccessKind::Write);
+        }
+        Ok(())
     }
 
     fn is_read_only(&self, addr: u32) -> bool {
-        self.lookup((addr & PAGE_UPPER_MASK) >> PAGE_BITS).unwrap().item.read_only
+        self.lookup((addr & PAGE_UPPER_MASK) >> PAGE_BITS).is_some_and(|l| l.item.read_only)
+    }
+
+    // Spans in address order as (page, pages, read_only, kind, data) where kind 0 is RAM followed by
+    // its bytes and kind 1 is MMIO followed by a blob from IOPage::save_state. Then the executable
+    // pages and the invalidate_on_write flag.
+    fn save_state(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_u32::<LE>(self.pages.len() as u32)?;
+        for (&page, span) in &self.pages {
+            w.write_u32::<LE>(page)?;
+            w.write_u32::<LE>(span.size)?;
+            w.write_u8(span.read_only as u8)?;
+            match &span.kind {
+                PageSpanKind::Normal { backing } => {
+                    w.write_u8(0)?;
+                    let bytes = backing.replace(Box::new([]));
+                    let result = w.write_all(&bytes);
+                    backing.set(bytes);
+                    result?;
+                },
+                PageSpanKind::MMIO { handler } => {
+                    w.write_u8(1)?;
+                    let h = handler.take().expect("Attempt to save IO page while it is in use");
+                    let result = write_blob(w, |w| h.save_state(w));
+                    handler.set(Some(h));
+                    result?;
+                }
+            }
+        }
+
+        w.write_u32::<LE>(self.executable.len() as u32)?;
+        for &page in &self.executable {
+            w.write_u32::<LE>(page)?;
+        }
+        w.write_u8(self.invalidate_on_write as u8)
+    }
+
+    // Everything is parsed and checked before touching the current mappings, so a broken savestate
+    // leaves them as they were. IO pages have to be mapped already, only their state is restored,
+    // and one failing to load may leave those before it loaded.
+    fn load_state(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        enum Saved {
+            Normal(Box<[u8]>),
+            Io(Vec<u8>),
+        }
+
+        let count = r.read_u32::<LE>()?;
+        let mut spans = Vec::new();
+        for _ in 0..count {
+            let page = r.read_u32::<LE>()?;
+            let size = r.read_u32::<LE>()?;
+            if size == 0 || page as u64 + size as u64 > NUM_PAGE_TABLE_ENTRIES as u64 {
+                return Err(invalid_data("Memory span out of range"));
+            }
+            let read_only = r.read_u8()? != 0;
+            let saved = match r.read_u8()? {
+                0 => Saved::Normal(read_bytes(r, (size as u64) << PAGE_BITS)?.into_boxed_slice()),
+                1 => {
+                    let current = self.pages.get(&page);
+                    match current {
+                        Some(PageSpan { size: current_size, kind: PageSpanKind::MMIO { .. }, .. }) if *current_size == size => {},
+                        _ => return Err(invalid_data(&format!("No IO page mapped at 0x{:X} to restore", page << PAGE_BITS))),
+                    }
+                    Saved::Io(read_blob(r)?)
+                },
+                _ => return Err(invalid_data("Unknown memory span kind")),
+            };
+            spans.push((page, size, read_only, saved));
+        }
+
+        let count = r.read_u32::<LE>()?;
+        let mut executable = BTreeSet::new();
+        for _ in 0..count {
+            executable.insert(r.read_u32::<LE>()?);
+        }
+        let invalidate_on_write = r.read_u8()? != 0;
+
+        // Saved in address order, and RAM can't cover an IO page that's staying
+        let mut end = 0;
+        for (page, size, _, saved) in &spans {
+            let covers_io = matches!(saved, Saved::Normal(_)) && self.pages.range(..page + size)
+                .any(|(&io_page, span)| io_page + span.size > *page && matches!(span.kind, PageSpanKind::MMIO { .. }));
+            if *page < end || covers_io {
+                return Err(invalid_data("Memory spans overlap"));
+            }
+            end = page + size;
+        }
+
+        for (page, _, _, saved) in &spans {
+            if let (Saved::Io(blob), Some(PageSpan { kind: PageSpanKind::MMIO { handler }, .. })) = (saved, self.pages.get_mut(page)) {
+                handler.get_mut().as_mut().unwrap().load_state(&mut &blob[..])?;
+            }
+        }
+
+        // Nothing can fail from here on. RAM is replaced wholesale, IO pages stay where they are.
+        let ram: Vec<(u32, u32)> = self.pages.iter()
+            .filter(|(_, span)| matches!(span.kind, PageSpanKind::Normal { .. }))
+            .map(|(&page, span)| (page, span.size))
+            .collect();
+        for (page, size) in ram {
+            self.unmap_memory(page << PAGE_BITS, size);
+        }
+
+        self.executable = executable;
+        self.invalidate_on_write = invalidate_on_write;
+        self.invalidations.borrow_mut().clear();
+
+        for (page, size, read_only, saved) in spans {
+            match saved {
+                Saved::Normal(bytes) => {
+                    self.insert_span(page, PageSpan {
+                        size,
+                        kind: PageSpanKind::Normal {
+                            backing: Cell::new(bytes),
+                        },
+                        read_only,
+                    });
+                },
+                Saved::Io(_) => self.pages.get_mut(&page).unwrap().read_only = read_only,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn take_invalidations(&self) -> Vec<(u32, u32)> {
+        std::mem::take(&mut *self.invalidations.borrow_mut())
+    }
+
+    fn take_watch_hits(&self) -> Vec<WatchHit> {
+        std::mem::take(&mut *self.watch_hits.borrow_mut())
+    }
+
+    fn page_table(&self) -> Option<&PageTable> {
+        Some(&self.page_table)
     }
 }
 
@@ -244,4 +824,253 @@ mod tests {
         assert!(mem.lookup(0).is_some());
         assert!(mem.lookup(1).is_some());
     }
+
+    #[test]
+    fn page_table_follows_mappings() {
+        let mut mem = MemoryImpl::new();
+        mem.map_memory(0x1000, 2, false);
+        assert!(mem.page_table[0].is_null());
+        assert!(!mem.page_table[1].is_null());
+        assert_eq!(mem.page_table[2], unsafe { mem.page_table[1].add(PAGE_SIZE) });
+
+        mem.unmap_memory(0x1000, 2);
+        assert!(mem.page_table[1].is_null());
+        assert!(mem.page_table[2].is_null());
+    }
+
+    #[test]
+    fn last_page_maps() {
+        let mut mem = MemoryImpl::new();
+        mem.map_memory(0xFFFFF000, 1, false);
+        mem.write(0xFFFFFFFC, 0x11223344u32).unwrap();
+        assert_eq!(mem.read::<u32>(0xFFFFFFFC), Ok(0x11223344));
+        assert!(!mem.page_table[NUM_PAGE_TABLE_ENTRIES as usize - 1].is_null());
+
+        mem.protect(0xFFFFF000, 1, true);
+        assert!(mem.is_read_only(0xFFFFF000));
+        mem.unmap_memory(0xFFFFF000, 1);
+        assert!(mem.lookup(NUM_PAGE_TABLE_ENTRIES - 1).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "past the end of the address space")]
+    fn mapping_past_the_end_panics() {
+        MemoryImpl::new().map_memory(0xFFFFF000, 2, false);
+    }
+
+    #[test]
+    fn unmap_splits_span() {
+        let mut mem = MemoryImpl::new();
+        mem.map_memory(0, 3, false);
+        mem.write(0x0000, 0x11u8).unwrap();
+        mem.write(0x2000, 0x22u8).unwrap();
+
+        mem.unmap_memory(0x1000, 1);
+        assert!(mem.lookup(1).is_none());
+        assert_eq!(mem.read::<u8>(0x0000), Ok(0x11));
+        assert_eq!(mem.read::<u8>(0x2000), Ok(0x22));
+        assert_eq!(unsafe { *mem.page_table[2] }, 0x22);
+    }
+
+    #[test]
+    fn unmapped_access_faults() {
+        let mut mem = MemoryImpl::new();
+        mem.map_memory(0x1000, 1, false);
+        assert_eq!(mem.read::<u32>(0x2000), Err(MemoryFault { addr: 0x2000, size: 4, kind: AccessKind::Read }));
+        assert_eq!(mem.write(0x0FFC, 0u16), Err(MemoryFault { addr: 0x0FFC, size: 2, kind: AccessKind::Write }));
+        assert!(!mem.is_read_only(0x2000));
+    }
+
+    #[test]
+    fn access_across_spans() {
+        let mut mem = MemoryImpl::new();
+        mem.map_memory(0x0000, 1, false);
+        mem.map_memory(0x1000, 1, false);
+        mem.write(0x0FFE, 0x44332211u32).unwrap();
+        assert_eq!(mem.read::<u32>(0x0FFE), Ok(0x44332211));
+        assert_eq!(mem.read::<u16>(0x1000), Ok(0x4433));
+
+        // Straddling into unmapped memory faults without a partial write
+        assert!(mem.write(0x1FFE, 0xFFFFFFFFu32).is_err());
+        assert_eq!(mem.read::<u16>(0x1FFE), Ok(0));
+    }
+
+    #[test]
+    fn write_exclusive_compares() {
+        let mut mem = MemoryImpl::new();
+        mem.map_memory(0x1000, 1, false);
+        mem.write(0x1000, 5u32).unwrap();
+        assert_eq!(mem.write_exclusive(0x1000, 7u32, 6), Ok(false));
+        assert_eq!(mem.read::<u32>(0x1000), Ok(5));
+        assert_eq!(mem.write_exclusive(0x1000, 7u32, 5), Ok(true));
+        assert_eq!(mem.read::<u32>(0x1000), Ok(7));
+        assert_eq!(mem.write_exclusive(0x2000, 1u8, 0), Err(MemoryFault { addr: 0x2000, size: 1, kind: AccessKind::Write }));
+    }
+
+    #[test]
+    fn writes_to_code_invalidate() {
+        let mut mem = MemoryImpl::new();
+        mem.map_memory(0x0000, 2, false);
+        mem.set_executable(0x1000, 1, true);
+
+        mem.write(0x1010, 0u32).unwrap();
+        assert!(mem.take_invalidations().is_empty());
+        assert!(!mem.page_table[1].is_null());
+
+        mem.set_invalidate_on_write(true);
+        assert!(!mem.page_table[0].is_null());
+        assert!(mem.page_table[1].is_null());
+
+        mem.write(0x0010, 0u32).unwrap();
+        mem.write(0x1010, 0u32).unwrap();
+        mem.write(0x0FFE, 0u32).unwrap(); // Straddles into the code page
+        assert_eq!(mem.take_invalidations(), vec![(0x1010, 4), (0x0FFE, 4)]);
+        assert!(mem.take_invalidations().is_empty());
+
+        // Remapping keeps the page out of the table
+        mem.map_memory(0x1000, 1, false);
+        assert!(mem.page_table[1].is_null());
+    }
+
+    #[test]
+    fn watchpoints_record_hits() {
+        let mut mem = MemoryImpl::new();
+        mem.map_memory(0x0000, 2, false);
+        mem.add_watchpoint(0x1004, 4, WatchKind::Write);
+        mem.add_watchpoint(0x1010, 1, WatchKind::Access);
+        assert!(!mem.page_table[0].is_null());
+        assert!(mem.page_table[1].is_null());
+
+        mem.write(0x1000, 0x11u32).unwrap();
+        mem.read::<u32>(0x1004).unwrap();
+        mem.write(0x1006, 0x2233u16).unwrap();
+        mem.write(0x100E, 0x44332211u32).unwrap();
+        mem.read::<u8>(0x1010).unwrap();
+        assert_eq!(mem.take_watch_hits(), vec![
+            WatchHit { addr: 0x1006, size: 2, kind: AccessKind::Write, value: 0x2233, pc: 0 },
+            WatchHit { addr: 0x100E, size: 4, kind: AccessKind::Write, value: 0x44332211, pc: 0 },
+            WatchHit { addr: 0x1010, size: 1, kind: AccessKind::Read, value: 0x33, pc: 0 },
+        ]);
+        assert!(mem.take_watch_hits().is_empty());
+
+        assert!(mem.remove_watchpoint(0x1004, 4, WatchKind::Write));
+        assert!(!mem.remove_watchpoint(0x1004, 4, WatchKind::Write));
+        assert!(mem.page_table[1].is_null());
+        assert!(mem.remove_watchpoint(0x1010, 1, WatchKind::Access));
+        assert!(!mem.page_table[1].is_null());
+    }
+
+    #[test]
+    fn watchpoint_at_the_top_of_memory() {
+        let mut mem = MemoryImpl::new();
+        mem.map_memory(0xFFFFF000, 1, false);
+        mem.add_watchpoint(0xFFFFFFFF, 2, WatchKind::Write);
+        assert!(mem.page_table[NUM_PAGE_TABLE_ENTRIES as usize - 1].is_null());
+
+        mem.write(0xFFFFFFFF, 0x55u8).unwrap();
+        assert_eq!(mem.take_watch_hits(), vec![
+            WatchHit { addr: 0xFFFFFFFF, size: 1, kind: AccessKind::Write, value: 0x55, pc: 0 },
+        ]);
+
+        assert!(mem.remove_watchpoint(0xFFFFFFFF, 2, WatchKind::Write));
+        assert!(!mem.page_table[NUM_PAGE_TABLE_ENTRIES as usize - 1].is_null());
+        assert!(!mem.remove_watchpoint(0x1000, 0, WatchKind::Write));
+    }
+
+    #[test]
+    #[should_panic(expected = "Empty watchpoint")]
+    fn empty_watchpoint_panics() {
+        MemoryImpl::new().add_watchpoint(0x1000, 0, WatchKind::Read);
+    }
+
+    #[test]
+    fn savestate_round_trip() {
+        struct Counter(u8);
+
+        impl IOPage for Counter {
+            fn read(&mut self, _o: usize, b: &mut [u8]) {
+                b[0] = self.0;
+            }
+
+            fn write(&mut self, _o: usize, b: &[u8]) {
+                self.0 = b[0];
+            }
+
+            fn save_state(&self, w: &mut dyn Write) -> io::Result<()> {
+                w.write_u8(self.0)
+            }
+
+            fn load_state(&mut self, r: &mut dyn Read) -> io::Result<()> {
+                self.0 = r.read_u8()?;
+                Ok(())
+            }
+        }
+
+        let mut mem = MemoryImpl::new();
+        mem.map_memory(0x1000, 2, true);
+        mem.map_memory(0x8000, 1, false);
+        mem.map_io(0x10000, 1, Box::new(Counter(0)));
+        mem.set_executable(0x1000, 1, true);
+        mem.write(0x1004, 0x12345678u32).unwrap();
+        mem.write(0x8000, 0xAAu8).unwrap();
+        mem.write(0x10000, 7u8).unwrap();
+
+        let mut state = Vec::new();
+        mem.save_state(&mut state).unwrap();
+
+        mem.unmap_memory(0x1000, 1);
+        mem.map_memory(0x20000, 1, false);
+        mem.write(0x8000, 0xBBu8).unwrap();
+        mem.write(0x10000, 9u8).unwrap();
+
+        mem.load_state(&mut &state[..]).unwrap();
+        assert_eq!(mem.read::<u32>(0x1004), Ok(0x12345678));
+        assert!(mem.is_read_only(0x1000));
+        assert_eq!(mem.read::<u8>(0x8000), Ok(0xAA));
+        assert_eq!(mem.read::<u8>(0x10000), Ok(7));
+        assert!(mem.read::<u8>(0x20000).is_err());
+        assert!(!mem.page_table[2].is_null());
+        assert!(mem.page_table[0x20].is_null());
+
+        // A failed load changes nothing
+        let mut overlapping = state.clone();
+        overlapping[4..8].copy_from_slice(&0x10u32.to_le_bytes()); // First span at 0x10000
+        mem.unmap_memory(0x8000, 1);
+        assert!(mem.load_state(&mut &overlapping[..]).is_err());
+        assert!(mem.read::<u8>(0x8000).is_err());
+        assert_eq!(mem.read::<u32>(0x1004), Ok(0x12345678));
+        assert_eq!(mem.read::<u8>(0x10000), Ok(7));
+
+        // IO pages aren't recreated
+        let mut other = MemoryImpl::new();
+        assert!(other.load_state(&mut &state[..]).is_err());
+        assert!(other.pages.is_empty());
+        // A span claiming all of memory with only a few bytes behind it
+        let mut truncated = Vec::new();
+        truncated.extend(&1u32.to_le_bytes());
+        truncated.extend(&0u32.to_le_bytes());
+        truncated.extend(&NUM_PAGE_TABLE_ENTRIES.to_le_bytes());
+        truncated.extend(&[0, 0, 1, 2, 3]);
+        let err = other.load_state(&mut &truncated[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn protect_splits_span() {
+        let mut mem = MemoryImpl::new();
+        mem.map_memory(0x0000, 4, false);
+        mem.write(0x2000, 0x1234u16).unwrap();
+        mem.protect(0x1000, 2, true);
+
+        assert!(!mem.is_read_only(0x0000));
+        assert!(mem.is_read_only(0x1000));
+        assert!(mem.is_read_only(0x2FFF));
+        assert!(!mem.is_read_only(0x3000));
+        assert_eq!(mem.read::<u16>(0x2000), Ok(0x1234));
+        assert_eq!(mem.pages.len(), 3);
+        assert!(!mem.page_table[2].is_null());
+
+        mem.protect(0x0000, 4, false);
+        assert!(!mem.is_read_only(0x1000));
+    }
 }
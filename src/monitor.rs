@@ -0,0 +1,46 @@
+use dynarmic_sys::{
+    ExclusiveMonitor as RawMonitor,
+    dynarmic_exclusive_monitor_new,
+    dynarmic_exclusive_monitor_delete,
+    dynarmic_exclusive_monitor_clear,
+};
+
+// Tracks LDREX reservations across cores. Every executor sharing one needs its own processor id,
+// see ExecutorBuilder::exclusive_monitor.
+pub struct ExclusiveMonitor {
+    raw: *mut RawMonitor,
+    processor_count: usize,
+}
+
+// Dynarmic locks the monitor internally
+unsafe impl Send for ExclusiveMonitor {}
+unsafe impl Sync for ExclusiveMonitor {}
+
+impl ExclusiveMonitor {
+    pub fn new(processor_count: usize) -> Self {
+        assert!(processor_count > 0, "Exclusive monitor needs at least one processor");
+        ExclusiveMonitor {
+            raw: unsafe { dynarmic_exclusive_monitor_new(processor_count) },
+            processor_count,
+        }
+    }
+
+    pub fn processor_count(&self) -> usize {
+        self.processor_count
+    }
+
+    // Drops every core's reservation
+    pub fn clear(&self) {
+        unsafe { dynarmic_exclusive_monitor_clear(self.raw) }
+    }
+
+    pub(crate) fn raw(&self) -> *mut RawMonitor {
+        self.raw
+    }
+}
+
+impl Drop for ExclusiveMonitor {
+    fn drop(&mut self) {
+        unsafe { dynarmic_exclusive_monitor_delete(self.raw) }
+    }
+}
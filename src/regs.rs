@@ -0,0 +1,474 @@
+// Typed views of the A32 registers. The status register wrappers keep the raw value reachable
+// through .0 for anything not covered here.
+
+use std::ops::{Index, IndexMut};
+
+// Core registers, indexes JitContext::regs and CpuContext::regs
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Reg {
+    R0, R1, R2, R3, R4, R5, R6, R7, R8, R9, R10, R11, R12,
+    Sp,
+    Lr,
+    Pc,
+}
+
+impl Reg {
+    // R13-R15 come back as Sp, Lr and Pc
+    pub fn from_index(index: usize) -> Option<Reg> {
+        const ALL: [Reg; 16] = [
+            Reg::R0, Reg::R1, Reg::R2, Reg::R3, Reg::R4, Reg::R5, Reg::R6, Reg::R7,
+            Reg::R8, Reg::R9, Reg::R10, Reg::R11, Reg::R12, Reg::Sp, Reg::Lr, Reg::Pc,
+        ];
+        ALL.get(index).copied()
+    }
+}
+
+impl From<Reg> for usize {
+    fn from(reg: Reg) -> usize {
+        reg as usize
+    }
+}
+
+impl Index<Reg> for [u32; 16] {
+    type Output = u32;
+
+    fn index(&self, reg: Reg) -> &u32 {
+        &self[reg as usize]
+    }
+}
+
+impl IndexMut<Reg> for [u32; 16] {
+    fn index_mut(&mut self, reg: Reg) -> &mut u32 {
+        &mut self[reg as usize]
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ProcessorMode {
+    User = 0x10,
+    Fiq = 0x11,
+    Irq = 0x12,
+    Supervisor = 0x13,
+    Monitor = 0x16,
+    Abort = 0x17,
+    Hyp = 0x1A,
+    Undefined = 0x1B,
+    System = 0x1F,
+}
+
+impl ProcessorMode {
+    // None for reserved encodings
+    pub fn from_bits(bits: u32) -> Option<ProcessorMode> {
+        Some(match bits & 0x1F {
+            0x10 => ProcessorMode::User,
+            0x11 => ProcessorMode::Fiq,
+            0x12 => ProcessorMode::Irq,
+            0x13 => ProcessorMode::Supervisor,
+            0x16 => ProcessorMode::Monitor,
+            0x17 => ProcessorMode::Abort,
+            0x1A => ProcessorMode::Hyp,
+            0x1B => ProcessorMode::Undefined,
+            0x1F => ProcessorMode::System,
+            _ => return None,
+        })
+    }
+}
+
+// FPSCR.RMode
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RoundingMode {
+    ToNearest = 0,
+    TowardsPlusInfinity = 1,
+    TowardsMinusInfinity = 2,
+    TowardsZero = 3,
+}
+
+fn bit(value: u32, n: u32) -> bool {
+    value & (1 << n) != 0
+}
+
+fn set_bit(value: &mut u32, n: u32, set: bool) {
+    if set {
+        *value |= 1 << n;
+    } else {
+        *value &= !(1 << n);
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Cpsr(pub u32);
+
+impl Cpsr {
+    const N: u32 = 31;
+    const Z: u32 = 30;
+    const C: u32 = 29;
+    const V: u32 = 28;
+    const Q: u32 = 27;
+    const J: u32 = 24;
+    const E: u32 = 9;
+    const A: u32 = 8;
+    const I: u32 = 7;
+    const F: u32 = 6;
+    const T: u32 = 5;
+
+    // ARM state, little endian, everything else clear
+    pub fn new(mode: ProcessorMode) -> Cpsr {
+        Cpsr(mode as u32)
+    }
+
+    pub fn n(self) -> bool {
+        bit(self.0, Cpsr::N)
+    }
+
+    pub fn set_n(&mut self, set: bool) {
+        set_bit(&mut self.0, Cpsr::N, set)
+    }
+
+    pub fn z(self) -> bool {
+        bit(self.0, Cpsr::Z)
+    }
+
+    pub fn set_z(&mut self, set: bool) {
+        set_bit(&mut self.0, Cpsr::Z, set)
+    }
+
+    pub fn c(self) -> bool {
+        bit(self.0, Cpsr::C)
+    }
+
+    pub fn set_c(&mut self, set: bool) {
+        set_bit(&mut self.0, Cpsr::C, set)
+    }
+
+    pub fn v(self) -> bool {
+        bit(self.0, Cpsr::V)
+    }
+
+    pub fn set_v(&mut self, set: bool) {
+        set_bit(&mut self.0, Cpsr::V, set)
+    }
+
+    // Sticky saturation flag
+    pub fn q(self) -> bool {
+        bit(self.0, Cpsr::Q)
+    }
+
+    pub fn set_q(&mut self, set: bool) {
+        set_bit(&mut self.0, Cpsr::Q, set)
+    }
+
+    // GE[3:0] from the SIMD add/subtract instructions
+    pub fn ge(self) -> u8 {
+        (self.0 >> 16) as u8 & 0xF
+    }
+
+    pub fn set_ge(&mut self, ge: u8) {
+        self.0 = self.0 & !(0xF << 16) | (ge as u32 & 0xF) << 16;
+    }
+
+    pub fn is_thumb(self) -> bool {
+        bit(self.0, Cpsr::T)
+    }
+
+    pub fn set_thumb(&mut self, thumb: bool) {
+        set_bit(&mut self.0, Cpsr::T, thumb)
+    }
+
+    // Jazelle, which dynarmic doesn't implement
+    pub fn is_jazelle(self) -> bool {
+        bit(self.0, Cpsr::J)
+    }
+
+    // Data endianness, SETEND changes this unless ExecutorBuilder::always_little_endian is set
+    pub fn is_big_endian(self) -> bool {
+        bit(self.0, Cpsr::E)
+    }
+
+    pub fn set_big_endian(&mut self, big_endian: bool) {
+        set_bit(&mut self.0, Cpsr::E, big_endian)
+    }
+
+    pub fn async_abort_masked(self) -> bool {
+        bit(self.0, Cpsr::A)
+    }
+
+    pub fn set_async_abort_masked(&mut self, masked: bool) {
+        set_bit(&mut self.0, Cpsr::A, masked)
+    }
+
+    pub fn irq_masked(self) -> bool {
+        bit(self.0, Cpsr::I)
+    }
+
+    pub fn set_irq_masked(&mut self, masked: bool) {
+        set_bit(&mut self.0, Cpsr::I, masked)
+    }
+
+    pub fn fiq_masked(self) -> bool {
+        bit(self.0, Cpsr::F)
+    }
+
+    pub fn set_fiq_masked(&mut self, masked: bool) {
+        set_bit(&mut self.0, Cpsr::F, masked)
+    }
+
+    // ITSTATE, split between IT[1:0] in bits 26:25 and IT[7:2] in bits 15:10
+    pub fn it(self) -> u8 {
+        ((self.0 >> 25) & 0x3 | (self.0 >> 8) & 0xFC) as u8
+    }
+
+    pub fn set_it(&mut self, it: u8) {
+        let it = it as u32;
+        self.0 = self.0 & !(0x3 << 25 | 0x3F << 10) | (it & 0x3) << 25 | (it & 0xFC) << 8;
+    }
+
+    pub fn in_it_block(self) -> bool {
+        self.it() & 0xF != 0
+    }
+
+    // None for reserved encodings
+    pub fn mode(self) -> Option<ProcessorMode> {
+        ProcessorMode::from_bits(self.0)
+    }
+
+    pub fn set_mode(&mut self, mode: ProcessorMode) {
+        self.0 = self.0 & !0x1F | mode as u32;
+    }
+}
+
+impl From<u32> for Cpsr {
+    fn from(value: u32) -> Cpsr {
+        Cpsr(value)
+    }
+}
+
+impl From<Cpsr> for u32 {
+    fn from(cpsr: Cpsr) -> u32 {
+        cpsr.0
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Fpscr(pub u32);
+
+impl Fpscr {
+    const N: u32 = 31;
+    const Z: u32 = 30;
+    const C: u32 = 29;
+    const V: u32 = 28;
+    const QC: u32 = 27;
+    const DN: u32 = 25;
+    const FZ: u32 = 24;
+    const IDC: u32 = 7;
+    const IXC: u32 = 4;
+    const UFC: u32 = 3;
+    const OFC: u32 = 2;
+    const DZC: u32 = 1;
+    const IOC: u32 = 0;
+
+    const CUMULATIVE_EXCEPTIONS: u32 = 0x9F;
+
+    pub fn n(self) -> bool {
+        bit(self.0, Fpscr::N)
+    }
+
+    pub fn set_n(&mut self, set: bool) {
+        set_bit(&mut self.0, Fpscr::N, set)
+    }
+
+    pub fn z(self) -> bool {
+        bit(self.0, Fpscr::Z)
+    }
+
+    pub fn set_z(&mut self, set: bool) {
+        set_bit(&mut self.0, Fpscr::Z, set)
+    }
+
+    pub fn c(self) -> bool {
+        bit(self.0, Fpscr::C)
+    }
+
+    pub fn set_c(&mut self, set: bool) {
+        set_bit(&mut self.0, Fpscr::C, set)
+    }
+
+    pub fn v(self) -> bool {
+        bit(self.0, Fpscr::V)
+    }
+
+    pub fn set_v(&mut self, set: bool) {
+        set_bit(&mut self.0, Fpscr::V, set)
+    }
+
+    // Sticky Advanced SIMD saturation flag
+    pub fn qc(self) -> bool {
+        bit(self.0, Fpscr::QC)
+    }
+
+    pub fn set_qc(&mut self, set: bool) {
+        set_bit(&mut self.0, Fpscr::QC, set)
+    }
+
+    pub fn default_nan(self) -> bool {
+        bit(self.0, Fpscr::DN)
+    }
+
+    pub fn set_default_nan(&mut self, enable: bool) {
+        set_bit(&mut self.0, Fpscr::DN, enable)
+    }
+
+    pub fn flush_to_zero(self) -> bool {
+        bit(self.0, Fpscr::FZ)
+    }
+
+    pub fn set_flush_to_zero(&mut self, enable: bool) {
+        set_bit(&mut self.0, Fpscr::FZ, enable)
+    }
+
+    pub fn rounding_mode(self) -> RoundingMode {
+        match (self.0 >> 22) & 0x3 {
+            0 => RoundingMode::ToNearest,
+            1 => RoundingMode::TowardsPlusInfinity,
+            2 => RoundingMode::TowardsMinusInfinity,
+            _ => RoundingMode::TowardsZero,
+        }
+    }
+
+    pub fn set_rounding_mode(&mut self, mode: RoundingMode) {
+        self.0 = self.0 & !(0x3 << 22) | (mode as u32) << 22;
+    }
+
+    // Cumulative exception flags, set by the JIT and only cleared by the guest
+    pub fn invalid_operation(self) -> bool {
+        bit(self.0, Fpscr::IOC)
+    }
+
+    pub fn set_invalid_operation(&mut self, set: bool) {
+        set_bit(&mut self.0, Fpscr::IOC, set)
+    }
+
+    pub fn division_by_zero(self) -> bool {
+        bit(self.0, Fpscr::DZC)
+    }
+
+    pub fn set_division_by_zero(&mut self, set: bool) {
+        set_bit(&mut self.0, Fpscr::DZC, set)
+    }
+
+    pub fn overflow(self) -> bool {
+        bit(self.0, Fpscr::OFC)
+    }
+
+    pub fn set_overflow(&mut self, set: bool) {
+        set_bit(&mut self.0, Fpscr::OFC, set)
+    }
+
+    pub fn underflow(self) -> bool {
+        bit(self.0, Fpscr::UFC)
+    }
+
+    pub fn set_underflow(&mut self, set: bool) {
+        set_bit(&mut self.0, Fpscr::UFC, set)
+    }
+
+    pub fn inexact(self) -> bool {
+        bit(self.0, Fpscr::IXC)
+    }
+
+    pub fn set_inexact(&mut self, set: bool) {
+        set_bit(&mut self.0, Fpscr::IXC, set)
+    }
+
+    pub fn input_denormal(self) -> bool {
+        bit(self.0, Fpscr::IDC)
+    }
+
+    pub fn set_input_denormal(&mut self, set: bool) {
+        set_bit(&mut self.0, Fpscr::IDC, set)
+    }
+
+    pub fn clear_cumulative_exceptions(&mut self) {
+        self.0 &= !Fpscr::CUMULATIVE_EXCEPTIONS;
+    }
+}
+
+impl From<u32> for Fpscr {
+    fn from(value: u32) -> Fpscr {
+        Fpscr(value)
+    }
+}
+
+impl From<Fpscr> for u32 {
+    fn from(fpscr: Fpscr) -> u32 {
+        fpscr.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reg_indexing() {
+        let mut regs = [0u32; 16];
+        regs[Reg::Sp] = 0x8000;
+        regs[Reg::R3] = 3;
+        assert_eq!(regs[13], 0x8000);
+        assert_eq!(regs[Reg::R3], 3);
+        assert_eq!(Reg::from_index(15), Some(Reg::Pc));
+        assert_eq!(Reg::from_index(16), None);
+        assert_eq!(usize::from(Reg::Lr), 14);
+    }
+
+    #[test]
+    fn cpsr_fields() {
+        let mut cpsr = Cpsr::new(ProcessorMode::Supervisor);
+        assert_eq!(cpsr.mode(), Some(ProcessorMode::Supervisor));
+        assert!(!cpsr.is_thumb());
+
+        cpsr.set_thumb(true);
+        cpsr.set_n(true);
+        cpsr.set_c(true);
+        cpsr.set_ge(0b1010);
+        cpsr.set_irq_masked(true);
+        assert_eq!(cpsr, Cpsr(0xA00A00B3));
+
+        cpsr.set_mode(ProcessorMode::User);
+        cpsr.set_thumb(false);
+        assert_eq!(cpsr.mode(), Some(ProcessorMode::User));
+        assert_eq!(cpsr.ge(), 0b1010);
+        assert_eq!(Cpsr(0x15).mode(), None);
+    }
+
+    #[test]
+    fn cpsr_it_state() {
+        let mut cpsr = Cpsr::new(ProcessorMode::User);
+        cpsr.set_it(0b1011_0110);
+        assert_eq!(cpsr.0, 0x0400_B410);
+        assert_eq!(cpsr.it(), 0b1011_0110);
+        assert!(cpsr.in_it_block());
+
+        cpsr.set_it(0);
+        assert_eq!(cpsr, Cpsr::new(ProcessorMode::User));
+        assert!(!cpsr.in_it_block());
+    }
+
+    #[test]
+    fn fpscr_fields() {
+        let mut fpscr = Fpscr::default();
+        fpscr.set_default_nan(true);
+        fpscr.set_flush_to_zero(true);
+        fpscr.set_rounding_mode(RoundingMode::TowardsZero);
+        assert_eq!(fpscr, Fpscr(0x03C00000));
+
+        fpscr.set_rounding_mode(RoundingMode::TowardsMinusInfinity);
+        assert_eq!(fpscr.rounding_mode(), RoundingMode::TowardsMinusInfinity);
+
+        fpscr.set_inexact(true);
+        fpscr.set_input_denormal(true);
+        assert_eq!(fpscr.0 & 0xFF, 0x90);
+        fpscr.clear_cumulative_exceptions();
+        assert!(!fpscr.inexact() && !fpscr.input_denormal());
+        assert!(fpscr.default_nan() && fpscr.flush_to_zero());
+    }
+}
@@ -0,0 +1,96 @@
+// Savestate layout, all integers little endian:
+//
+//   magic, version
+//   CPU: r0-r15, extregs, cpsr, fpscr
+//   memory blob (Memory::save_state)
+//   handlers blob (Handlers::save_state)
+//   16 coprocessor blobs, empty for missing coprocessors
+//
+// Blobs are length prefixed, so a reader that consumes too little doesn't throw off the rest.
+// Exclusive reservations aren't saved, a STREX right after loading fails and gets retried.
+
+use byteorder::{LE, ReadBytesExt, WriteBytesExt};
+use std::io::{self, Read, Write};
+
+pub const MAGIC: [u8; 8] = *b"DYNARMSS";
+pub const VERSION: u32 = 1;
+
+pub(crate) fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+pub(crate) fn write_header(w: &mut dyn Write) -> io::Result<()> {
+    w.write_all(&MAGIC)?;
+    w.write_u32::<LE>(VERSION)
+}
+
+pub(crate) fn read_header(r: &mut dyn Read) -> io::Result<()> {
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(invalid_data("Not a savestate"));
+    }
+    let version = r.read_u32::<LE>()?;
+    if version != VERSION {
+        return Err(invalid_data(&format!("Unsupported savestate version {}", version)));
+    }
+    Ok(())
+}
+
+pub(crate) fn write_blob<F: FnOnce(&mut dyn Write) -> io::Result<()>>(w: &mut dyn Write, f: F) -> io::Result<()> {
+    let mut blob = Vec::new();
+    f(&mut blob)?;
+    w.write_u64::<LE>(blob.len() as u64)?;
+    w.write_all(&blob)
+}
+
+pub(crate) fn read_blob(r: &mut dyn Read) -> io::Result<Vec<u8>> {
+    let len = r.read_u64::<LE>()?;
+    read_bytes(r, len)
+}
+
+// Reads len bytes as they arrive, so a corrupt length fails at the end of the input instead of
+// allocating all of it up front
+pub(crate) fn read_bytes(r: &mut dyn Read, len: u64) -> io::Result<Vec<u8>> {
+    if len > isize::MAX as u64 {
+        return Err(invalid_data("Savestate data too large for this host"));
+    }
+    let mut bytes = Vec::new();
+    r.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
+
+pub(crate) fn write_words(w: &mut dyn Write, words: &[u32]) -> io::Result<()> {
+    words.iter().try_for_each(|&word| w.write_u32::<LE>(word))
+}
+
+pub(crate) fn read_words(r: &mut dyn Read, words: &mut [u32]) -> io::Result<()> {
+    r.read_u32_into::<LE>(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_is_checked() {
+        let mut buf = Vec::new();
+        write_header(&mut buf).unwrap();
+        read_header(&mut &buf[..]).unwrap();
+
+        buf[8] = 2;
+        assert_eq!(read_header(&mut &buf[..]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read_header(&mut &b"DYNARM"[..]).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn short_blob_fails() {
+        let mut buf = Vec::new();
+        write_blob(&mut buf, |w| w.write_all(&[1, 2, 3])).unwrap();
+        assert_eq!(read_blob(&mut &buf[..]).unwrap(), vec![1, 2, 3]);
+        assert!(read_blob(&mut &buf[..buf.len() - 1]).is_err());
+    }
+}
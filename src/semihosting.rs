@@ -0,0 +1,552 @@
+// ARM semihosting for bare metal firmware. Calls are SVC 0x123456 in ARM state, SVC 0xAB in Thumb
+// state or BKPT 0xAB in either, with the operation in r0, its argument (usually a pointer to a
+// parameter block) in r1 and the result returned in r0.
+//
+// Wire it up from both Handlers::handle_svc and Handlers::handle_exception. Files are opened below
+// a host directory, ":tt" is the console.
+
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{Exception, JitContext};
+use crate::host::{HostFile, io_errno, sandboxed_path};
+use crate::memory::Memory;
+
+pub mod op {
+    pub const SYS_OPEN: u32 = 0x01;
+    pub const SYS_CLOSE: u32 = 0x02;
+    pub const SYS_WRITEC: u32 = 0x03;
+    pub const SYS_WRITE0: u32 = 0x04;
+    pub const SYS_WRITE: u32 = 0x05;
+    pub const SYS_READ: u32 = 0x06;
+    pub const SYS_READC: u32 = 0x07;
+    pub const SYS_ISERROR: u32 = 0x08;
+    pub const SYS_ISTTY: u32 = 0x09;
+    pub const SYS_SEEK: u32 = 0x0A;
+    pub const SYS_FLEN: u32 = 0x0C;
+    pub const SYS_REMOVE: u32 = 0x0E;
+    pub const SYS_RENAME: u32 = 0x0F;
+    pub const SYS_CLOCK: u32 = 0x10;
+    pub const SYS_TIME: u32 = 0x11;
+    pub const SYS_SYSTEM: u32 = 0x12;
+    pub const SYS_ERRNO: u32 = 0x13;
+    pub const SYS_GET_CMDLINE: u32 = 0x15;
+    pub const SYS_HEAPINFO: u32 = 0x16;
+    pub const SYS_EXIT: u32 = 0x18;
+    pub const SYS_EXIT_EXTENDED: u32 = 0x20;
+    pub const SYS_ELAPSED: u32 = 0x30;
+    pub const SYS_TICKFREQ: u32 = 0x31;
+}
+
+const ARM_SVC: u32 = 0x123456;
+const THUMB_SVC: u32 = 0xAB;
+const BKPT: u32 = 0xAB;
+
+const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x20026;
+
+// Largest host buffer a single read or write goes through
+const MAX_CHUNK: u32 = 1 << 20;
+const MAX_PATH: u32 = 4096;
+
+// Payload of the halt after SYS_EXIT, see JitContext::halt_with. SYS_EXIT only tells success (0)
+// from failure (1), SYS_EXIT_EXTENDED passes the code through.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Exit(pub i32);
+
+fn is_thumb(context: &JitContext) -> bool {
+    context.cpsr().is_thumb()
+}
+
+pub struct Semihosting {
+    root: PathBuf,
+    files: BTreeMap<u32, HostFile>,
+    console_in: Option<HostFile>,
+    console_out: Option<HostFile>,
+    errno: i32,
+    cmdline: String,
+    heap_info: [u32; 4],
+    started: Instant,
+}
+
+impl Semihosting {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Semihosting {
+            root: root.into(),
+            files: BTreeMap::new(),
+            console_in: Some(HostFile::Reader(Box::new(io::stdin()))),
+            console_out: Some(HostFile::Writer(Box::new(io::stdout()))),
+            errno: 0,
+            cmdline: String::new(),
+            heap_info: [0; 4],
+            started: Instant::now(),
+        }
+    }
+
+    pub fn set_console_input<R: Read + 'static>(&mut self, input: R) {
+        self.console_in = Some(HostFile::Reader(Box::new(input)));
+    }
+
+    pub fn set_console_output<W: Write + 'static>(&mut self, output: W) {
+        self.console_out = Some(HostFile::Writer(Box::new(output)));
+    }
+
+    // Returned by SYS_GET_CMDLINE
+    pub fn set_cmdline<S: Into<String>>(&mut self, cmdline: S) {
+        self.cmdline = cmdline.into();
+    }
+
+    // Heap base, heap limit, stack base and stack limit for SYS_HEAPINFO. Zero lets the C library
+    // pick its own.
+    pub fn set_heap_info(&mut self, heap_info: [u32; 4]) {
+        self.heap_info = heap_info;
+    }
+
+    // Returns false if this isn't a semihosting SVC
+    pub fn handle_svc<M: Memory>(&mut self, memory: &M, context: &JitContext, swi: u32) -> bool {
+        let expected = if is_thumb(context) { THUMB_SVC } else { ARM_SVC };
+        if swi != expected {
+            return false;
+        }
+        self.call(memory, context);
+        true
+    }
+
+    // Returns false if this isn't a semihosting BKPT. Dynarmic has already moved PC past it.
+    pub fn handle_exception<M: Memory>(&mut self, memory: &M, context: &JitContext, pc: u32, exception: Exception) -> bool {
+        if exception != Exception::Breakpoint {
+            return false;
+        }
+
+        let imm = if is_thumb(context) {
+            match memory.read::<u16>(pc) {
+                Ok(insn) if insn & 0xFF00 == 0xBE00 => (insn & 0xFF) as u32,
+                _ => return false,
+            }
+        } else {
+            match memory.read::<u32>(pc) {
+                Ok(insn) if insn & 0x0FF000F0 == 0x01200070 => ((insn >> 4) & 0xFFF0) | (insn & 0xF),
+                _ => return false,
+            }
+        };
+        if imm != BKPT {
+            return false;
+        }
+
+        self.call(memory, context);
+        true
+    }
+
+    fn call<M: Memory>(&mut self, memory: &M, context: &JitContext) {
+        let (operation, parameter) = {
+            let regs = context.regs();
+            (regs[0], regs[1])
+        };
+
+        match operation {
+            op::SYS_EXIT => {
+                let code = if parameter == ADP_STOPPED_APPLICATION_EXIT { 0 } else { 1 };
+                context.halt_with(Exit(code));
+            },
+            op::SYS_EXIT_EXTENDED => {
+                let code = match (memory.read::<u32>(parameter), memory.read::<u32>(parameter.wrapping_add(4))) {
+                    (Ok(ADP_STOPPED_APPLICATION_EXIT), Ok(subcode)) => subcode as i32,
+                    _ => 1,
+                };
+                context.halt_with(Exit(code));
+            },
+            _ => {
+                let result = self.operation(memory, operation, parameter);
+                context.regs_mut()[0] = result;
+            },
+        }
+    }
+
+    fn args<M: Memory>(memory: &M, block: u32, count: u32) -> Option<Vec<u32>> {
+        (0..count).map(|i| memory.read::<u32>(block.wrapping_add(i * 4)).ok()).collect()
+    }
+
+    // File names, so anything longer than MAX_PATH is refused
+    fn string<M: Memory>(memory: &M, addr: u32, len: u32) -> Option<String> {
+        if len > MAX_PATH {
+            return None;
+        }
+        let mut bytes = vec![0u8; len as usize];
+        memory.read_bytes(addr, &mut bytes).ok()?;
+        Some(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    fn fail(&mut self, error: io::Error) -> u32 {
+        self.errno = io_errno(&error);
+        -1i32 as u32
+    }
+
+    fn file(&mut self, handle: u32) -> Option<&mut HostFile> {
+        match handle {
+            // Console handles from ":tt"
+            1 => self.console_in.as_mut(),
+            2 | 3 => self.console_out.as_mut(),
+            _ => self.files.get_mut(&handle),
+        }
+    }
+
+    fn operation<M: Memory>(&mut self, memory: &M, operation: u32, parameter: u32) -> u32 {
+        let bad_handle = || io::Error::new(io::ErrorKind::InvalidInput, "Bad handle");
+        let fault = || io::Error::new(io::ErrorKind::InvalidInput, "Bad parameter block");
+
+        match operation {
+            op::SYS_OPEN => {
+                let args = match Semihosting::args(memory, parameter, 3) {
+                    Some(args) => args,
+                    None => return self.fail(fault()),
+                };
+                let name = match Semihosting::string(memory, args[0], args[2]) {
+                    Some(name) => name,
+                    None => return self.fail(fault()),
+                };
+                let mode = args[1];
+
+                if name == ":tt" {
+                    return match mode / 4 {
+                        0 => 1,
+                        _ => 2,
+                    };
+                }
+
+                let path = match sandboxed_path(&self.root, &name) {
+                    Some(path) => path,
+                    None => return self.fail(io::ErrorKind::PermissionDenied.into()),
+                };
+                let plus = mode % 4 >= 2;
+                let result = match mode / 4 {
+                    0 => OpenOptions::new().read(true).write(plus).open(path),
+                    1 => OpenOptions::new().read(plus).write(true).create(true).truncate(true).open(path),
+                    _ => OpenOptions::new().read(plus).append(true).create(true).open(path),
+                };
+                match result {
+                    Ok(file) => {
+                        let handle = (4..).find(|handle| !self.files.contains_key(handle)).unwrap();
+                        self.files.insert(handle, HostFile::File(file));
+                        handle
+                    },
+                    Err(error) => self.fail(error),
+                }
+            },
+            op::SYS_CLOSE => {
+                let handle = memory.read::<u32>(parameter).unwrap_or(0);
+                match handle {
+                    1..=3 => 0,
+                    _ if self.files.remove(&handle).is_some() => 0,
+                    _ => self.fail(bad_handle()),
+                }
+            },
+            op::SYS_WRITEC => {
+                let byte = memory.read::<u8>(parameter).unwrap_or(b'?');
+                self.console_write(&[byte]);
+                0
+            },
+            op::SYS_WRITE0 => {
+                let mut bytes = Vec::new();
+                let mut addr = parameter;
+                while let Ok(byte) = memory.read::<u8>(addr) {
+                    if byte == 0 {
+                        break;
+                    }
+                    bytes.push(byte);
+                    addr = addr.wrapping_add(1);
+                }
+                self.console_write(&bytes);
+                0
+            },
+            // Both return the number of bytes not transferred
+            op::SYS_WRITE => {
+                let args = match Semihosting::args(memory, parameter, 3) {
+                    Some(args) => args,
+                    None => return self.fail(fault()),
+                };
+                let (handle, buf, len) = (args[0], args[1], args[2]);
+                let mut bytes = vec![0u8; len.min(MAX_CHUNK) as usize];
+                let mut written = 0;
+                while written < len {
+                    let chunk = &mut bytes[..(len - written).min(MAX_CHUNK) as usize];
+                    if memory.read_bytes(buf.wrapping_add(written), chunk).is_err() {
+                        return len - written;
+                    }
+                    let result = match self.file(handle) {
+                        Some(file) => file.write_all(chunk),
+                        None => Err(bad_handle()),
+                    };
+                    if let Err(error) = result {
+                        self.fail(error);
+                        return len - written;
+                    }
+                    written += chunk.len() as u32;
+                }
+                0
+            },
+            op::SYS_READ => {
+                let args = match Semihosting::args(memory, parameter, 3) {
+                    Some(args) => args,
+                    None => return self.fail(fault()),
+                };
+                let mut bytes = vec![0u8; args[2].min(MAX_CHUNK) as usize];
+                let result = match self.file(args[0]) {
+                    Some(file) => file.read(&mut bytes),
+                    None => Err(bad_handle()),
+                };
+                match result {
+                    Ok(read) if memory.write_bytes(args[1], &bytes[..read]).is_ok() => args[2] - read as u32,
+                    Ok(_) => args[2],
+                    Err(error) => {
+                        self.fail(error);
+                        args[2]
+                    },
+                }
+            },
+            op::SYS_READC => {
+                let mut byte = [0u8];
+                match self.console_in.as_mut().map(|input| input.read(&mut byte)) {
+                    Some(Ok(1)) => byte[0] as u32,
+                    _ => -1i32 as u32,
+                }
+            },
+            op::SYS_ISERROR => {
+                let status = memory.read::<u32>(parameter).unwrap_or(0) as i32;
+                (status < 0) as u32
+            },
+            op::SYS_ISTTY => {
+                let handle = memory.read::<u32>(parameter).unwrap_or(0);
+                match handle {
+                    1..=3 => 1,
+                    _ if self.files.contains_key(&handle) => 0,
+                    _ => self.fail(bad_handle()),
+                }
+            },
+            op::SYS_SEEK => {
+                let args = match Semihosting::args(memory, parameter, 2) {
+                    Some(args) => args,
+                    None => return self.fail(fault()),
+                };
+                let result = match self.files.get_mut(&args[0]).and_then(HostFile::file) {
+                    Some(file) => file.seek(SeekFrom::Start(args[1] as u64)),
+                    None => Err(bad_handle()),
+                };
+                match result {
+                    Ok(_) => 0,
+                    Err(error) => self.fail(error),
+                }
+            },
+            op::SYS_FLEN => {
+                let handle = memory.read::<u32>(parameter).unwrap_or(0);
+                let result = match self.files.get_mut(&handle).and_then(HostFile::file) {
+                    Some(file) => file.metadata(),
+                    None => Err(bad_handle()),
+                };
+                match result {
+                    Ok(metadata) => metadata.len() as u32,
+                    Err(error) => self.fail(error),
+                }
+            },
+            op::SYS_REMOVE => {
+                let path = Semihosting::args(memory, parameter, 2)
+                    .and_then(|args| Semihosting::string(memory, args[0], args[1]))
+                    .and_then(|name| sandboxed_path(&self.root, &name));
+                match path.map(fs::remove_file) {
+                    Some(Ok(())) => 0,
+                    Some(Err(error)) => self.fail(error),
+                    None => self.fail(io::ErrorKind::PermissionDenied.into()),
+                }
+            },
+            op::SYS_RENAME => {
+                let paths = Semihosting::args(memory, parameter, 4).and_then(|args| {
+                    let from = Semihosting::string(memory, args[0], args[1])?;
+                    let to = Semihosting::string(memory, args[2], args[3])?;
+                    Some((sandboxed_path(&self.root, &from)?, sandboxed_path(&self.root, &to)?))
+                });
+                match paths.map(|(from, to)| fs::rename(from, to)) {
+                    Some(Ok(())) => 0,
+                    Some(Err(error)) => self.fail(error),
+                    None => self.fail(io::ErrorKind::PermissionDenied.into()),
+                }
+            },
+            op::SYS_CLOCK => (self.started.elapsed().as_millis() / 10) as u32,
+            op::SYS_TIME => SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs() as u32),
+            // Firmware doesn't get to run host commands
+            op::SYS_SYSTEM => -1i32 as u32,
+            op::SYS_ERRNO => self.errno as u32,
+            op::SYS_GET_CMDLINE => {
+                let args = match Semihosting::args(memory, parameter, 2) {
+                    Some(args) => args,
+                    None => return self.fail(fault()),
+                };
+                let mut bytes = self.cmdline.clone().into_bytes();
+                bytes.push(0);
+                if bytes.len() as u32 > args[1] || memory.write_bytes(args[0], &bytes).is_err() {
+                    return -1i32 as u32;
+                }
+                let _ = memory.write(parameter.wrapping_add(4), bytes.len() as u32 - 1);
+                0
+            },
+            op::SYS_HEAPINFO => {
+                // The parameter points at a pointer to the result block
+                let block = memory.read::<u32>(parameter).unwrap_or(0);
+                for (i, value) in self.heap_info.iter().enumerate() {
+                    let _ = memory.write(block.wrapping_add(i as u32 * 4), *value);
+                }
+                0
+            },
+            op::SYS_ELAPSED => {
+                let ticks = self.started.elapsed().as_micros() as u64;
+                match memory.write(parameter, ticks) {
+                    Ok(()) => 0,
+                    Err(_) => -1i32 as u32,
+                }
+            },
+            op::SYS_TICKFREQ => 1_000_000,
+            _ => -1i32 as u32,
+        }
+    }
+
+    fn console_write(&mut self, bytes: &[u8]) {
+        if let Some(output) = self.console_out.as_mut() {
+            let _ = output.write_all(bytes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cpsr, ProcessorMode};
+    use crate::memory::MemoryImpl;
+    use crate::testing::{Captured, TestHandlers};
+
+    fn write_block(memory: &MemoryImpl, addr: u32, words: &[u32]) {
+        for (i, word) in words.iter().enumerate() {
+            memory.write(addr + i as u32 * 4, *word).unwrap();
+        }
+    }
+
+    #[test]
+    fn console_output() {
+        let mut memory = MemoryImpl::new();
+        memory.map_memory(0x1000, 1, false);
+        let mut semihosting = Semihosting::new(".");
+        let console = Captured::default();
+        semihosting.set_console_output(console.clone());
+
+        memory.write_bytes(0x1000, b"hello\0:tt\0").unwrap();
+        assert_eq!(semihosting.operation(&memory, op::SYS_WRITE0, 0x1000), 0);
+        assert_eq!(semihosting.operation(&memory, op::SYS_WRITEC, 0x1004), 0);
+
+        write_block(&memory, 0x1100, &[0x1006, 4, 3]);
+        let handle = semihosting.operation(&memory, op::SYS_OPEN, 0x1100);
+        write_block(&memory, 0x1100, &[handle, 0x1000, 3]);
+        assert_eq!(semihosting.operation(&memory, op::SYS_WRITE, 0x1100), 0);
+        assert_eq!(semihosting.operation(&memory, op::SYS_ISTTY, 0x1100), 1);
+
+        // Huge lengths fail without being allocated up front
+        write_block(&memory, 0x1100, &[handle, 0x1000, 0xFFFFFFFF]);
+        assert_eq!(semihosting.operation(&memory, op::SYS_WRITE, 0x1100), 0xFFFFFFFF);
+        write_block(&memory, 0x1100, &[0x1006, 4, 0xFFFFFFFF]);
+        assert_eq!(semihosting.operation(&memory, op::SYS_OPEN, 0x1100), -1i32 as u32);
+
+        assert_eq!(&console.0.borrow()[..], b"helloohel");
+    }
+
+    #[test]
+    fn files_stay_below_root() {
+        let root = std::env::temp_dir().join(format!("dynarmic-semihosting-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+
+        let mut memory = MemoryImpl::new();
+        memory.map_memory(0x1000, 1, false);
+        let mut semihosting = Semihosting::new(&root);
+
+        memory.write_bytes(0x1000, b"out.bin../x\0").unwrap();
+        write_block(&memory, 0x1100, &[0x1000, 6, 7]); // "w+"
+        let handle = semihosting.operation(&memory, op::SYS_OPEN, 0x1100);
+        assert_eq!(handle, 4);
+
+        write_block(&memory, 0x1100, &[handle, 0x1000, 7]);
+        assert_eq!(semihosting.operation(&memory, op::SYS_WRITE, 0x1100), 0);
+        assert_eq!(semihosting.operation(&memory, op::SYS_FLEN, 0x1100), 7);
+        write_block(&memory, 0x1100, &[handle, 3]);
+        assert_eq!(semihosting.operation(&memory, op::SYS_SEEK, 0x1100), 0);
+        write_block(&memory, 0x1100, &[handle, 0x1200, 16]);
+        assert_eq!(semihosting.operation(&memory, op::SYS_READ, 0x1100), 12);
+        let mut bytes = [0u8; 4];
+        memory.read_bytes(0x1200, &mut bytes).unwrap();
+        assert_eq!(&bytes, b".bin");
+        assert_eq!(semihosting.operation(&memory, op::SYS_CLOSE, 0x1100), 0);
+        assert_eq!(semihosting.operation(&memory, op::SYS_CLOSE, 0x1100), -1i32 as u32);
+        assert_eq!(std::fs::read(root.join("out.bin")).unwrap(), b"out.bin");
+
+        write_block(&memory, 0x1100, &[0x1007, 0, 4]); // "../x"
+        assert_eq!(semihosting.operation(&memory, op::SYS_OPEN, 0x1100), -1i32 as u32);
+        assert_eq!(semihosting.operation(&memory, op::SYS_ERRNO, 0), 13);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn cmdline_and_heap_info() {
+        let mut memory = MemoryImpl::new();
+        memory.map_memory(0x1000, 1, false);
+        let mut semihosting = Semihosting::new(".");
+        semihosting.set_cmdline("fw --fast");
+        semihosting.set_heap_info([0x20000, 0x30000, 0x40000, 0x38000]);
+
+        write_block(&memory, 0x1100, &[0x1200, 64]);
+        assert_eq!(semihosting.operation(&memory, op::SYS_GET_CMDLINE, 0x1100), 0);
+        assert_eq!(memory.read::<u32>(0x1104), Ok(9));
+        let mut bytes = [0u8; 10];
+        memory.read_bytes(0x1200, &mut bytes).unwrap();
+        assert_eq!(&bytes, b"fw --fast\0");
+
+        write_block(&memory, 0x1100, &[0x1300]);
+        assert_eq!(semihosting.operation(&memory, op::SYS_HEAPINFO, 0x1100), 0);
+        assert_eq!(memory.read::<u32>(0x130C), Ok(0x38000));
+        assert_eq!(semihosting.operation(&memory, 0xFF, 0), -1i32 as u32);
+    }
+
+    #[test]
+    fn executor_svc_and_bkpt() {
+        use crate::{Executor, HaltReason};
+
+        let mut memory = MemoryImpl::new();
+        memory.map_memory(0x0000, 1, false);
+        write_block(&memory, 0x0000, &[
+            0xE3A00004, // mov r0, #4 (SYS_WRITE0)
+            0xE28F1014, // add r1, pc, #20 (message)
+            0xEF123456, // svc #0x123456
+            0xE3A00020, // mov r0, #0x20 (SYS_EXIT_EXTENDED)
+            0xE28F1018, // add r1, pc, #24 (block)
+            0xE1200A7B, // bkpt #0xAB
+        ]);
+        memory.write_bytes(0x0020, b"hi\n\0").unwrap();
+        write_block(&memory, 0x0030, &[ADP_STOPPED_APPLICATION_EXIT, 7]);
+
+        let mut semihosting = Semihosting::new(".");
+        let console = Captured::default();
+        semihosting.set_console_output(console.clone());
+
+        let mut executor = Executor::new(TestHandlers::with(memory, semihosting)
+            .on_svc(|semihosting, memory, context, swi| {
+                assert!(semihosting.handle_svc(memory, &context, swi));
+            })
+            .on_exception(|semihosting, memory, context, pc, exception| {
+                assert!(semihosting.handle_exception(memory, &context, pc, exception));
+            }));
+        executor.context().set_cpsr(Cpsr::new(ProcessorMode::User));
+        executor.context().regs_mut()[15] = 0;
+
+        match executor.run() {
+            HaltReason::User(payload) => assert_eq!(payload.downcast_ref::<Exit>(), Some(&Exit(7))),
+            other => panic!("Unexpected halt {:?}", other),
+        }
+        assert_eq!(&console.0.borrow()[..], b"hi\n");
+    }
+}
@@ -0,0 +1,152 @@
+// Fixtures shared by the tests that run guest code
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use crate::a64::{Handlers64, JitContext64};
+use crate::coproc::CoprocessorCallbacks;
+use crate::memory::{Memory, MemoryImpl};
+use crate::{Cpsr, Exception, Executor, Handlers, HaltReason, JitContext, ProcessorMode};
+
+type SvcHook<T> = Box<dyn FnMut(&mut T, &mut MemoryImpl, JitContext, u32)>;
+type ExceptionHook<T> = Box<dyn FnMut(&mut T, &mut MemoryImpl, JitContext, u32, Exception)>;
+type Svc64Hook<T> = Box<dyn FnMut(&mut T, JitContext64, u32)>;
+type MakeCoprocessor<T> = for<'jit> fn(&'jit T) -> CoprocessorCallbacks<'jit>;
+
+// Hooks get the state, e.g. the Linux or Semihosting instance under test
+pub(crate) struct TestHandlers<T = ()> {
+    pub(crate) memory: MemoryImpl,
+    pub(crate) state: T,
+    svc: Option<SvcHook<T>>,
+    exception: Option<ExceptionHook<T>>,
+    svc64: Option<Svc64Hook<T>>,
+    coprocessor: Option<(usize, MakeCoprocessor<T>)>,
+}
+
+impl TestHandlers {
+    pub(crate) fn new(memory: MemoryImpl) -> Self {
+        TestHandlers::with(memory, ())
+    }
+}
+
+impl<T> TestHandlers<T> {
+    pub(crate) fn with(memory: MemoryImpl, state: T) -> Self {
+        TestHandlers {
+            memory,
+            state,
+            svc: None,
+            exception: None,
+            svc64: None,
+            coprocessor: None,
+        }
+    }
+
+    pub(crate) fn on_svc<F: FnMut(&mut T, &mut MemoryImpl, JitContext, u32) + 'static>(mut self, f: F) -> Self {
+        self.svc = Some(Box::new(f));
+        self
+    }
+
+    // Replaces the default of halting on everything but hints
+    pub(crate) fn on_exception<F: FnMut(&mut T, &mut MemoryImpl, JitContext, u32, Exception) + 'static>(mut self, f: F) -> Self {
+        self.exception = Some(Box::new(f));
+        self
+    }
+
+    // Coprocessor number, and how to build it when the executor asks
+    pub(crate) fn coprocessor(mut self, number: usize, make: MakeCoprocessor<T>) -> Self {
+        self.coprocessor = Some((number, make));
+        self
+    }
+
+    pub(crate) fn on_svc64<F: FnMut(&mut T, JitContext64, u32) + 'static>(mut self, f: F) -> Self {
+        self.svc64 = Some(Box::new(f));
+        self
+    }
+}
+
+impl<T> Handlers for TestHandlers<T> {
+    type Memory = MemoryImpl;
+
+    fn memory(&self) -> &Self::Memory {
+        &self.memory
+    }
+
+    fn memory_mut(&mut self) -> Option<&mut Self::Memory> {
+        Some(&mut self.memory)
+    }
+
+    fn handle_svc(&mut self, context: JitContext, swi: u32) {
+        if let Some(svc) = &mut self.svc {
+            svc(&mut self.state, &mut self.memory, context, swi);
+        }
+    }
+
+    fn handle_exception(&mut self, context: JitContext, pc: u32, exception: Exception) {
+        match &mut self.exception {
+            Some(handle) => handle(&mut self.state, &mut self.memory, context, pc, exception),
+            None if !exception.is_hint() => context.halt_with_reason(HaltReason::Exception { pc, exception }),
+            None => {}
+        }
+    }
+
+    fn make_coprocessors<'jit>(&'jit mut self) -> Option<[Option<CoprocessorCallbacks<'jit>>; 16]> {
+        let (number, make) = self.coprocessor?;
+        let mut cp: [Option<CoprocessorCallbacks<'jit>>; 16] = Default::default();
+        cp[number] = Some(make(&self.state));
+        Some(cp)
+    }
+}
+
+impl<T> Handlers64 for TestHandlers<T> {
+    type Memory = MemoryImpl;
+
+    fn memory(&self) -> &Self::Memory {
+        &self.memory
+    }
+
+    fn handle_svc(&mut self, context: JitContext64, swi: u32) {
+        if let Some(svc) = &mut self.svc64 {
+            svc(&mut self.state, context, swi);
+        }
+    }
+}
+
+// Output sink the test can still read after handing a clone to the code under test
+#[derive(Clone, Default)]
+pub(crate) struct Captured(pub(crate) Rc<RefCell<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// One page at 0 holding code, given as (address, word) pairs
+pub(crate) fn memory_with(read_only: bool, code: &[(u32, u32)]) -> MemoryImpl {
+    let mut mem = MemoryImpl::new();
+
+    mem.map_memory(0x00000000, 1, read_only);
+    for &(addr, word) in code {
+        mem.write(addr, word).unwrap();
+    }
+    mem
+}
+
+// ARM user mode at PC 0
+pub(crate) fn reset<H: Handlers>(executor: &mut Executor<H>) {
+    let context = executor.context();
+    context.set_cpsr(Cpsr::new(ProcessorMode::User));
+    context.set_pc(0);
+}
+
+pub(crate) fn executor_with(code: &[(u32, u32)]) -> Executor<TestHandlers> {
+    let mut executor = Executor::new(TestHandlers::new(memory_with(true, code)));
+    reset(&mut executor);
+    executor
+}